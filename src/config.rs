@@ -4,7 +4,7 @@ use ron;
 use serde::*;
 use std::collections::HashMap;
 use std::fs::{create_dir_all, read_to_string, File};
-use std::hash::Hash;
+use std::hash::{Hash, Hasher};
 use std::io::{Read, Write};
 use std::path::PathBuf;
 
@@ -39,10 +39,35 @@ pub struct Config {
     pub input_device: Option<String>,
     pub output_device: Option<String>,
     pub loopback_device: Option<String>,
+    /// Stable id from `sound::list_devices`, finds the loop device even if its name changed
+    #[serde(default)]
+    pub loopback_device_id: Option<String>,
     #[cfg(feature = "autoloop")]
     pub autoloop: bool,
     pub hotkeys: HashMap<(String, String), Vec<Key>>,
     pub repos: IdMap<(SoundRepo, Option<DownloadedSoundRepo>)>,
+    #[serde(default)]
+    pub sound_settings: HashMap<(String, String), SoundSettings>,
+    /// What to do when one of the devices above is missing or unplugged
+    #[serde(default)]
+    pub device_fallback: DeviceFallback,
+    #[serde(default)]
+    pub levels: Levels,
+    #[serde(default)]
+    pub mic: MicSettings,
+    #[serde(default)]
+    pub eq: Equalizers,
+    #[serde(default)]
+    pub dynamics: SinkDynamics,
+    /// Adds noise to the sounds before they are rounded to the format of the devices
+    #[serde(default)]
+    pub dither: bool,
+    /// Lowers the microphone and the music bed while sounds play, off when `None`
+    #[serde(default)]
+    pub ducking: Option<Ducking>,
+    /// Brings the sounds to the same loudness when they play, off when `None`
+    #[serde(default)]
+    pub normalization: Option<Normalization>,
 }
 
 impl Default for Config {
@@ -53,9 +78,313 @@ impl Default for Config {
             input_device: None,
             output_device: None,
             loopback_device: None,
+            loopback_device_id: None,
             #[cfg(feature = "autoloop")]
             autoloop: true,
-            hotkeys: HashMap::new()
+            hotkeys: HashMap::new(),
+            sound_settings: HashMap::new(),
+            device_fallback: DeviceFallback::default(),
+            levels: Levels::default(),
+            mic: MicSettings::default(),
+            eq: Equalizers::default(),
+            dynamics: SinkDynamics::default(),
+            dither: false,
+            ducking: None,
+            normalization: None,
+        }
+    }
+}
+
+/// Volume and mute of one device of the sound loop
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct Level {
+    /// Linear amplitude multiplier
+    pub volume: f32,
+    pub muted: bool,
+}
+
+impl Level {
+    /// The volume, silent while muted
+    pub fn gain(&self) -> f32 {
+        if self.muted {
+            0.0
+        } else {
+            self.volume
+        }
+    }
+}
+
+impl Default for Level {
+    fn default() -> Self {
+        Self {
+            volume: 1.0,
+            muted: false,
+        }
+    }
+}
+
+/// When the microphone goes through to the loop device
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MicMode {
+    #[default]
+    AlwaysOn,
+    /// Only while the push keys are held
+    PushToTalk,
+    /// Except while the push keys are held
+    PushToMute,
+}
+
+/// The microphone passed through to the loop device. Its volume and mute are in `Levels`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+pub struct MicSettings {
+    /// Gain in decibels, can boost a quiet microphone unlike the volume
+    #[serde(default)]
+    pub gain_db: f32,
+    #[serde(default)]
+    pub mode: MicMode,
+    /// Held for push to talk or push to mute
+    #[serde(default)]
+    pub push_keys: Vec<Key>,
+    /// Toggle the mute of the microphone
+    #[serde(default)]
+    pub mute_keys: Vec<Key>,
+    /// Voice effects by name
+    #[serde(default)]
+    pub presets: HashMap<String, VoicePreset>,
+    /// The preset in use, the plain voice when `None`
+    #[serde(default)]
+    pub preset: Option<String>,
+    #[serde(default)]
+    pub gate: Option<NoiseGate>,
+    #[serde(default)]
+    pub suppression: Option<NoiseSuppression>,
+}
+
+/// Silences the microphone while it is quieter than the threshold, see `sound::noise`
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct NoiseGate {
+    /// Peak level in decibels below full scale that opens the gate
+    pub threshold_db: f32,
+    /// Time taken to open
+    pub attack_ms: f32,
+    /// Time the gate stays open after the level goes below the threshold
+    pub hold_ms: f32,
+    /// Time taken to close
+    pub release_ms: f32,
+}
+
+/// Takes out the noise learnt with `sound::Message::LearnNoise`, see `sound::noise`
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct NoiseSuppression {
+    /// Most the noise is lowered by, in decibels
+    pub reduction_db: f32,
+    /// Samples in each block the noise is taken out of, bigger ones cut it finer but delay the
+    /// microphone more
+    pub fft_size: usize,
+}
+
+/// A set of voice effects for the microphone
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+pub struct VoicePreset {
+    /// Applied in order
+    #[serde(default)]
+    pub effects: Vec<Effect>,
+    /// Switch to the preset, or back to the plain voice while it is in use
+    #[serde(default)]
+    pub keys: Vec<Key>,
+}
+
+/// A voice effect, see `sound::effect`. A `mix` is the share of the effect in the output, from
+/// `0.0` to `1.0`.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum Effect {
+    /// Shifts the pitch by semitones, up when positive
+    PitchShift { semitones: f32 },
+    /// Ring modulation with a sine at `frequency` Hz
+    Robot { frequency: f32 },
+    Echo {
+        delay_ms: f32,
+        /// Share of each repeat fed back into the next one, from `0.0` to below `1.0`
+        feedback: f32,
+        mix: f32,
+    },
+    Reverb {
+        /// From `0.0` to `1.0`, longer tails for bigger rooms
+        room_size: f32,
+        /// From `0.0` to `1.0`, how fast the high frequencies die out
+        damping: f32,
+        mix: f32,
+    },
+    Chorus {
+        rate_hz: f32,
+        depth_ms: f32,
+        mix: f32,
+    },
+    /// Soft clipping after boosting the voice by `drive_db` decibels
+    Distortion { drive_db: f32 },
+    /// Keeps the band between `low_hz` and `high_hz`, like a phone line
+    Telephone { low_hz: f32, high_hz: f32 },
+}
+
+/// Levels of the sounds heard by the user, the sounds sent to the loop device and the microphone
+/// passed through to it
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+pub struct Levels {
+    pub output: Level,
+    pub loopback: Level,
+    pub mic: Level,
+}
+
+/// The shape of an EQ band
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum EqKind {
+    /// Changes the frequencies below the band by its gain
+    LowShelf,
+    /// Changes the frequencies above the band by its gain
+    HighShelf,
+    /// Changes the frequencies around the band by its gain
+    Peaking,
+    /// Cuts the frequencies above the band
+    LowPass,
+    /// Cuts the frequencies below the band, like the rumble of a microphone
+    HighPass,
+    /// Cuts the frequencies right at the band, like a hum
+    Notch,
+}
+
+/// One band of a parametric EQ, see `sound::eq`
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct EqBand {
+    pub kind: EqKind,
+    /// Centre or corner frequency in Hz
+    pub frequency: f32,
+    /// Only used by shelves and peaks
+    #[serde(default)]
+    pub gain_db: f32,
+    /// Narrower bands and sharper corners when higher, `0.707` is flat for passes
+    pub q: f32,
+}
+
+/// The bands of the EQ of each device, applied in order, with no EQ when empty
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+pub struct Equalizers {
+    pub output: Vec<EqBand>,
+    pub loopback: Vec<EqBand>,
+    pub mic: Vec<EqBand>,
+}
+
+/// Lowers the mix of a sink by `ratio` above the threshold, see `sound::dynamics`
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct Compressor {
+    /// Peak level in decibels below full scale where compression starts
+    pub threshold_db: f32,
+    /// Decibels over the threshold going in for each decibel coming out
+    pub ratio: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+    /// Gain in decibels added back after compressing
+    #[serde(default)]
+    pub makeup_db: f32,
+}
+
+/// Keeps the mix of a sink under a ceiling, see `sound::dynamics`
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct Limiter {
+    /// Highest peak let through, in decibels below full scale
+    pub ceiling_db: f32,
+    /// Time taken to come back from a peak
+    pub release_ms: f32,
+    /// How far ahead peaks are seen, delaying the sink by as much
+    #[serde(default = "default_lookahead_ms")]
+    pub lookahead_ms: f32,
+}
+
+fn default_lookahead_ms() -> f32 {
+    5.0
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self {
+            ceiling_db: -1.0,
+            release_ms: 100.0,
+            lookahead_ms: default_lookahead_ms(),
+        }
+    }
+}
+
+/// The compressor and limiter of a sink, applied in that order after its other filters
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+pub struct Dynamics {
+    #[serde(default)]
+    pub compressor: Option<Compressor>,
+    #[serde(default)]
+    pub limiter: Option<Limiter>,
+}
+
+/// The dynamics of the sounds heard by the user and of the sounds sent to the loop device
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+pub struct SinkDynamics {
+    #[serde(default)]
+    pub output: Dynamics,
+    #[serde(default)]
+    pub loopback: Dynamics,
+}
+
+/// Lowers the microphone and the music bed while sounds play, see `sound::duck`
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct Ducking {
+    /// Gain taken away in decibels
+    pub amount_db: f32,
+    /// Time taken to go all the way down once a sound starts
+    pub attack_ms: f32,
+    /// Time taken to come all the way back up after the last sound ends
+    pub release_ms: f32,
+    /// Lowers the microphone passed to the loop device
+    pub mic: bool,
+    /// Lowers the sounds set as `SoundDucking::MusicBed`
+    pub music_bed: bool,
+}
+
+impl Default for Ducking {
+    fn default() -> Self {
+        Self {
+            amount_db: 12.0,
+            attack_ms: 50.0,
+            release_ms: 500.0,
+            mic: true,
+            music_bed: true,
+        }
+    }
+}
+
+/// What the sound loop does while a device it should use is missing, until the device comes back
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeviceFallback {
+    /// Uses the default device of the system instead. The loop device has no sensible default
+    /// and waits for its device
+    #[default]
+    UseDefault,
+    /// Tries to open the device `attempts` times, `interval_ms` apart, then gives up
+    Retry { attempts: u32, interval_ms: u64 },
+    /// Keeps checking for the device, playing nothing on it meanwhile
+    Wait,
+}
+
+/// Brings every sound to the same loudness when it plays, see `sound::loudness`
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct Normalization {
+    /// Integrated loudness the sounds are brought to, in LUFS
+    pub target_lufs: f32,
+    /// Highest true peak a sound is raised to, in decibels relative to full scale
+    pub true_peak_db: f32,
+}
+
+impl Default for Normalization {
+    fn default() -> Self {
+        Self {
+            target_lufs: -16.0,
+            true_peak_db: -1.0,
         }
     }
 }
@@ -109,6 +438,129 @@ pub struct Sound {
     pub name: String,
     pub wav: PathBuf,
     pub img: Option<PathBuf>,
+    #[serde(default)]
+    pub settings: SoundSettings,
+}
+
+/// Mixing settings for a single sound, stored in `Config::sound_settings`
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct SoundSettings {
+    /// Gain in decibels, `0.0` leaves the sound as it was mastered
+    #[serde(default)]
+    pub gain_db: f32,
+    /// Stereo position, from `-1.0` (left) through `0.0` (centre) to `1.0` (right)
+    #[serde(default)]
+    pub pan: f32,
+    /// Overrides the loop points declared in the repo's `sounds.ron`
+    #[serde(default)]
+    pub loop_points: LoopPoints,
+    #[serde(default)]
+    pub retrigger: Retrigger,
+    /// Playing a sound stops the others in the same group, overrides the repo's `sounds.ron`
+    #[serde(default)]
+    pub choke_group: Option<String>,
+    /// What the sound does with `Config::ducking`
+    #[serde(default)]
+    pub ducking: SoundDucking,
+}
+
+impl SoundSettings {
+    /// The gain as a linear amplitude multiplier
+    pub fn gain(&self) -> f32 {
+        crate::utils::db_to_gain(self.gain_db)
+    }
+}
+
+impl Default for SoundSettings {
+    fn default() -> Self {
+        Self {
+            gain_db: 0.0,
+            pan: 0.0,
+            loop_points: LoopPoints::default(),
+            retrigger: Retrigger::default(),
+            choke_group: None,
+            ducking: SoundDucking::default(),
+        }
+    }
+}
+
+impl Eq for SoundSettings {}
+
+impl Hash for SoundSettings {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        float_bits(self.gain_db).hash(state);
+        float_bits(self.pan).hash(state);
+        self.loop_points.hash(state);
+        self.retrigger.hash(state);
+        self.choke_group.hash(state);
+        self.ducking.hash(state);
+    }
+}
+
+/// What playing a sound does while copies of it are still playing
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Retrigger {
+    /// Plays another copy, stopping the oldest ones once `max_instances` are playing
+    Overlap { max_instances: Option<u32> },
+    /// Stops the playing copies and starts again from the beginning
+    Restart,
+    /// Does nothing until the sound has finished
+    Ignore,
+    /// Stops the playing copies without starting a new one
+    Toggle,
+}
+
+impl Default for Retrigger {
+    fn default() -> Self {
+        Retrigger::Overlap {
+            max_instances: None,
+        }
+    }
+}
+
+/// How a sound takes part in `Config::ducking`
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum SoundDucking {
+    /// Lowers the microphone and the music bed while it plays
+    #[default]
+    Ducks,
+    /// Plays without lowering anything
+    Ignored,
+    /// Is lowered while the other sounds play
+    MusicBed,
+}
+
+/// Section of a sound, in seconds, that is repeated when it's played looping.
+///
+/// A missing start loops from the beginning and a missing end loops at the end of the sound.
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq)]
+pub struct LoopPoints {
+    pub start: Option<f32>,
+    pub end: Option<f32>,
+}
+
+impl LoopPoints {
+    /// Takes each point from `self`, falling back to the one in `other`
+    pub fn or(self, other: LoopPoints) -> LoopPoints {
+        LoopPoints {
+            start: self.start.or(other.start),
+            end: self.end.or(other.end),
+        }
+    }
+}
+
+impl Eq for LoopPoints {}
+
+impl Hash for LoopPoints {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.start.map(float_bits).hash(state);
+        self.end.map(float_bits).hash(state);
+    }
+}
+
+/// Bits to hash a float by, `-0.0` is equal to `0.0` so it is hashed like it
+fn float_bits(value: f32) -> u32 {
+    (value + 0.0).to_bits()
 }
 
 #[derive(Debug, Deserialize, Serialize, Default, PartialEq, Eq, Hash, Clone)]
@@ -116,6 +568,10 @@ pub struct SoundRON {
     pub name: String,
     pub wav: PathBuf,
     pub img: Option<PathBuf>,
+    #[serde(default)]
+    pub loop_points: LoopPoints,
+    #[serde(default)]
+    pub choke_group: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Default, PartialEq, Eq, Hash, Clone)]
@@ -139,6 +595,7 @@ impl SoundConfig {
         // let mut repos = HashMap::new();
         // let mut named_repos = HashMap::new();
         let mut sounds_hm = HashMap::new();
+        let sound_settings = &config.sound_settings;
         for (repo, data) in &mut config.repos.iter_mut() {
             let mut hm = HashMap::new();
             if !matches!(data, Some(x) if sounds_dir.join(&x.name).exists()) {
@@ -167,6 +624,14 @@ impl SoundConfig {
             )
             .expect("Error parsing sounds");
             sounds.sounds.iter().for_each(|x| {
+                let mut settings: SoundSettings = sound_settings
+                    .get(&(soundrepo_data.name.clone(), x.name.clone()))
+                    .cloned()
+                    .unwrap_or_default();
+                settings.loop_points = settings.loop_points.or(x.loop_points);
+                if settings.choke_group.is_none() {
+                    settings.choke_group = x.choke_group.clone();
+                }
                 hm.insert(
                     x.name.clone(),
                     Sound {
@@ -178,6 +643,7 @@ impl SoundConfig {
                                 .join(&soundrepo_data.name)
                                 .join(x.img.clone().unwrap_or(sounds.default_img.clone())),
                         ),
+                        settings,
                     },
                 );
             });
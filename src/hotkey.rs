@@ -26,6 +26,8 @@ pub enum ManagerMessage<K> {
     Stop,
     Loopback(ThreadMessage),
     Register(K, Vec<Key>, Box<dyn Fn() + Send>),
+    /// Called with `true` once all the keys are pressed, and `false` once one is released
+    RegisterHold(K, Vec<Key>, Box<dyn Fn(bool) + Send>),
     Unregister(K),
 }
 
@@ -56,6 +58,7 @@ impl<K: Hash + Eq + Send + 'static> HotkeyManager<K> {
             info!("Listening for keys (CHANNEL THREAD)");
             let mut detected: Option<Vec<Key>> = None;
             let mut hotkeys: HashMap<K, (Vec<Key>, Box<dyn Fn() + Send>)> = hotkeys;
+            let mut holds: HashMap<K, (Vec<Key>, Box<dyn Fn(bool) + Send>, bool)> = HashMap::new();
             let mut pressed_keys: Vec<Key> = Vec::new();
             loop {
                 //info!("Trying to recv");
@@ -74,6 +77,15 @@ impl<K: Hash + Eq + Send + 'static> HotkeyManager<K> {
                                 }
                                 pressed_keys.push(k);
                                 //warn!("P {:?}", pressed_keys);
+                                for (keys, f, held) in holds.values_mut() {
+                                    if !*held
+                                        && !keys.is_empty()
+                                        && keys.iter().all(|x| pressed_keys.contains(x))
+                                    {
+                                        *held = true;
+                                        f(true);
+                                    }
+                                }
                                 for (_, (key, f)) in hotkeys.iter() {
                                     if !key.is_empty() && key.iter().fold(true, |v, x| v && pressed_keys.contains(x)) {
                                         pressed_keys.retain(|x| x != &k);
@@ -83,6 +95,12 @@ impl<K: Hash + Eq + Send + 'static> HotkeyManager<K> {
                             }
                             EventType::KeyRelease(k) => {
                                 pressed_keys.retain(|x| x != &k);
+                                for (keys, f, held) in holds.values_mut() {
+                                    if *held && keys.contains(&k) {
+                                        *held = false;
+                                        f(false);
+                                    }
+                                }
                                 //warn!("R {:?}", pressed_keys);
                             }
                             _ => {}
@@ -113,10 +131,14 @@ impl<K: Hash + Eq + Send + 'static> HotkeyManager<K> {
                             hotkeys.insert(name, (keys, f));
                             // info!("{:?}", hotkeys.keys());
                         }
+                        ManagerMessage::RegisterHold(name, keys, f) => {
+                            holds.insert(name, (keys, f, false));
+                        }
                         ManagerMessage::Unregister(keys) => {
                             // info!("Unregistering {}", keys);
                             // info!("{:?}", hotkeys.keys());
                             hotkeys.remove(&keys);
+                            holds.remove(&keys);
                             // info!("{:?}", hotkeys.keys());
                         }
                     };
@@ -168,6 +190,14 @@ impl<K: Hash + Eq + Send + 'static> HotkeyManager<K> {
             .expect("Error sending register request");
     }
 
+    /// Registers keys that are held, like push to talk. `f` gets `true` once all of them are
+    /// pressed and `false` once one of them is released.
+    pub fn register_hold(&self, name: K, keys: Vec<Key>, f: Box<dyn Fn(bool) + Send>) {
+        self.manager_sender
+            .send(ManagerMessage::RegisterHold(name, keys, f))
+            .expect("Error sending register request");
+    }
+
     pub fn unregister(&self, name: K) {
         self.manager_sender
             .send(ManagerMessage::Unregister(name))
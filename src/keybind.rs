@@ -7,7 +7,7 @@ use log::error;
 use std::collections::HashMap;
 use std::hash::Hash;
 
-use crate::config::Config;
+use crate::config::{Config, MicSettings};
 use crate::utils::IdMap;
 
 pub struct KeyBindings<
@@ -18,6 +18,8 @@ pub struct KeyBindings<
     keybinds: IdMap<(K, Vec<Key>)>,
     setting_keybind: Option<(usize, Vec<Key>)>,
     hotkeys: crate::hotkey::HotkeyManager<K>,
+    /// Registered by `set_mic_hotkeys`
+    mic_hotkeys: Vec<K>,
     m_sender: Sender<Message>,
     on_hotkey: F,
 }
@@ -35,6 +37,7 @@ where
             keybinds: IdMap::new(),
             setting_keybind: None,
             hotkeys: crate::hotkey::HotkeyManager::new(Default::default()),
+            mic_hotkeys: Vec::new(),
             m_sender,
             on_hotkey,
         };
@@ -173,4 +176,60 @@ where
     pub fn keys(&self) -> IdMap<((String, String), Vec<Key>)> {
         self.keybinds.clone()
     }
+
+    /// Registers the push, mute and voice preset keys of `mic`, which go straight to the sound
+    /// loop, replacing the ones registered before
+    pub fn set_mic_hotkeys(&mut self, mic: &MicSettings, sound_sender: crate::SoundSender) {
+        for name in self.mic_hotkeys.drain(..) {
+            self.hotkeys.unregister(name);
+        }
+
+        let push_sender = sound_sender.clone();
+        self.hotkeys.register_hold(
+            mic_hotkey("push"),
+            mic.push_keys.clone(),
+            Box::new(move |held| {
+                push_sender
+                    .send(crate::sound::Message::SetPushKeyHeld(held))
+                    .expect("Error sending sound message");
+            }),
+        );
+        let mute_sender = sound_sender.clone();
+        self.hotkeys.register(
+            mic_hotkey("mute"),
+            mic.mute_keys.clone(),
+            Box::new(move || {
+                mute_sender
+                    .send(crate::sound::Message::ToggleDeviceMuted(
+                        crate::sound::DeviceRole::Input,
+                    ))
+                    .expect("Error sending sound message");
+            }),
+        );
+        self.mic_hotkeys = vec![mic_hotkey("push"), mic_hotkey("mute")];
+
+        for (name, preset) in mic.presets.iter() {
+            let preset_sender = sound_sender.clone();
+            let preset_name = name.clone();
+            self.hotkeys.register(
+                mic_hotkey(&format!("preset {}", name)),
+                preset.keys.clone(),
+                Box::new(move || {
+                    preset_sender
+                        .send(crate::sound::Message::ToggleVoicePreset(
+                            preset_name.clone(),
+                        ))
+                        .expect("Error sending sound message");
+                }),
+            );
+            self.mic_hotkeys
+                .push(mic_hotkey(&format!("preset {}", name)));
+        }
+    }
+}
+
+/// Name of a microphone hotkey. Sounds are named after their repo, which can't be empty, so
+/// these never clash with them.
+fn mic_hotkey(name: &str) -> (String, String) {
+    (String::new(), format!("mic {}", name))
 }
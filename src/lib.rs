@@ -73,10 +73,20 @@ pub struct SoundLoop {
     output_id: Option<String>,
     input_id: Option<String>,
     loopback_id: Option<String>,
+    loopback_stable_id: Option<String>,
+    device_fallback: config::DeviceFallback,
+    levels: config::Levels,
+    mic: config::MicSettings,
+    eq: config::Equalizers,
+    dynamics: config::SinkDynamics,
+    dither: bool,
+    ducking: Option<config::Ducking>,
+    normalization: Option<config::Normalization>,
 
     gui_sender: crossbeam_channel::Sender<sound::Message>,
     sound_receiver: crossbeam_channel::Receiver<sound::Message>,
     sound_sender: crossbeam_channel::Sender<sound::Message>,
+    events: sound::Events,
 
     thread_handle: Option<Arc<std::thread::JoinHandle<()>>>,
 }
@@ -91,9 +101,19 @@ impl SoundLoop {
             gui_sender,
             sound_receiver,
             sound_sender,
+            events: sound::Events::default(),
             output_id: None,
             input_id: None,
             loopback_id: None,
+            loopback_stable_id: None,
+            device_fallback: config::DeviceFallback::default(),
+            levels: config::Levels::default(),
+            mic: config::MicSettings::default(),
+            eq: config::Equalizers::default(),
+            dynamics: config::SinkDynamics::default(),
+            dither: false,
+            ducking: None,
+            normalization: None,
             thread_handle: None,
         };
         r.load();
@@ -103,30 +123,89 @@ impl SoundLoop {
     pub fn load(&mut self) {
         let conf = config::Config::load();
         self.loopback_id = conf.loopback_device;
+        self.loopback_stable_id = conf.loopback_device_id;
         self.output_id = conf.output_device;
         self.input_id = conf.input_device;
+        self.device_fallback = conf.device_fallback;
+        self.levels = conf.levels;
+        self.mic = conf.mic;
+        self.eq = conf.eq;
+        self.dynamics = conf.dynamics;
+        self.dither = conf.dither;
+        self.ducking = conf.ducking;
+        self.normalization = conf.normalization;
+    }
+
+    /// Receives the playback events of the sound loop, including after it is restarted
+    pub fn subscribe(&self) -> crossbeam_channel::Receiver<sound::Event> {
+        self.events.subscribe()
     }
 
     pub fn run(&mut self) -> Result<(), ()> {
-        if let Some(loopback_id) = self.loopback_id.clone() {
+        self.spawn(None)
+    }
+
+    /// Runs the sound loop on the devices of `backend` instead of the sound card, like the
+    /// `sound::backend::NullBackend` used in tests
+    #[allow(clippy::result_unit_err)]
+    pub fn run_with_backend<B>(&mut self, backend: B) -> Result<(), ()>
+    where
+        B: sound::backend::Backend + Send + 'static,
+    {
+        self.spawn(Some(Box::new(backend)))
+    }
+
+    fn spawn(
+        &mut self,
+        backend: Option<Box<dyn sound::backend::Backend + Send>>,
+    ) -> Result<(), ()> {
+        // The stable id alone is enough, the loop finds the name that goes with it
+        let loopback_name = self
+            .loopback_id
+            .clone()
+            .or_else(|| self.loopback_stable_id.as_ref().map(|_| String::new()));
+        if let Some(loopback_id) = loopback_name {
             //  START AUDIO THREAD
             let sound_receiver = self.sound_receiver.clone();
             let sound_sender = self.sound_sender.clone();
             let gui_sender = self.gui_sender.clone();
+            let events = self.events.clone();
 
-            let input_id = self.input_id.clone();
-            let output_id = self.output_id.clone();
+            let devices = sound::LoopDevices {
+                input: self.input_id.clone(),
+                output: self.output_id.clone(),
+                loopback: loopback_id,
+                loopback_id: self.loopback_stable_id.clone(),
+                fallback: self.device_fallback,
+                levels: self.levels,
+                mic: self.mic.clone(),
+                eq: self.eq.clone(),
+                dynamics: self.dynamics,
+                dither: self.dither,
+                ducking: self.ducking,
+                normalization: self.normalization,
+            };
 
             self.thread_handle = Some(Arc::new(std::thread::spawn(move || {
                 println!("Running sound loop");
-                sound::run_sound_loop(
-                    sound_receiver,
-                    sound_sender,
-                    gui_sender,
-                    input_id,
-                    output_id,
-                    loopback_id,
-                );
+                if let Some(backend) = backend {
+                    sound::run_sound_loop_with_backend(
+                        &*backend,
+                        sound_receiver,
+                        sound_sender,
+                        gui_sender,
+                        devices,
+                        events,
+                    );
+                } else {
+                    sound::run_sound_loop(
+                        sound_receiver,
+                        sound_sender,
+                        gui_sender,
+                        devices,
+                        events,
+                    );
+                }
             })));
 
             Ok(())
@@ -144,6 +223,184 @@ impl SoundLoop {
         }
     }
 
+    /// Moves the output to `device` without restarting, the default device when `None`, and
+    /// saves it in the config
+    pub fn set_output_device(&mut self, device: Option<String>) {
+        let mut conf = config::Config::load();
+        conf.output_device = device.clone();
+        conf.save();
+        self.output_id = device.clone();
+        self.send_if_running(sound::Message::SetOutputDevice(device));
+    }
+
+    /// Moves the loop device without restarting and saves it in the config, see
+    /// `set_output_device`
+    pub fn set_loopback_device(&mut self, device: String) {
+        let mut conf = config::Config::load();
+        conf.loopback_device = Some(device.clone());
+        conf.loopback_device_id = None;
+        conf.save();
+        self.loopback_id = Some(device.clone());
+        self.loopback_stable_id = None;
+        self.send_if_running(sound::Message::SetLoopDevice(device));
+    }
+
+    /// Passes another microphone to the loop device without restarting and saves it in the
+    /// config, see `set_output_device`
+    pub fn set_input_device(&mut self, device: Option<String>) {
+        let mut conf = config::Config::load();
+        conf.input_device = device.clone();
+        conf.save();
+        self.input_id = device.clone();
+        self.send_if_running(sound::Message::SetInputDevice(device));
+    }
+
+    /// Changes the volume of a device and saves it in the config
+    pub fn set_device_volume(&mut self, role: sound::DeviceRole, volume: f32) {
+        self.save_level(role, |level| level.volume = volume);
+        self.send_if_running(sound::Message::SetDeviceVolume(role, volume));
+    }
+
+    /// Mutes or unmutes a device and saves it in the config
+    pub fn set_device_muted(&mut self, role: sound::DeviceRole, muted: bool) {
+        self.save_level(role, |level| level.muted = muted);
+        self.send_if_running(sound::Message::SetDeviceMuted(role, muted));
+    }
+
+    /// Changes the gain of the microphone and saves it in the config
+    pub fn set_mic_gain(&mut self, gain_db: f32) {
+        let mut conf = config::Config::load();
+        conf.mic.gain_db = gain_db;
+        conf.save();
+        self.mic.gain_db = gain_db;
+        self.send_if_running(sound::Message::SetMicGain(gain_db));
+    }
+
+    /// Switches the microphone between always on, push to talk and push to mute and saves it in
+    /// the config
+    pub fn set_mic_mode(&mut self, mode: config::MicMode) {
+        let mut conf = config::Config::load();
+        conf.mic.mode = mode;
+        conf.save();
+        self.mic.mode = mode;
+        self.send_if_running(sound::Message::SetMicMode(mode));
+    }
+
+    /// Changes the voice effects on the microphone to a preset, or removes them when `None`, and
+    /// saves it in the config
+    pub fn set_voice_preset(&mut self, preset: Option<String>) {
+        let mut conf = config::Config::load();
+        conf.mic.preset = preset.clone();
+        conf.save();
+        self.mic.preset = preset.clone();
+        self.send_if_running(sound::Message::SetVoicePreset(preset));
+    }
+
+    /// Replaces the bands of the EQ of a device and saves them in the config
+    pub fn set_eq(&mut self, role: sound::DeviceRole, bands: Vec<config::EqBand>) {
+        let mut conf = config::Config::load();
+        let saved = match role {
+            sound::DeviceRole::Output => &mut conf.eq.output,
+            sound::DeviceRole::Loop => &mut conf.eq.loopback,
+            sound::DeviceRole::Input => &mut conf.eq.mic,
+        };
+        *saved = bands.clone();
+        conf.save();
+        self.eq = conf.eq;
+        self.send_if_running(sound::Message::SetEq(role, bands));
+    }
+
+    /// Replaces the compressor and limiter of the output or loop device and saves them in the
+    /// config. The input has none, so it is left alone.
+    pub fn set_dynamics(&mut self, role: sound::DeviceRole, dynamics: config::Dynamics) {
+        let mut conf = config::Config::load();
+        let saved = match role {
+            sound::DeviceRole::Output => &mut conf.dynamics.output,
+            sound::DeviceRole::Loop => &mut conf.dynamics.loopback,
+            sound::DeviceRole::Input => return,
+        };
+        *saved = dynamics;
+        conf.save();
+        self.dynamics = conf.dynamics;
+        self.send_if_running(sound::Message::SetDynamics(role, dynamics));
+    }
+
+    /// Adds noise to the sounds before they are rounded to the format of the devices, and saves
+    /// it in the config
+    pub fn set_dither(&mut self, dither: bool) {
+        let mut conf = config::Config::load();
+        conf.dither = dither;
+        conf.save();
+        self.dither = dither;
+        self.send_if_running(sound::Message::SetDither(dither));
+    }
+
+    /// Changes how the microphone and the music bed are lowered while sounds play, or stops
+    /// lowering them when `None`, and saves it in the config
+    pub fn set_ducking(&mut self, ducking: Option<config::Ducking>) {
+        let mut conf = config::Config::load();
+        conf.ducking = ducking;
+        conf.save();
+        self.ducking = ducking;
+        self.send_if_running(sound::Message::SetDucking(ducking));
+    }
+
+    /// Brings the sounds to the same loudness when they play, or stops when `None`, and saves
+    /// it in the config. A sound that wasn't measured yet starts once it is, see
+    /// `sound::loudness::LoudnessCache::measure_all` to measure the sounds ahead of time.
+    pub fn set_normalization(&mut self, normalization: Option<config::Normalization>) {
+        let mut conf = config::Config::load();
+        conf.normalization = normalization;
+        conf.save();
+        self.normalization = normalization;
+        self.send_if_running(sound::Message::SetNormalization(normalization));
+    }
+
+    /// Silences the microphone while it is quiet, or stops when `None`, and saves it in the
+    /// config
+    pub fn set_noise_gate(&mut self, gate: Option<config::NoiseGate>) {
+        let mut conf = config::Config::load();
+        conf.mic.gate = gate;
+        conf.save();
+        self.mic.gate = gate;
+        self.send_if_running(sound::Message::SetNoiseGate(gate));
+    }
+
+    /// Takes the noise out of the microphone, or stops when `None`, and saves it in the config.
+    /// The noise is learnt with `learn_noise`.
+    pub fn set_noise_suppression(&mut self, suppression: Option<config::NoiseSuppression>) {
+        let mut conf = config::Config::load();
+        conf.mic.suppression = suppression;
+        conf.save();
+        self.mic.suppression = suppression;
+        self.send_if_running(sound::Message::SetNoiseSuppression(suppression));
+    }
+
+    /// Starts learning the noise of the microphone, or stops and takes it out
+    pub fn learn_noise(&self, learning: bool) {
+        self.send_if_running(sound::Message::LearnNoise(learning));
+    }
+
+    fn save_level(&mut self, role: sound::DeviceRole, change: impl Fn(&mut config::Level)) {
+        let mut conf = config::Config::load();
+        for levels in [&mut self.levels, &mut conf.levels] {
+            change(match role {
+                sound::DeviceRole::Output => &mut levels.output,
+                sound::DeviceRole::Loop => &mut levels.loopback,
+                sound::DeviceRole::Input => &mut levels.mic,
+            });
+        }
+        conf.save();
+    }
+
+    fn send_if_running(&self, message: sound::Message) {
+        if self.thread_handle.is_some() {
+            self.gui_sender
+                .send(message)
+                .expect("Error sending device change");
+        }
+    }
+
     pub fn restart(&mut self) -> Result<(), ()> {
         self.stop();
         self.load();
@@ -1,4 +1,4 @@
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use log::{error, info, warn};
 use std::collections::HashMap;
 // use std::io::BufReader;
@@ -12,179 +12,173 @@ use super::config;
 //use super::download;
 //use super::utils;
 
+pub mod backend;
 mod decoder;
+pub mod duck;
+pub mod dynamics;
+pub mod effect;
+pub mod eq;
+mod mic;
 mod sample;
 mod sink;
 mod source;
 pub mod filter;
-// pub mod freq;
+pub mod freq;
+pub mod loudness;
+pub mod meter;
+pub mod noise;
+#[cfg(feature = "wav")]
+pub mod render;
+mod watch;
 
+use backend::{Backend, Device, DeviceError};
 use decoder::Decoder;
-use miniaudio::{Context, DeviceId, DeviceType, ShareMode};
+pub use sink::InstanceId;
 use sink::Sink;
 // use source::Source;
 
-static DEFAULT_BACKENDS: [miniaudio::Backend; 5] = [
-    miniaudio::Backend::Wasapi,
-    miniaudio::Backend::DSound,
-    miniaudio::Backend::CoreAudio,
-    miniaudio::Backend::PulseAudio,
-    miniaudio::Backend::Alsa,
-];
-
-fn print_device_info(context: &Context, device_type: DeviceType, device_id: &DeviceId) {
-    // This can fail, so we have to check the result.
-    let info = match context.get_device_info(device_type, device_id, ShareMode::Shared) {
-        Ok(info) => info,
-        Err(err) => {
-            error!("\t\tfailed to get device info: {}", err);
-            return;
-        }
-    };
+/// Lists the playback and capture devices of the sound card
+pub fn list_devices() -> Result<backend::DeviceList, DeviceError> {
+    backend::MiniaudioBackend::new()?.devices()
+}
 
+fn print_device_info(info: &backend::DeviceInfo) {
     info!(
         "\t\t\tSample Rate: {}-{}Hz",
-        info.min_sample_rate(),
-        info.max_sample_rate()
+        info.sample_rates.start(),
+        info.sample_rates.end()
     );
 
     info!(
         "\t\t\tChannels: {}-{}",
-        info.min_channels(),
-        info.max_channels()
+        info.channels.start(),
+        info.channels.end()
     );
 
-    info!("\t\t\tFormats: {:?}", info.formats());
+    info!("\t\t\tFormats: {:?}", info.formats);
 }
 
 #[allow(dead_code)]
 pub fn print_possible_devices_and_exit() {
-    let context = Context::new(&DEFAULT_BACKENDS, None).expect("could not create audio context");
-    print_possible_devices(&context, true);
+    let backend = backend::MiniaudioBackend::new().expect("could not create audio context");
+    print_possible_devices(&backend, true);
 }
 
-fn print_possible_devices(context: &Context, full: bool) {
-    info!("Audio Backend: {:?}", context.backend());
+fn print_possible_devices(backend: &backend::MiniaudioBackend, full: bool) {
+    info!("Audio Backend: {:?}", backend.context().backend());
 
-    context
-        .with_devices(|playback_devices, capture_devices| {
-            info!("\tOutput Devices:");
-            for (idx, device) in playback_devices.iter().enumerate() {
-                info!("\t\t{}: {}", idx, device.name());
-                if full {
-                    print_device_info(&context, DeviceType::Playback, device.id());
-                }
+    let devices = match backend.devices() {
+        Ok(devices) => devices,
+        Err(err) => {
+            error!("failed to get devices {}", err);
+            return;
+        }
+    };
+    for (title, devices) in [
+        ("Output Devices", &devices.playback),
+        ("Input Devices", &devices.capture),
+    ] {
+        info!("\t{}:", title);
+        for (idx, device) in devices.iter().enumerate() {
+            info!("\t\t{}: {} ({})", idx, device.name, device.id);
+            if full {
+                print_device_info(device);
             }
+        }
+    }
+}
 
-            info!("\tInput Devices:");
-            for (idx, device) in capture_devices.iter().enumerate() {
-                info!("\t\t{}: {}", idx, device.name());
-                if full {
-                    print_device_info(&context, DeviceType::Capture, device.id());
-                }
-            }
-        })
-        .expect("failed to get devices");
+/// The devices the sound loop plays on, by name
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoopDevices {
+    /// Microphone passed through to the loop device, the default one when `None`
+    pub input: Option<String>,
+    /// Where the sounds are heard, the default one when `None`
+    pub output: Option<String>,
+    /// Where the sounds and the microphone are mixed for other programs to record
+    pub loopback: String,
+    /// Stable id of the loop device from `list_devices`, picked over `loopback` when present
+    pub loopback_id: Option<String>,
+    pub fallback: config::DeviceFallback,
+    /// Volume and mute of each device when the loop starts
+    pub levels: config::Levels,
+    pub mic: config::MicSettings,
+    /// EQ of each device when the loop starts
+    pub eq: config::Equalizers,
+    /// Compressor and limiter of each sink when the loop starts
+    pub dynamics: config::SinkDynamics,
+    /// Dithers the sounds of both sinks, see `Sink::set_dither`
+    pub dither: bool,
+    /// Lowers the microphone and the music bed while sounds play, off when `None`
+    pub ducking: Option<config::Ducking>,
+    /// Brings the sounds to the same loudness, off when `None`
+    pub normalization: Option<config::Normalization>,
 }
 
 pub fn run_sound_loop(
     sound_receiver: crossbeam_channel::Receiver<Message>,
     sound_sender: crossbeam_channel::Sender<Message>,
     gui_sender: crossbeam_channel::Sender<Message>,
-    input_device_identifier: Option<String>,
-    output_device_identifier: Option<String>,
-    loop_device_identifier: String,
-) -> () {
-    let mut context_config = miniaudio::ContextConfig::default();
-    context_config
-        .pulse_mut()
-        .set_application_name("mlws_lib")
-        .expect("failed to set pulse app name");
-    let context = Context::new(&DEFAULT_BACKENDS, Some(&context_config))
-        .expect("could not create audio context");
-    let mut ms_input_device = None;
-    let mut ms_output_device = None;
-    let mut ms_loop_device = None;
+    devices: LoopDevices,
+    events: Events,
+) {
+    let backend = match backend::MiniaudioBackend::new() {
+        Ok(backend) => backend,
+        Err(err) => {
+            error!("could not create audio context {}", err);
+            events.send(Event::DeviceError(err.to_string()));
+            return;
+        }
+    };
 
     info!("Possible Devices: ");
-    print_possible_devices(&context, false);
+    print_possible_devices(&backend, false);
 
-    context
-        .with_devices(|playback_devices, capture_devices| {
-            for (_, device) in playback_devices.iter().enumerate() {
-                if device.name() == loop_device_identifier {
-                    ms_loop_device = Some(device.clone());
-                }
-                if output_device_identifier.is_some()
-                    && device.name() == output_device_identifier.as_ref().unwrap()
-                {
-                    ms_output_device = Some(device.clone());
-                }
-            }
-
-            if input_device_identifier.is_none() {
-                return;
-            };
-            for (_, device) in capture_devices.iter().enumerate() {
-                if device.name() == input_device_identifier.as_ref().unwrap() {
-                    ms_input_device = Some(device.clone());
-                }
-            }
-        })
-        .expect("failed to create context");
-
-    if ms_loop_device.is_none() {
-        panic!(
-            "Could not find loop device identifier \"{}\"",
-            loop_device_identifier
-        );
-    }
-
-    if input_device_identifier.is_some() && ms_input_device.is_none() {
-        panic!(
-            "Could not find input device identifier \"{}\"",
-            input_device_identifier.unwrap()
-        );
-    }
-
-    if output_device_identifier.is_some() && ms_output_device.is_none() {
-        panic!(
-            "Could not find output device identifier \"{}\"",
-            output_device_identifier.unwrap()
-        );
-    }
+    run_sound_loop_with_backend(
+        &backend,
+        sound_receiver,
+        sound_sender,
+        gui_sender,
+        devices,
+        events,
+    );
+}
 
-    if let Some(input_device) = ms_input_device.as_ref() {
-        info!("Input device: \"{}\"", input_device.name());
+/// Runs the sound loop on the devices of `backend` until it receives `Message::Kill`.
+///
+/// Missing devices don't stop the loop, they are handled as set by `devices.fallback` and
+/// reported with `Event::DeviceLost`.
+pub fn run_sound_loop_with_backend(
+    backend: &dyn Backend,
+    sound_receiver: crossbeam_channel::Receiver<Message>,
+    sound_sender: crossbeam_channel::Sender<Message>,
+    gui_sender: crossbeam_channel::Sender<Message>,
+    devices: LoopDevices,
+    events: Events,
+) {
+    if let Some(input_device) = devices.input.as_ref() {
+        info!("Input device: \"{}\"", input_device);
     } else {
         info!("Input device: default input device");
     }
-    if let Some(output_device) = ms_output_device.as_ref() {
-        info!("Output device: \"{}\"", output_device.name());
+    if let Some(output_device) = devices.output.as_ref() {
+        info!("Output device: \"{}\"", output_device);
     } else {
         info!("Output device: default output device");
     }
-    info!(
-        "Loop device: \"{}\"",
-        ms_loop_device.as_ref().unwrap().name()
-    );
-
-    let loop_back_device = {
-        let ms_loop_device_clone = ms_loop_device.clone();
-        Some(
-            create_duplex_device(&context, ms_input_device, ms_loop_device_clone.unwrap())
-                .expect("create duplex device failed"),
-        )
-    };
+    info!("Loop device: \"{}\"", devices.loopback);
+    if let Some(loopback_id) = devices.loopback_id.as_ref() {
+        info!("Loop device id: {}", loopback_id);
+    }
+    info!("Device fallback: {:?}", devices.fallback);
 
     run_sound_message_loop(
-        context,
+        backend,
         sound_receiver,
         sound_sender,
         gui_sender,
-        ms_loop_device.unwrap(),
-        ms_output_device,
-        loop_back_device,
+        devices,
+        events,
     );
 }
 
@@ -223,7 +217,27 @@ impl std::hash::Hash for SoundKey {
 }
 
 type StartedTime = std::time::Instant;
-type SoundMap = HashMap<SoundKey, (SoundStatus, StartedTime, Option<TotalDuration>)>;
+type SoundMap = HashMap<SoundKey, Vec<SoundInstance>>;
+
+/// One copy of a sound, from the moment it is requested until it finishes
+#[derive(Debug, Clone)]
+struct SoundInstance {
+    id: InstanceId,
+    status: SoundStatus,
+    started: StartedTime,
+    total_duration: Option<TotalDuration>,
+}
+
+impl SoundInstance {
+    fn new(id: InstanceId) -> Self {
+        SoundInstance {
+            id,
+            status: SoundStatus::Downloading,
+            started: std::time::Instant::now(),
+            total_duration: None,
+        }
+    }
+}
 
 #[derive(
     Debug,
@@ -249,40 +263,266 @@ type TotalDuration = std::time::Duration;
 pub enum SoundStatus {
     Downloading,
     Playing,
+    Paused,
 }
 
+/// Status of every playing copy of every sound, with the id telling copies of a sound apart
 pub type PlayStatusVecType = Vec<(
     SoundStatus,
     config::Sound,
     PlayDuration,
     Option<TotalDuration>,
+    InstanceId,
 )>;
 
+/// How often a sound repeats its loop section
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum LoopMode {
+    /// Plays through once
+    #[default]
+    Off,
+    /// Repeats until the sound is stopped
+    Infinite,
+    /// Plays the loop section this many times in total
+    Times(u32),
+}
+
+/// Settings for a single `Message::PlaySound` request
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct PlayOptions {
+    /// Time taken to ramp up from silence when the sound starts
+    pub fade_in: std::time::Duration,
+    /// Time taken to ramp down to silence before the sound ends, also used when it is stopped
+    pub fade_out: std::time::Duration,
+    pub loop_mode: LoopMode,
+    /// Start of the loop section, overrides the sound's own loop points
+    pub loop_start: Option<std::time::Duration>,
+    /// End of the loop section, overrides the sound's own loop points
+    pub loop_end: Option<std::time::Duration>,
+}
+
+/// Pushed to every subscriber of the sound loop as playback changes
+#[derive(Debug, PartialEq, Clone)]
+pub enum Event {
+    SoundStarted(config::Sound, InstanceId),
+    /// The sound played until its end
+    SoundFinished(config::Sound, InstanceId),
+    /// The sound was stopped before its end
+    SoundStopped(config::Sound, InstanceId),
+    /// Status of every sound, sent at the interval set with `Message::SetPositionInterval`
+    PositionUpdate(PlayStatusVecType),
+    DeviceError(String),
+    /// The device used for the role went missing, with its name or "default"
+    DeviceLost(DeviceRole, String),
+    /// The device lost or stopped for the role is back and in use again
+    DeviceRestored(DeviceRole, String),
+    /// The role moved to the device asked for with `Message::SetOutputDevice` and the like
+    DeviceSwitched(DeviceRole, String),
+    VolumeChanged(f32),
+    /// The volume or mute of a device changed
+    LevelChanged(DeviceRole, config::Level),
+    /// Highest peak of the channels of the microphone, from `0.0` to `1.0`, sent along with
+    /// its `Event::Levels`
+    MicLevel(f32),
+    /// The microphone started or stopped going through, following the push keys
+    MicOpen(bool),
+    /// The voice effects on the microphone changed to the named preset, or to none
+    VoicePresetChanged(Option<String>),
+    /// Most gain the compressor and limiter of a sink took away since the last one, in
+    /// decibels, sent at the interval of `Event::PositionUpdate` while they are on
+    GainReduction(DeviceRole, f32),
+    /// Level of each channel of a sink's mix, or of the microphone for `DeviceRole::Input`,
+    /// since the last one. Sent at the interval set with `Message::SetMeterInterval`.
+    Levels(DeviceRole, Vec<meter::ChannelLevel>),
+}
+
+/// What the sound loop uses a device for
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum DeviceRole {
+    /// Plays the sounds for the user
+    Output,
+    /// Plays the sounds and the microphone for other programs
+    Loop,
+    /// The microphone passed through to the loop device
+    Input,
+}
+
+impl std::fmt::Display for DeviceRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DeviceRole::Output => write!(f, "output"),
+            DeviceRole::Loop => write!(f, "loop"),
+            DeviceRole::Input => write!(f, "input"),
+        }
+    }
+}
+
+/// Subscribers to the events of a sound loop
+#[derive(Debug, Clone, Default)]
+pub struct Events(std::sync::Arc<parking_lot::Mutex<Vec<Subscriber>>>);
+
+#[derive(Debug)]
+struct Subscriber {
+    sender: crossbeam_channel::Sender<Event>,
+    /// Events are being dropped since its channel filled up
+    behind: bool,
+}
+
+impl Events {
+    /// Receives every event sent from now on. Events are dropped for a subscriber while
+    /// `EVENT_CAPACITY` of them are waiting to be received.
+    pub fn subscribe(&self) -> crossbeam_channel::Receiver<Event> {
+        let (sender, receiver) = crossbeam_channel::bounded(EVENT_CAPACITY);
+        self.0.lock().push(Subscriber {
+            sender,
+            behind: false,
+        });
+        receiver
+    }
+
+    /// Sends `event` to every subscriber, forgetting the ones that went away
+    fn send(&self, event: Event) {
+        let mut subscribers = self.0.lock();
+        subscribers.retain_mut(|subscriber| {
+            match subscriber.sender.try_send(event.clone()) {
+                Err(crossbeam_channel::TrySendError::Full(_)) => {
+                    // Once until it catches up, it would be logged for every event otherwise
+                    if !subscriber.behind {
+                        warn!("event subscriber fell behind, dropping events");
+                        subscriber.behind = true;
+                    }
+                    true
+                }
+                Err(crossbeam_channel::TrySendError::Disconnected(_)) => false,
+                Ok(()) => {
+                    subscriber.behind = false;
+                    true
+                }
+            }
+        });
+    }
+
+    fn has_subscribers(&self) -> bool {
+        !self.0.lock().is_empty()
+    }
+
+    /// Reports the instances that were stopped, skipping the ones that never started
+    fn stopped(&self, key: &SoundKey, instances: impl IntoIterator<Item = SoundInstance>) {
+        for sound in instances {
+            if sound.status != SoundStatus::Downloading {
+                self.send(Event::SoundStopped(key.0.clone(), sound.id));
+            }
+        }
+    }
+}
+
+/// Events kept for a subscriber that isn't receiving them
+pub const EVENT_CAPACITY: usize = 1024;
+const DEFAULT_POSITION_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+/// How often the devices are listed to find the ones that went missing or came back
+const DEVICE_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Message {
-    PlaySound(config::Sound, SoundDevices),
+    PlaySound(config::Sound, SoundDevices, PlayOptions),
     StopSound(config::Sound),
+    /// Stops every sound in the named choke group
+    StopChokeGroup(String),
     StopAll,
+    /// Stops a sound after fading it out for the given time
+    StopSoundWithFade(config::Sound, std::time::Duration),
+    /// Stops every sound after fading them out for the given time
+    StopAllWithFade(std::time::Duration),
+    PauseSound(config::Sound),
+    ResumeSound(config::Sound),
+    SeekSound(config::Sound, std::time::Duration),
+    /// Gain in decibels for a sound that is already playing
+    SetSoundGain(config::Sound, f32),
+    /// Stereo position, from `-1.0` to `1.0`, for a sound that is already playing
+    SetSoundPan(config::Sound, f32),
+    PauseAll,
+    ResumeAll,
+    /// Volume of the sounds on both the output and the loop device, on top of their levels
     SetVolume(f32),
-    PlayStatus(PlayStatusVecType, f32),
-    _PlaySoundDownloaded(config::Sound, SoundDevices, std::path::PathBuf),
+    /// Volume of a device, for the input it is the microphone passed to the loop device
+    SetDeviceVolume(DeviceRole, f32),
+    /// Silences a device, keeping its volume for when it is unmuted
+    SetDeviceMuted(DeviceRole, bool),
+    ToggleDeviceMuted(DeviceRole),
+    /// Gain of the microphone in decibels
+    SetMicGain(f32),
+    SetMicMode(config::MicMode),
+    /// The push to talk or push to mute keys were pressed or released
+    SetPushKeyHeld(bool),
+    /// Changes the voice effects on the microphone to a preset of `config::MicSettings`, or
+    /// removes them when `None`
+    SetVoicePreset(Option<String>),
+    /// Changes to the preset, or back to the plain voice when it is already in use
+    ToggleVoicePreset(String),
+    /// How often `Event::PositionUpdate` is sent, `None` stops sending it
+    SetPositionInterval(Option<std::time::Duration>),
+    /// How often `Event::Levels` is sent, `None` stops sending it, which is the default
+    SetMeterInterval(Option<std::time::Duration>),
+    /// Moves the sounds playing for the user to another device, the default one when `None`
+    SetOutputDevice(Option<String>),
+    /// Moves the loop device, along with its sounds and the microphone passthrough
+    SetLoopDevice(String),
+    /// Passes another microphone to the loop device, the default one when `None`
+    SetInputDevice(Option<String>),
+    /// Replaces the bands of the EQ of a device, removing the EQ when empty. The EQ keeps its
+    /// place in the chain of filters.
+    SetEq(DeviceRole, Vec<config::EqBand>),
+    /// Replaces the compressor and limiter of the output or loop device, they are applied after
+    /// every other filter
+    SetDynamics(DeviceRole, config::Dynamics),
+    /// Adds noise to the sounds of both sinks before they are rounded to the device format
+    SetDither(bool),
+    /// Changes how the microphone and the music bed are lowered while sounds play, or stops
+    /// lowering them when `None`
+    SetDucking(Option<config::Ducking>),
+    /// Brings the sounds played from now on to the same loudness, or stops when `None`. Sounds
+    /// that weren't measured yet are measured in the background before they play.
+    SetNormalization(Option<config::Normalization>),
+    /// Silences the microphone while it is quiet, or stops when `None`
+    SetNoiseGate(Option<config::NoiseGate>),
+    /// Takes the noise learnt with `LearnNoise` out of the microphone, or stops when `None`.
+    /// The noise has to be learnt again after the settings change.
+    SetNoiseSuppression(Option<config::NoiseSuppression>),
+    /// Starts learning the noise of the microphone, which should only pick up the noise until
+    /// learning stops. Once it stops the noise is taken out.
+    LearnNoise(bool),
+    /// Appends a filter to the chain of a device, for the input it filters the microphone
+    /// passed to the loop device. A filter with the same id is replaced.
+    AddFilter(DeviceRole, filter::FilterId, filter::NewFilter),
+    RemoveFilter(DeviceRole, filter::FilterId),
+    /// Moves a filter to a position in its chain, the end when past it
+    MoveFilter(DeviceRole, filter::FilterId, usize),
+    /// Skips a filter, or stops skipping it, keeping its place in the chain
+    BypassFilter(DeviceRole, filter::FilterId, bool),
+    /// The sounds, the master volume and the levels of the devices
+    PlayStatus(PlayStatusVecType, f32, config::Levels),
+    _PlaySoundDownloaded(
+        config::Sound,
+        SoundDevices,
+        PlayOptions,
+        InstanceId,
+        std::path::PathBuf,
+    ),
     Kill,
 }
 
+#[allow(clippy::too_many_arguments)]
 fn insert_sink_with_config(
     path: &std::path::Path,
-    device: Option<miniaudio::DeviceIdAndName>,
+    device: Option<&str>,
     sink: &mut SinkDecoder,
     sound_config: config::Sound,
+    options: PlayOptions,
+    instance: InstanceId,
+    normalization: f32,
     sinks: &mut SoundMap,
 ) -> Result<()> {
-    let device_name = {
-        if let Some(device) = device.as_ref() {
-            device.name().to_string()
-        } else {
-            "default output".to_string()
-        }
-    };
+    let device_name = device.unwrap_or("default output");
     info!(
         "Playing sound config: {:?} on device: {}",
         sound_config, device_name
@@ -293,110 +533,434 @@ fn insert_sink_with_config(
     let mut reader = std::io::BufReader::with_capacity(1000 * 50, std::fs::File::open(path)?);
     let total_duration = decoder.total_duration_mut(&mut reader);
     let total_duration = total_duration;
-    sink.play(sound_config.clone().into(), decoder, None, None)?;
-
-    match sinks.entry(sound_config.into()) {
-        std::collections::hash_map::Entry::Occupied(mut entry) => {
-            let entry = entry.get_mut();
-            entry.0 = SoundStatus::Playing;
-            entry.1 = std::time::Instant::now();
-            entry.2 = total_duration;
+    let mix = sink::Mix {
+        gain: sound_config.settings.gain(),
+        pan: sound_config.settings.pan,
+        normalization,
+    };
+    let fades = sink::Fades {
+        fade_in: options.fade_in.as_secs_f32(),
+        fade_out: options.fade_out.as_secs_f32(),
+    };
+    // The fade out before the natural end needs to know where the end is
+    let end = if fades.fade_out > 0.0 {
+        total_duration.map(|duration| duration.as_secs_f32())
+    } else {
+        None
+    };
+    let repeats = match options.loop_mode {
+        LoopMode::Off => None,
+        LoopMode::Infinite => Some(None),
+        LoopMode::Times(times) => times.checked_sub(1).filter(|&n| n > 0).map(Some),
+    };
+    let loop_points = sound_config.settings.loop_points;
+    let looping = repeats.map(|repeats| sink::Looping {
+        start: options
+            .loop_start
+            .map(|start| start.as_secs_f32())
+            .or(loop_points.start)
+            .unwrap_or_default(),
+        end: options
+            .loop_end
+            .map(|end| end.as_secs_f32())
+            .or(loop_points.end),
+        repeats,
+    });
+    sink.play(
+        sound_config.clone().into(),
+        instance,
+        decoder,
+        sink::PlaySettings {
+            mix,
+            fades,
+            looping,
+            start: None,
+            end,
+            music_bed: sound_config.settings.ducking == config::SoundDucking::MusicBed,
+        },
+    )?;
+
+    let started = SoundInstance {
+        id: instance,
+        status: SoundStatus::Playing,
+        started: std::time::Instant::now(),
+        total_duration,
+    };
+    let instances = sinks.entry(sound_config.into()).or_default();
+    match instances.iter_mut().find(|sound| sound.id == instance) {
+        Some(sound) => *sound = started,
+        None => instances.push(started),
+    }
+    Ok(())
+}
+
+/// Applies the sound's retrigger policy to the copies that are already playing.
+///
+/// Returns whether a new copy should be started.
+fn retrigger(
+    sound_config: &config::Sound,
+    sinks: &mut SoundMap,
+    output_sink: &mut SinkDecoder,
+    loopback_sink: &mut SinkDecoder,
+    events: &Events,
+) -> bool {
+    let key: SoundKey = sound_config.clone().into();
+    let instances = match sinks.get_mut(&key) {
+        Some(instances) if !instances.is_empty() => instances,
+        _ => return true,
+    };
+    match sound_config.settings.retrigger {
+        config::Retrigger::Overlap { max_instances } => {
+            if let Some(max_instances) = max_instances {
+                // Oldest copies go first, leaving room for the new one
+                let excess = (instances.len() + 1).saturating_sub(max_instances.max(1) as usize);
+                for sound in instances.drain(..excess) {
+                    output_sink.fade_out_instance(&key, sound.id, None);
+                    loopback_sink.fade_out_instance(&key, sound.id, None);
+                    events.stopped(&key, Some(sound));
+                }
+            }
+            true
         }
-        std::collections::hash_map::Entry::Vacant(entry) => {
-            entry.insert((
-                SoundStatus::Playing,
-                std::time::Instant::now(),
-                total_duration,
+        config::Retrigger::Ignore => false,
+        config::Retrigger::Restart | config::Retrigger::Toggle => {
+            if let Some(instances) = sinks.remove(&key) {
+                events.stopped(&key, instances);
+            }
+            output_sink.fade_out(&key, None);
+            loopback_sink.fade_out(&key, None);
+            sound_config.settings.retrigger == config::Retrigger::Restart
+        }
+    }
+}
+
+/// Stops every sound in the choke group `group` except `except`
+fn choke(
+    group: &str,
+    except: Option<&SoundKey>,
+    sinks: &mut SoundMap,
+    output_sink: &mut SinkDecoder,
+    loopback_sink: &mut SinkDecoder,
+    events: &Events,
+) {
+    let choked: Vec<SoundKey> = sinks
+        .keys()
+        .filter(|key| Some(*key) != except)
+        .filter(|key| key.0.settings.choke_group.as_deref() == Some(group))
+        .cloned()
+        .collect();
+    for key in choked {
+        if let Some(instances) = sinks.remove(&key) {
+            events.stopped(&key, instances);
+        }
+        output_sink.fade_out(&key, None);
+        loopback_sink.fade_out(&key, None);
+    }
+}
+
+/// Status and position of every instance
+fn play_status(
+    sinks: &SoundMap,
+    output_sink: &SinkDecoder,
+    loopback_sink: &SinkDecoder,
+) -> PlayStatusVecType {
+    let mut sounds = Vec::new();
+    for (key, instances) in sinks.iter() {
+        for sound in instances {
+            let play_duration = output_sink
+                .position(key, sound.id)
+                .or_else(|| loopback_sink.position(key, sound.id))
+                .unwrap_or_else(|| sound.started.elapsed());
+            sounds.push((
+                sound.status,
+                key.0.clone(),
+                play_duration,
+                sound.total_duration,
+                sound.id,
             ));
         }
     }
-    Ok(())
+    sounds
+}
+
+/// Forgets an instance that failed to start playing
+fn drop_pending(sinks: &mut SoundMap, key: &SoundKey, instance: InstanceId) {
+    if let Some(instances) = sinks.get_mut(key) {
+        instances.retain(|sound| sound.id != instance || sound.status != SoundStatus::Downloading);
+        if instances.is_empty() {
+            sinks.remove(key);
+        }
+    }
 }
 
 type SinkDecoder = Sink<SoundKey, Decoder<std::io::BufReader<std::fs::File>>>;
 
 fn run_sound_message_loop(
-    context: Context,
+    backend: &dyn Backend,
     sound_receiver: crossbeam_channel::Receiver<Message>,
     sound_sender: crossbeam_channel::Sender<Message>,
     gui_sender: crossbeam_channel::Sender<Message>,
-    loop_device: miniaudio::DeviceIdAndName,
-    output_device: Option<miniaudio::DeviceIdAndName>,
-    loopback_device: Option<miniaudio::Device>,
-) -> () {
+    mut devices: LoopDevices,
+    events: Events,
+) {
     let mut volume: f32 = 1.0;
     let mut sinks: SoundMap = HashMap::new();
+    let mut next_instance: InstanceId = 0;
+    let mut position_ticker = crossbeam_channel::tick(DEFAULT_POSITION_INTERVAL);
+    let device_ticker = crossbeam_channel::tick(DEVICE_CHECK_INTERVAL);
+    let mut meter_ticker = crossbeam_channel::never();
 
-    let output_device_id = {
-        if let Some(device) = output_device.clone() {
-            Some(device.id().clone())
-        } else {
-            None
-        }
-    };
-
-    let mut output_sink =
-        SinkDecoder::new(&context, output_device_id).expect("failed to create output sink");
-    output_sink.start().expect("failed to start output_sink");
-
-    let mut loopback_sink = SinkDecoder::new(&context, Some(loop_device.id().clone()))
-        .expect("failed to create output sink");
-    loopback_sink
-        .start()
-        .expect("failed to start loopback_sink");
+    let mut watches = watch::DeviceWatches::new(&devices);
+    let mut output_sink = SinkDecoder::closed();
+    let mut loopback_sink = SinkDecoder::closed();
+    let mut passthrough = Passthrough::closed();
+    let mut levels = devices.levels;
+    let mut push_held = false;
+    // The filters of the voice effects in use
+    let mut voice: Vec<filter::FilterId> = Vec::new();
+    let gate_filter = filter::FilterId::new();
+    let suppressor_filter = filter::FilterId::new();
+    let noise_learning = std::sync::Arc::new(noise::NoiseLearning::default());
+    // Noise goes first, so the EQ and the effects don't work on it
+    replace_filter(
+        suppressor_filter,
+        suppressor(devices.mic.suppression, &noise_learning),
+        &mut passthrough.filters,
+    );
+    replace_filter(
+        gate_filter,
+        gate(devices.mic.gate),
+        &mut passthrough.filters,
+    );
+    let eq_filters: HashMap<DeviceRole, filter::FilterId> =
+        [DeviceRole::Output, DeviceRole::Loop, DeviceRole::Input]
+            .iter()
+            .map(|role| (*role, filter::FilterId::new()))
+            .collect();
+    for (role, bands) in [
+        (DeviceRole::Output, &devices.eq.output),
+        (DeviceRole::Loop, &devices.eq.loopback),
+        (DeviceRole::Input, &devices.eq.mic),
+    ]
+    .iter()
+    {
+        replace_filter(
+            eq_filters[role],
+            equalizer(bands.to_vec()),
+            filters_mut(
+                *role,
+                &mut output_sink,
+                &mut loopback_sink,
+                &mut passthrough,
+            ),
+        );
+    }
+    let gain_reductions: HashMap<DeviceRole, std::sync::Arc<dynamics::GainReduction>> =
+        [DeviceRole::Output, DeviceRole::Loop]
+            .iter()
+            .map(|role| (*role, std::sync::Arc::default()))
+            .collect();
+    let dynamics_filter = filter::FilterId::new();
+    for (role, settings, sink) in [
+        (
+            DeviceRole::Output,
+            devices.dynamics.output,
+            &mut output_sink,
+        ),
+        (
+            DeviceRole::Loop,
+            devices.dynamics.loopback,
+            &mut loopback_sink,
+        ),
+    ] {
+        replace_filter(
+            dynamics_filter,
+            sink_dynamics(settings, &gain_reductions[&role]),
+            sink.dynamics(),
+        );
+    }
+    output_sink.set_dither(devices.dither);
+    loopback_sink.set_dither(devices.dither);
+    let sidechain = std::sync::Arc::new(duck::Sidechain::default());
+    let duck_filter = filter::FilterId::new();
+    replace_filter(
+        duck_filter,
+        mic_duck(devices.ducking, &sidechain),
+        &mut passthrough.filters,
+    );
+    output_sink.set_sidechain(sidechain.clone());
+    loopback_sink.set_sidechain(sidechain.clone());
+    // Only read from disk once sounds are normalized
+    let mut normalizer = devices
+        .normalization
+        .map(|_| Normalizer::start(gui_sender.clone()));
+    passthrough
+        .mic
+        .set_gain(crate::utils::db_to_gain(devices.mic.gain_db));
+    passthrough
+        .mic
+        .set_open(mic_open(devices.mic.mode, push_held));
+    set_voice_preset(
+        devices.mic.preset.clone(),
+        &mut devices.mic,
+        &mut voice,
+        &mut passthrough.filters,
+        &events,
+    );
+    apply_levels(
+        volume,
+        &levels,
+        &mut output_sink,
+        &mut loopback_sink,
+        &mut passthrough,
+    );
+    watch::maintain_devices(
+        backend,
+        &mut watches,
+        &mut output_sink,
+        &mut loopback_sink,
+        &mut passthrough,
+        true,
+        &events,
+    );
 
     'mainloop: loop {
-        match sound_receiver.recv() {
-            Ok(message) => match message {
-                Message::PlaySound(sound_config, sound_devices) => {
+        let mut check_devices = false;
+        // Finished sounds, position updates and device checks wake the loop up as well as
+        // messages
+        let received = crossbeam_channel::select! {
+            recv(sound_receiver) -> message => Some(message),
+            recv(output_sink.finished()) -> _ => None,
+            recv(loopback_sink.finished()) -> _ => None,
+            recv(device_ticker) -> _ => {
+                check_devices = true;
+                None
+            }
+            recv(position_ticker) -> _ => {
+                if !sinks.is_empty() && events.has_subscribers() {
+                    events.send(Event::PositionUpdate(play_status(
+                        &sinks,
+                        &output_sink,
+                        &loopback_sink,
+                    )));
+                }
+                for (role, settings, sink) in [
+                    (DeviceRole::Output, devices.dynamics.output, &output_sink),
+                    (DeviceRole::Loop, devices.dynamics.loopback, &loopback_sink),
+                ] {
+                    let on = settings.compressor.is_some() || settings.limiter.is_some();
+                    if on && sink.is_open() && events.has_subscribers() {
+                        events.send(Event::GainReduction(role, gain_reductions[&role].take()));
+                    }
+                }
+                None
+            }
+            recv(meter_ticker) -> _ => {
+                if events.has_subscribers() {
+                    send_levels(&output_sink, &loopback_sink, &passthrough, &events);
+                }
+                None
+            }
+        };
+        match received {
+            Some(Ok(message)) => match message {
+                Message::PlaySound(sound_config, sound_devices, options) => {
+                    if !retrigger(
+                        &sound_config,
+                        &mut sinks,
+                        &mut output_sink,
+                        &mut loopback_sink,
+                        &events,
+                    ) {
+                        continue;
+                    }
+                    if let Some(group) = sound_config.settings.choke_group.as_ref() {
+                        choke(
+                            group,
+                            Some(&sound_config.clone().into()),
+                            &mut sinks,
+                            &mut output_sink,
+                            &mut loopback_sink,
+                            &events,
+                        );
+                    }
+                    let instance = next_instance;
+                    next_instance += 1;
+                    // Tracked from the start so retriggering sees copies that are still loading
+                    sinks
+                        .entry(sound_config.clone().into())
+                        .or_default()
+                        .push(SoundInstance::new(instance));
+
                     let maybe_path = { Some(sound_config.clone().wav) };
 
                     if let Some(path) = maybe_path {
-                        gui_sender
-                            .send(Message::_PlaySoundDownloaded(
-                                sound_config,
-                                sound_devices,
-                                path,
-                            ))
-                            .expect("sound channel send error");
-                    } else {
-                        match sinks.entry(sound_config.clone().into()) {
-                            std::collections::hash_map::Entry::Occupied(_) => {
-                                panic!("sink should not be occupied");
-                            }
-                            std::collections::hash_map::Entry::Vacant(entry) => {
-                                entry.insert((
-                                    SoundStatus::Downloading,
-                                    std::time::Instant::now(),
-                                    None,
-                                ));
-                            }
+                        let message = Message::_PlaySoundDownloaded(
+                            sound_config,
+                            sound_devices,
+                            options,
+                            instance,
+                            path.clone(),
+                        );
+                        match normalizer.as_ref().filter(|normalizer| {
+                            devices.normalization.is_some() && !normalizer.measured(&path)
+                        }) {
+                            // Played once it is measured, the loop keeps going meanwhile
+                            Some(normalizer) => normalizer.measure(path, message),
+                            None => gui_sender.send(message).expect("sound channel send error"),
                         }
+                    } else {
                         let gui_sender_clone = gui_sender.clone();
                         std::thread::spawn(move || {
                             gui_sender_clone
                                 .send(Message::_PlaySoundDownloaded(
                                     sound_config.clone(),
                                     sound_devices,
+                                    options,
+                                    instance,
                                     sound_config.clone().wav,
                                 ))
                                 .expect("sound channel send error");
                         });
                     }
                 }
-                Message::_PlaySoundDownloaded(sound_config, sound_devices, path) => {
+                Message::_PlaySoundDownloaded(
+                    sound_config,
+                    sound_devices,
+                    options,
+                    instance,
+                    path,
+                ) => {
+                    let key: SoundKey = sound_config.clone().into();
+                    let pending = sinks
+                        .get(&key)
+                        .into_iter()
+                        .flatten()
+                        .any(|sound| sound.id == instance);
+                    if !pending {
+                        // Stopped before it finished loading
+                        continue;
+                    }
+                    let normalization = match (devices.normalization, normalizer.as_ref()) {
+                        (Some(settings), Some(normalizer)) => normalizer.gain(settings, &path),
+                        _ => 1.0,
+                    };
                     if sound_devices == SoundDevices::Both || sound_devices == SoundDevices::Output
                     {
                         match insert_sink_with_config(
                             &path,
-                            output_device.clone(),
+                            devices.output.as_deref(),
                             &mut output_sink,
                             sound_config.clone(),
+                            options,
+                            instance,
+                            normalization,
                             &mut sinks,
                         ) {
                             Ok(path) => path,
                             Err(err) => {
                                 error!("failed to insert sound at output sink {}", err);
+                                drop_pending(&mut sinks, &key, instance);
                                 continue;
                             }
                         };
@@ -404,47 +968,325 @@ fn run_sound_message_loop(
                     if sound_devices == SoundDevices::Both || sound_devices == SoundDevices::Loop {
                         match insert_sink_with_config(
                             &path,
-                            Some(loop_device.clone()),
+                            Some(&devices.loopback),
                             &mut loopback_sink,
                             sound_config,
+                            options,
+                            instance,
+                            normalization,
                             &mut sinks,
                         ) {
                             Ok(path) => path,
                             Err(err) => {
                                 error!("failed to insert sound at loop sink {}", err);
+                                drop_pending(&mut sinks, &key, instance);
                                 continue;
                             }
                         };
                     }
+                    events.send(Event::SoundStarted(key.0.clone(), instance));
                 }
                 Message::StopSound(sound_handle) => {
-                    if let Some((_, _, _)) = sinks.remove(&sound_handle.clone().into()) {
-                        output_sink.remove(&sound_handle.clone().into());
-                        loopback_sink.remove(&sound_handle.into());
+                    let key: SoundKey = sound_handle.clone().into();
+                    if let Some(instances) = sinks.remove(&key) {
+                        events.stopped(&key, instances);
+                        output_sink.fade_out(&sound_handle.clone().into(), None);
+                        loopback_sink.fade_out(&sound_handle.into(), None);
                     };
                 }
+                Message::StopChokeGroup(group) => {
+                    choke(
+                        &group,
+                        None,
+                        &mut sinks,
+                        &mut output_sink,
+                        &mut loopback_sink,
+                        &events,
+                    );
+                }
                 Message::StopAll => {
-                    for (key, _) in sinks.drain() {
-                        output_sink.remove(&key);
-                        loopback_sink.remove(&key);
+                    for (key, instances) in sinks.drain() {
+                        events.stopped(&key, instances);
+                    }
+                    output_sink.fade_out_all(None);
+                    loopback_sink.fade_out_all(None);
+                }
+                Message::StopSoundWithFade(sound_handle, fade) => {
+                    let key: SoundKey = sound_handle.clone().into();
+                    if let Some(instances) = sinks.remove(&key) {
+                        events.stopped(&key, instances);
+                        output_sink
+                            .fade_out(&sound_handle.clone().into(), Some(fade.as_secs_f32()));
+                        loopback_sink.fade_out(&sound_handle.into(), Some(fade.as_secs_f32()));
+                    };
+                }
+                Message::StopAllWithFade(fade) => {
+                    for (key, instances) in sinks.drain() {
+                        events.stopped(&key, instances);
+                    }
+                    output_sink.fade_out_all(Some(fade.as_secs_f32()));
+                    loopback_sink.fade_out_all(Some(fade.as_secs_f32()));
+                }
+                Message::PauseSound(sound_handle) => {
+                    let key: SoundKey = sound_handle.into();
+                    if let Some(instances) = sinks.get_mut(&key) {
+                        output_sink.pause(&key);
+                        loopback_sink.pause(&key);
+                        for sound in instances.iter_mut() {
+                            if sound.status == SoundStatus::Playing {
+                                sound.status = SoundStatus::Paused;
+                            }
+                        }
+                    }
+                }
+                Message::ResumeSound(sound_handle) => {
+                    let key: SoundKey = sound_handle.into();
+                    if let Some(instances) = sinks.get_mut(&key) {
+                        output_sink.resume(&key);
+                        loopback_sink.resume(&key);
+                        for sound in instances.iter_mut() {
+                            if sound.status == SoundStatus::Paused {
+                                sound.status = SoundStatus::Playing;
+                            }
+                        }
+                    }
+                }
+                Message::SeekSound(sound_handle, position) => {
+                    let key: SoundKey = sound_handle.into();
+                    if sinks.contains_key(&key) {
+                        if let Err(err) = output_sink.seek(&key, position.as_secs_f32()) {
+                            error!("failed to seek sound at output sink {}", err);
+                        }
+                        if let Err(err) = loopback_sink.seek(&key, position.as_secs_f32()) {
+                            error!("failed to seek sound at loop sink {}", err);
+                        }
+                    }
+                }
+                Message::SetSoundGain(sound_handle, gain_db) => {
+                    let key: SoundKey = sound_handle.into();
+                    let gain = crate::utils::db_to_gain(gain_db);
+                    output_sink.set_gain(&key, gain);
+                    loopback_sink.set_gain(&key, gain);
+                }
+                Message::SetSoundPan(sound_handle, pan) => {
+                    let key: SoundKey = sound_handle.into();
+                    output_sink.set_pan(&key, pan);
+                    loopback_sink.set_pan(&key, pan);
+                }
+                Message::PauseAll => {
+                    output_sink.pause_all();
+                    loopback_sink.pause_all();
+                    for sound in sinks.values_mut().flatten() {
+                        if sound.status == SoundStatus::Playing {
+                            sound.status = SoundStatus::Paused;
+                        }
+                    }
+                }
+                Message::ResumeAll => {
+                    output_sink.resume_all();
+                    loopback_sink.resume_all();
+                    for sound in sinks.values_mut().flatten() {
+                        if sound.status == SoundStatus::Paused {
+                            sound.status = SoundStatus::Playing;
+                        }
                     }
                 }
                 Message::SetVolume(volume_new) => {
                     volume = volume_new;
-                    output_sink
-                        .set_volume(volume)
-                        .expect("failed to set volume");
-                    loopback_sink
-                        .set_volume(volume)
-                        .expect("failed to set volume");
-                }
-                Message::PlayStatus(_, _) => {
-                    let mut sounds = Vec::new();
-                    for (id, (status, instant, total_duration)) in sinks.iter() {
-                        sounds.push((*status, id.0.clone(), instant.elapsed(), *total_duration));
+                    apply_levels(
+                        volume,
+                        &levels,
+                        &mut output_sink,
+                        &mut loopback_sink,
+                        &mut passthrough,
+                    );
+                    events.send(Event::VolumeChanged(volume));
+                }
+                Message::SetDeviceVolume(role, device_volume) => {
+                    let level = level_mut(&mut levels, role);
+                    level.volume = device_volume;
+                    events.send(Event::LevelChanged(role, *level));
+                    apply_levels(
+                        volume,
+                        &levels,
+                        &mut output_sink,
+                        &mut loopback_sink,
+                        &mut passthrough,
+                    );
+                }
+                Message::SetDeviceMuted(role, muted) => {
+                    let level = level_mut(&mut levels, role);
+                    level.muted = muted;
+                    events.send(Event::LevelChanged(role, *level));
+                    apply_levels(
+                        volume,
+                        &levels,
+                        &mut output_sink,
+                        &mut loopback_sink,
+                        &mut passthrough,
+                    );
+                }
+                Message::ToggleDeviceMuted(role) => {
+                    let level = level_mut(&mut levels, role);
+                    level.muted = !level.muted;
+                    events.send(Event::LevelChanged(role, *level));
+                    apply_levels(
+                        volume,
+                        &levels,
+                        &mut output_sink,
+                        &mut loopback_sink,
+                        &mut passthrough,
+                    );
+                }
+                Message::SetPositionInterval(interval) => {
+                    position_ticker = match interval {
+                        Some(interval) => crossbeam_channel::tick(interval),
+                        None => crossbeam_channel::never(),
+                    };
+                }
+                Message::SetMeterInterval(interval) => {
+                    meter_ticker = match interval {
+                        Some(interval) => crossbeam_channel::tick(interval),
+                        None => crossbeam_channel::never(),
+                    };
+                }
+                Message::SetMicGain(gain_db) => {
+                    devices.mic.gain_db = gain_db;
+                    passthrough.mic.set_gain(crate::utils::db_to_gain(gain_db));
+                }
+                Message::SetMicMode(mode) => {
+                    devices.mic.mode = mode;
+                    set_mic_open(&passthrough, mic_open(mode, push_held), &events);
+                }
+                Message::SetPushKeyHeld(held) => {
+                    push_held = held;
+                    set_mic_open(&passthrough, mic_open(devices.mic.mode, push_held), &events);
+                }
+                Message::SetVoicePreset(preset) => {
+                    set_voice_preset(
+                        preset,
+                        &mut devices.mic,
+                        &mut voice,
+                        &mut passthrough.filters,
+                        &events,
+                    );
+                }
+                Message::ToggleVoicePreset(preset) => {
+                    let preset =
+                        Some(preset).filter(|preset| devices.mic.preset.as_ref() != Some(preset));
+                    set_voice_preset(
+                        preset,
+                        &mut devices.mic,
+                        &mut voice,
+                        &mut passthrough.filters,
+                        &events,
+                    );
+                }
+                Message::SetEq(role, bands) => {
+                    replace_filter(
+                        eq_filters[&role],
+                        equalizer(bands),
+                        filters_mut(role, &mut output_sink, &mut loopback_sink, &mut passthrough),
+                    );
+                }
+                Message::SetDynamics(role, settings) => {
+                    let (saved, sink) = match role {
+                        DeviceRole::Output => (&mut devices.dynamics.output, &mut output_sink),
+                        DeviceRole::Loop => (&mut devices.dynamics.loopback, &mut loopback_sink),
+                        DeviceRole::Input => {
+                            warn!("the {} device has no compressor or limiter", role);
+                            continue;
+                        }
+                    };
+                    *saved = settings;
+                    replace_filter(
+                        dynamics_filter,
+                        sink_dynamics(settings, &gain_reductions[&role]),
+                        sink.dynamics(),
+                    );
+                }
+                Message::SetDither(dither) => {
+                    devices.dither = dither;
+                    output_sink.set_dither(dither);
+                    loopback_sink.set_dither(dither);
+                }
+                Message::SetDucking(settings) => {
+                    devices.ducking = settings;
+                    replace_filter(
+                        duck_filter,
+                        mic_duck(settings, &sidechain),
+                        &mut passthrough.filters,
+                    );
+                }
+                Message::SetNormalization(settings) => {
+                    devices.normalization = settings;
+                    if settings.is_some() && normalizer.is_none() {
+                        normalizer = Some(Normalizer::start(gui_sender.clone()));
+                    }
+                }
+                Message::SetNoiseGate(settings) => {
+                    devices.mic.gate = settings;
+                    replace_filter(gate_filter, gate(settings), &mut passthrough.filters);
+                }
+                Message::SetNoiseSuppression(settings) => {
+                    devices.mic.suppression = settings;
+                    replace_filter(
+                        suppressor_filter,
+                        suppressor(settings, &noise_learning),
+                        &mut passthrough.filters,
+                    );
+                }
+                Message::LearnNoise(learning) => {
+                    if learning && devices.mic.suppression.is_none() {
+                        warn!("learning the noise without noise suppression");
+                    }
+                    noise_learning.set(learning);
+                }
+                Message::AddFilter(role, id, new_filter) => {
+                    filters_mut(role, &mut output_sink, &mut loopback_sink, &mut passthrough)
+                        .add(id, new_filter.make());
+                }
+                Message::RemoveFilter(role, id) => {
+                    if !filters_mut(role, &mut output_sink, &mut loopback_sink, &mut passthrough)
+                        .remove(id)
+                    {
+                        warn!("no filter {:?} on the {} device to remove", id, role);
+                    }
+                }
+                Message::MoveFilter(role, id, index) => {
+                    if !filters_mut(role, &mut output_sink, &mut loopback_sink, &mut passthrough)
+                        .move_to(id, index)
+                    {
+                        warn!("no filter {:?} on the {} device to move", id, role);
                     }
+                }
+                Message::BypassFilter(role, id, bypassed) => {
+                    if !filters_mut(role, &mut output_sink, &mut loopback_sink, &mut passthrough)
+                        .bypass(id, bypassed)
+                    {
+                        warn!("no filter {:?} on the {} device to bypass", id, role);
+                    }
+                }
+                Message::SetOutputDevice(device) => {
+                    watches.switch(DeviceRole::Output, device.clone());
+                    devices.output = device;
+                    check_devices = true;
+                }
+                Message::SetLoopDevice(device) => {
+                    watches.switch(DeviceRole::Loop, Some(device.clone()));
+                    devices.loopback = device;
+                    devices.loopback_id = None;
+                    check_devices = true;
+                }
+                Message::SetInputDevice(device) => {
+                    watches.switch(DeviceRole::Input, device.clone());
+                    devices.input = device;
+                    check_devices = true;
+                }
+                Message::PlayStatus(_, _, _) => {
+                    let sounds = play_status(&sinks, &output_sink, &loopback_sink);
                     sound_sender
-                        .send(Message::PlayStatus(sounds, volume))
+                        .send(Message::PlayStatus(sounds, volume, levels))
                         .expect("sound channel error");
                 }
                 Message::Kill => {
@@ -452,102 +1294,428 @@ fn run_sound_message_loop(
                     break 'mainloop;
                 }
             },
-            Err(err) => {
+            Some(Err(err)) => {
                 error!("message receive error {}", err);
             }
+            None => {}
         };
-        sinks.retain(|key, (status, _, _)| {
-            *status == SoundStatus::Downloading
-                || output_sink.is_playing(&key)
-                || loopback_sink.is_playing(&key)
-        });
-        if loopback_sink.stopped() {
-            loopback_sink
-                .start()
-                .expect("failed to start loopback_sink again");
-        }
-        if output_sink.stopped() {
-            output_sink
-                .start()
-                .expect("failed to start output_sink again");
+        for (key, instances) in sinks.iter_mut() {
+            instances.retain(|sound| {
+                let playing = sound.status == SoundStatus::Downloading
+                    || output_sink.is_playing(key, sound.id)
+                    || loopback_sink.is_playing(key, sound.id);
+                if !playing {
+                    events.send(Event::SoundFinished(key.0.clone(), sound.id));
+                }
+                playing
+            });
         }
-        if let Some(loopback_device) = loopback_device.as_ref() {
-            if !loopback_device.is_started() {
-                loopback_device
-                    .start()
-                    .expect("failed to start loopback device again");
-                
+        sinks.retain(|_, instances| !instances.is_empty());
+        sidechain.update(devices.ducking, ducking_playing(&sinks));
+        watch::maintain_devices(
+            backend,
+            &mut watches,
+            &mut output_sink,
+            &mut loopback_sink,
+            &mut passthrough,
+            check_devices,
+            &events,
+        );
+    }
+}
+
+fn level_mut(levels: &mut config::Levels, role: DeviceRole) -> &mut config::Level {
+    match role {
+        DeviceRole::Output => &mut levels.output,
+        DeviceRole::Loop => &mut levels.loopback,
+        DeviceRole::Input => &mut levels.mic,
+    }
+}
+
+/// Replaces the voice effects on the microphone, which are the filters in `voice`, with the ones
+/// of `preset`
+fn set_voice_preset(
+    preset: Option<String>,
+    mic: &mut config::MicSettings,
+    voice: &mut Vec<filter::FilterId>,
+    filters: &mut filter::FilterChain,
+    events: &Events,
+) {
+    let effects = match preset.as_ref() {
+        Some(name) => match mic.presets.get(name) {
+            Some(preset) => preset.effects.clone(),
+            None => {
+                warn!("no voice preset \"{}\"", name);
+                return;
             }
-        }
+        },
+        None => Vec::new(),
+    };
+    for id in voice.drain(..) {
+        filters.remove(id);
+    }
+    for effect in effects {
+        let id = filter::FilterId::new();
+        filters.add(id, effect::filter(effect));
+        voice.push(id);
+    }
+    if mic.preset != preset {
+        mic.preset = preset.clone();
+        events.send(Event::VoicePresetChanged(preset));
     }
 }
 
-fn create_duplex_device(
-    context: &Context,
-    input_device: Option<miniaudio::DeviceIdAndName>,
-    loop_device: miniaudio::DeviceIdAndName,
-) -> Result<miniaudio::Device> {
-    let loop_info = match context.get_device_info(
-        miniaudio::DeviceType::Playback,
-        loop_device.id(),
-        ShareMode::Shared,
-    ) {
-        Ok(loop_info) => loop_info,
-        Err(err) => {
-            error!("failed to get device info: {}", err);
-            return Err(anyhow!("failed to get device info: {}", err));
+/// Replaces the filter `id` in `filters` with `filter`, keeping its place, or removes it when
+/// `None`
+fn replace_filter(
+    id: filter::FilterId,
+    filter: Option<Box<dyn filter::FrameFilter>>,
+    filters: &mut filter::FilterChain,
+) {
+    let index = filters
+        .filters()
+        .iter()
+        .position(|(filter, _)| *filter == id);
+    let filter = match filter {
+        Some(filter) => filter,
+        None => {
+            filters.remove(id);
+            return;
         }
     };
+    filters.add(id, filter);
+    if let Some(index) = index {
+        filters.move_to(id, index);
+    }
+}
 
-    let mut device_config = miniaudio::DeviceConfig::new(DeviceType::Duplex);
-    let format = loop_info.formats()[0];
-    info!("duplex: format {:?}", format);
-    device_config.capture_mut().set_format(format);
-    let channels = loop_info.max_channels();
-    info!("duplex: channels {}", channels);
-    device_config.capture_mut().set_channels(channels);
-    if let Some(input_device) = input_device {
-        device_config
-            .capture_mut()
-            .set_device_id(Some(input_device.id().clone()));
-    }
-
-    let sample_rate = {
-        let default_sample_rate = 48000;
-
-        if loop_info.min_sample_rate() <= default_sample_rate
-            && loop_info.max_sample_rate() >= default_sample_rate
-        {
-            default_sample_rate
-        } else {
-            loop_info.min_sample_rate()
+/// The EQ made of `bands`, none when empty
+fn equalizer(bands: Vec<config::EqBand>) -> Option<Box<dyn filter::FrameFilter>> {
+    Some(bands)
+        .filter(|bands| !bands.is_empty())
+        .map(|bands| Box::new(eq::Equalizer::new(bands)) as Box<dyn filter::FrameFilter>)
+}
+
+/// The compressor and limiter of a sink reporting to `reduction`, none when both are off
+fn sink_dynamics(
+    settings: config::Dynamics,
+    reduction: &std::sync::Arc<dynamics::GainReduction>,
+) -> Option<Box<dyn filter::FrameFilter>> {
+    Some(settings)
+        .filter(|settings| settings.compressor.is_some() || settings.limiter.is_some())
+        .map(|settings| {
+            Box::new(dynamics::Dynamics::new(settings, reduction.clone()))
+                as Box<dyn filter::FrameFilter>
+        })
+}
+
+/// Lowers the microphone with `sidechain`, when `settings` ask for it
+fn mic_duck(
+    settings: Option<config::Ducking>,
+    sidechain: &std::sync::Arc<duck::Sidechain>,
+) -> Option<Box<dyn filter::FrameFilter>> {
+    settings
+        .filter(|settings| settings.mic)
+        .map(|_| Box::new(duck::MicDuck::new(sidechain.clone())) as Box<dyn filter::FrameFilter>)
+}
+
+/// Is a sound that ducks the others playing
+fn ducking_playing(sinks: &SoundMap) -> bool {
+    sinks.iter().any(|(key, instances)| {
+        key.0.settings.ducking == config::SoundDucking::Ducks
+            && instances
+                .iter()
+                .any(|sound| sound.status == SoundStatus::Playing)
+    })
+}
+
+/// Sends the levels of the devices that are open and metered something
+fn send_levels(
+    output_sink: &SinkDecoder,
+    loopback_sink: &SinkDecoder,
+    passthrough: &Passthrough,
+    events: &Events,
+) {
+    for (role, open, levels) in [
+        (
+            DeviceRole::Output,
+            output_sink.is_open(),
+            output_sink.take_levels(),
+        ),
+        (
+            DeviceRole::Loop,
+            loopback_sink.is_open(),
+            loopback_sink.take_levels(),
+        ),
+        (
+            DeviceRole::Input,
+            passthrough.is_open(),
+            passthrough.mic.take_levels(),
+        ),
+    ] {
+        if !open || levels.is_empty() {
+            continue;
         }
-    };
-    info!("duplex: sample_rate {}", sample_rate);
-    device_config.set_sample_rate(sample_rate);
-    device_config
-        .playback_mut()
-        .set_device_id(Some(loop_device.id().clone()));
-    println!("Setting filter up");
-    // let mut filter: filter::Filter = filter::Filter::new();
-    // let bass_id = filter.add_sample_filter(|s|{println!("{}", s); i16::sine});
-
-
-    // filter.add_freq_filter(freq::ApplyKind::Less,4000., |f, s| {s*0.});
-
-    println!("Setting data callback up");
-    device_config.set_data_callback(move |_device, output, input| {
-        output.as_bytes_mut().copy_from_slice(input.as_bytes())
-        // filter.apply(device.sample_rate() as usize, input, output)
-    });
+        if role == DeviceRole::Input {
+            let peak = levels.iter().map(|level| level.peak).fold(0.0, f32::max);
+            events.send(Event::MicLevel(peak));
+        }
+        events.send(Event::Levels(role, levels));
+    }
+}
 
-    device_config.set_stop_callback(|_device| {
-        warn!("Loopback device stopped!!!");
-    });
+/// The loudness of the sounds, measured on its own thread the first time they play
+struct Normalizer {
+    cache: std::sync::Arc<parking_lot::Mutex<loudness::LoudnessCache>>,
+    /// Sounds to measure, with the message playing them once they are
+    unmeasured: crossbeam_channel::Sender<(std::path::PathBuf, Message)>,
+}
 
-    let device = miniaudio::Device::new(Some(context.clone()), &device_config)
-        .expect("failed to open playback device");
-    device.start().expect("failed to start device");
+impl Normalizer {
+    /// Loads the cache and starts the thread measuring the sounds missing from it, which
+    /// stops with the normalizer
+    fn start(gui_sender: crossbeam_channel::Sender<Message>) -> Self {
+        let cache = std::sync::Arc::new(parking_lot::Mutex::new(loudness::LoudnessCache::load()));
+        let (unmeasured, sounds) = crossbeam_channel::unbounded::<(std::path::PathBuf, _)>();
+        let measured = cache.clone();
+        std::thread::spawn(move || {
+            // One at a time, so a sound played again while it is measured is only measured once
+            for (path, play) in sounds {
+                if let Err(err) = measure_loudness(&measured, &path) {
+                    warn!(
+                        "could not measure the loudness of {}: {}",
+                        path.display(),
+                        err
+                    );
+                }
+                if gui_sender.send(play).is_err() {
+                    break;
+                }
+            }
+        });
+        Self { cache, unmeasured }
+    }
+
+    fn measured(&self, path: &std::path::Path) -> bool {
+        self.cache.lock().get(path).is_some()
+    }
+
+    /// Measures the sound at `path` in the background, then sends `play`
+    fn measure(&self, path: std::path::PathBuf, play: Message) {
+        // The thread only stops with the normalizer or the loop
+        let _ = self.unmeasured.send((path, play));
+    }
+
+    /// Linear gain bringing the sound at `path` to the loudness of `settings`, none when it
+    /// couldn't be measured
+    fn gain(&self, settings: config::Normalization, path: &std::path::Path) -> f32 {
+        let loudness = self.cache.lock().get(path);
+        loudness.map_or(1.0, |loudness| {
+            crate::utils::db_to_gain(loudness.gain_db(&settings))
+        })
+    }
+}
+
+/// Measures the sound at `path` into `cache` and saves it, unless it was measured since it was
+/// asked for, by playing it twice or with `LoudnessCache::measure_all`
+fn measure_loudness(
+    cache: &parking_lot::Mutex<loudness::LoudnessCache>,
+    path: &std::path::Path,
+) -> Result<()> {
+    if cache.lock().get(path).is_some() {
+        return Ok(());
+    }
+    let mut saved = loudness::LoudnessCache::load();
+    if saved.get(path).is_none() {
+        saved.insert(path, loudness::measure_file(path)?)?;
+        saved.save()?;
+    }
+    cache.lock().merge(saved);
+    Ok(())
+}
+
+fn gate(settings: Option<config::NoiseGate>) -> Option<Box<dyn filter::FrameFilter>> {
+    settings
+        .map(|settings| Box::new(noise::NoiseGate::new(settings)) as Box<dyn filter::FrameFilter>)
+}
+
+/// The noise suppressor, which learns the noise while `learning` is set
+fn suppressor(
+    settings: Option<config::NoiseSuppression>,
+    learning: &std::sync::Arc<noise::NoiseLearning>,
+) -> Option<Box<dyn filter::FrameFilter>> {
+    settings.map(|settings| {
+        Box::new(noise::NoiseSuppressor::new(settings, learning.clone()))
+            as Box<dyn filter::FrameFilter>
+    })
+}
+
+/// The filters of the device used for `role`
+fn filters_mut<'a>(
+    role: DeviceRole,
+    output_sink: &'a mut SinkDecoder,
+    loopback_sink: &'a mut SinkDecoder,
+    passthrough: &'a mut Passthrough,
+) -> &'a mut filter::FilterChain {
+    match role {
+        DeviceRole::Output => output_sink.filters(),
+        DeviceRole::Loop => loopback_sink.filters(),
+        DeviceRole::Input => &mut passthrough.filters,
+    }
+}
+
+/// Sets the volume of every device from the master `volume` and the `levels` of the devices
+fn apply_levels(
+    volume: f32,
+    levels: &config::Levels,
+    output_sink: &mut SinkDecoder,
+    loopback_sink: &mut SinkDecoder,
+    passthrough: &mut Passthrough,
+) {
+    if let Err(err) = output_sink.set_volume(volume * levels.output.gain()) {
+        error!("failed to set volume of output sink {}", err);
+    }
+    if let Err(err) = loopback_sink.set_volume(volume * levels.loopback.gain()) {
+        error!("failed to set volume of loop sink {}", err);
+    }
+    if let Err(err) = passthrough.set_volume(levels.mic.gain()) {
+        error!("failed to set volume of microphone {}", err);
+    }
+}
+
+/// Does the microphone go through in `mode`
+fn mic_open(mode: config::MicMode, push_held: bool) -> bool {
+    match mode {
+        config::MicMode::AlwaysOn => true,
+        config::MicMode::PushToTalk => push_held,
+        config::MicMode::PushToMute => !push_held,
+    }
+}
+
+fn set_mic_open(passthrough: &Passthrough, open: bool, events: &Events) {
+    if passthrough.mic.is_open() != open {
+        passthrough.mic.set_open(open);
+        events.send(Event::MicOpen(open));
+    }
+}
+
+/// The duplex stream passing the microphone to the loop device
+struct Passthrough {
+    device: Option<Box<dyn Device>>,
+    /// Gain and gate of the microphone, kept across streams
+    mic: std::sync::Arc<mic::MicState>,
+    /// Applied to the microphone after its gain, only ever locked by the stream
+    filter: std::sync::Arc<parking_lot::Mutex<filter::Filter>>,
+    filters: filter::FilterChain,
+    /// The loop device the stream plays on
+    loop_device: Option<String>,
+    volume: f32,
+}
+
+impl Passthrough {
+    fn closed() -> Self {
+        let (filters, filter) = filter::FilterChain::new();
+        Self {
+            device: None,
+            mic: std::sync::Arc::new(mic::MicState::new()),
+            filter: std::sync::Arc::new(parking_lot::Mutex::new(filter)),
+            filters,
+            loop_device: None,
+            volume: 1.0,
+        }
+    }
+
+    /// Passes `input`, the default microphone when `None`, to `loop_device`
+    fn reopen(
+        &mut self,
+        backend: &dyn Backend,
+        input: Option<&str>,
+        loop_device: &str,
+    ) -> Result<(), DeviceError> {
+        // Only one stream may play the microphone on the loop device
+        self.close();
+        let device = create_duplex_device(
+            backend,
+            input,
+            loop_device,
+            self.mic.clone(),
+            self.filter.clone(),
+            &mut self.filters,
+        )?;
+        device.set_volume(self.volume)?;
+        self.device = Some(device);
+        self.loop_device = Some(loop_device.to_string());
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.device = None;
+        self.loop_device = None;
+    }
+
+    fn is_open(&self) -> bool {
+        self.device.is_some()
+    }
+
+    fn loop_device(&self) -> Option<&str> {
+        self.loop_device.as_deref()
+    }
+
+    fn start(&self) -> Result<(), DeviceError> {
+        self.device.as_ref().ok_or(DeviceError::Closed)?.start()
+    }
+
+    /// Has the open stream stopped
+    fn stopped(&self) -> bool {
+        self.device
+            .as_ref()
+            .into_iter()
+            .any(|device| !device.is_started())
+    }
+
+    /// Changes the volume of the microphone on the loop device, kept while the stream is closed
+    fn set_volume(&mut self, volume: f32) -> Result<(), DeviceError> {
+        self.volume = volume;
+        match self.device.as_ref() {
+            Some(device) => device.set_volume(volume),
+            None => Ok(()),
+        }
+    }
+}
+
+fn create_duplex_device(
+    backend: &dyn Backend,
+    input_device: Option<&str>,
+    loop_device: &str,
+    mic: std::sync::Arc<mic::MicState>,
+    filter: std::sync::Arc<parking_lot::Mutex<filter::Filter>>,
+    filters: &mut filter::FilterChain,
+) -> Result<Box<dyn Device>, DeviceError> {
+    let chain_filter = filter.clone();
+    let samples = parking_lot::Mutex::new(Vec::new());
+    let device = backend.open_duplex(
+        input_device,
+        loop_device,
+        std::sync::Arc::new(move |sample_rate, output, input| {
+            // Only ever locked here
+            let mut samples = samples.lock();
+            let channels = output.channels() as usize;
+            samples.resize(output.sample_count(), 0.0);
+            sink::read_samples(input, &mut samples);
+            mic.pass(&mut samples, channels);
+            // Only held by another stream while it is being replaced, the microphone is left
+            // out then rather than let through without its filters
+            match filter.try_lock() {
+                Some(mut filter) => filter.apply_samples(sample_rate, channels, &mut samples),
+                None => samples.iter_mut().for_each(|sample| *sample = 0.0),
+            }
+            sink::write_mix(&samples, output, None);
+        }),
+    )?;
+    // Reset before the stream starts, so the callback doesn't allocate for it
+    let (sample_rate, channels) = (device.sample_rate(), device.channels());
+    filters.set_stream(&mut chain_filter.lock(), sample_rate, channels);
+    device.start()?;
 
     Ok(device)
 }
@@ -0,0 +1,671 @@
+//! Audio devices the sound loop plays through
+
+use log::{info, warn};
+use miniaudio::{Context, DeviceType, Format, Frames, FramesMut, ShareMode};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+
+/// Called with the sample rate, the buffer to fill and the captured input whenever a device
+/// needs more audio
+pub type DataCallback = Arc<dyn Fn(u32, &mut FramesMut, &Frames) + Send + Sync>;
+
+/// Why a device couldn't be opened or kept running
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceError {
+    /// No device has this name, it may have been unplugged
+    NotFound(String),
+    /// There is no default device to fall back to
+    NoDefault,
+    /// The device was lost and nothing has been opened since
+    Closed,
+    /// The backend failed, with its message
+    Backend(String),
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceError::NotFound(name) => write!(f, "could not find device \"{}\"", name),
+            DeviceError::NoDefault => write!(f, "there is no default device"),
+            DeviceError::Closed => write!(f, "the device is closed"),
+            DeviceError::Backend(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for DeviceError {}
+
+/// Wraps a miniaudio error with what was being done
+fn backend_error(what: &str, err: impl fmt::Display) -> DeviceError {
+    DeviceError::Backend(format!("{} {}", what, err))
+}
+
+/// Describes a device, as listed by `Backend::devices`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    /// Display name, which is what the devices are opened by
+    pub name: String,
+    /// Identifies the device across runs, even if its name changes
+    pub id: String,
+    pub sample_rates: RangeInclusive<u32>,
+    pub channels: RangeInclusive<u32>,
+    /// Sample formats the device supports natively
+    pub formats: Vec<Format>,
+    pub is_default: bool,
+}
+
+/// The playback and capture devices of a backend
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DeviceList {
+    pub playback: Vec<DeviceInfo>,
+    pub capture: Vec<DeviceInfo>,
+}
+
+impl DeviceList {
+    /// The playback device with the stable `id`
+    pub fn playback_by_id(&self, id: &str) -> Option<&DeviceInfo> {
+        self.playback.iter().find(|device| device.id == id)
+    }
+}
+
+/// A stream opened on an audio device, pulling audio from its `DataCallback` while started
+pub trait Device {
+    fn start(&self) -> Result<(), DeviceError>;
+    fn stop(&self) -> Result<(), DeviceError>;
+    fn is_started(&self) -> bool;
+    /// Has the device stopped without being restarted since
+    fn stopped(&self) -> bool;
+    fn volume(&self) -> Result<f32, DeviceError>;
+    fn set_volume(&self, volume: f32) -> Result<(), DeviceError>;
+    /// Sample rate the callback is called with
+    fn sample_rate(&self) -> u32;
+    /// Channels of the buffers the callback fills
+    fn channels(&self) -> usize;
+}
+
+/// Opens the devices used by the sound loop, identified by their names
+pub trait Backend {
+    fn devices(&self) -> Result<DeviceList, DeviceError>;
+
+    /// Opens a stream on the playback device `device`, the default one when `None`, in whatever
+    /// format the device uses. The callback converts its mix to that format.
+    fn open_playback(
+        &self,
+        device: Option<&str>,
+        callback: DataCallback,
+    ) -> Result<Box<dyn Device>, DeviceError>;
+
+    /// Opens a stream capturing from `input`, the default one when `None`, and playing on
+    /// `output`. Both sides share the same format.
+    fn open_duplex(
+        &self,
+        input: Option<&str>,
+        output: &str,
+        callback: DataCallback,
+    ) -> Result<Box<dyn Device>, DeviceError>;
+}
+
+static DEFAULT_BACKENDS: [miniaudio::Backend; 5] = [
+    miniaudio::Backend::Wasapi,
+    miniaudio::Backend::DSound,
+    miniaudio::Backend::CoreAudio,
+    miniaudio::Backend::PulseAudio,
+    miniaudio::Backend::Alsa,
+];
+
+/// The sound card, through miniaudio
+pub struct MiniaudioBackend {
+    context: Context,
+}
+
+impl MiniaudioBackend {
+    pub fn new() -> Result<Self, DeviceError> {
+        let mut context_config = miniaudio::ContextConfig::default();
+        context_config
+            .pulse_mut()
+            .set_application_name("mlws_lib")
+            .map_err(|err| backend_error("failed to set pulse app name", err))?;
+        let context = Context::new(&DEFAULT_BACKENDS, Some(&context_config))
+            .map_err(|err| backend_error("could not create audio context", err))?;
+        Ok(Self { context })
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    fn find(
+        &self,
+        device_type: DeviceType,
+        name: &str,
+    ) -> Result<miniaudio::DeviceIdAndName, DeviceError> {
+        let mut found = None;
+        self.context
+            .with_devices(|playback_devices, capture_devices| {
+                let devices = if device_type == DeviceType::Capture {
+                    capture_devices
+                } else {
+                    playback_devices
+                };
+                found = devices.iter().find(|device| device.name() == name).cloned();
+            })
+            .map_err(|err| backend_error("failed to list devices", err))?;
+        found.ok_or_else(|| DeviceError::NotFound(name.to_string()))
+    }
+
+    fn describe(
+        &self,
+        device_type: DeviceType,
+        devices: &[miniaudio::DeviceIdAndName],
+    ) -> Vec<DeviceInfo> {
+        devices
+            .iter()
+            .enumerate()
+            .map(|(index, device)| {
+                let mut info = DeviceInfo {
+                    name: device.name().to_string(),
+                    id: stable_id(device.id()),
+                    sample_rates: 0..=0,
+                    channels: 0..=0,
+                    formats: Vec::new(),
+                    is_default: index == 0,
+                };
+                match self
+                    .context
+                    .get_device_info(device_type, device.id(), ShareMode::Shared)
+                {
+                    Ok(details) => {
+                        info.sample_rates = details.min_sample_rate()..=details.max_sample_rate();
+                        info.channels = details.min_channels()..=details.max_channels();
+                        info.formats = details.formats().to_vec();
+                    }
+                    Err(err) => warn!("failed to get device info of {}: {}", info.name, err),
+                }
+                info
+            })
+            .collect()
+    }
+
+    fn open(
+        &self,
+        mut device_config: miniaudio::DeviceConfig,
+        callback: DataCallback,
+    ) -> Result<Box<dyn Device>, DeviceError> {
+        device_config.set_data_callback(move |device, output, input| {
+            callback(device.sample_rate(), output, input)
+        });
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stopped_clone = Arc::clone(&stopped);
+        device_config.set_stop_callback(move |_device| {
+            stopped_clone.store(true, Ordering::Relaxed);
+        });
+        let device = miniaudio::Device::new(Some(self.context.clone()), &device_config)
+            .map_err(|err| backend_error("failed to create miniaudio device", err))?;
+        Ok(Box::new(MiniaudioDevice { device, stopped }))
+    }
+}
+
+impl Backend for MiniaudioBackend {
+    /// miniaudio 0.10 doesn't say which device is the default, the first one listed is taken
+    /// as the default as it is on most backends
+    fn devices(&self) -> Result<DeviceList, DeviceError> {
+        let mut found = (Vec::new(), Vec::new());
+        self.context
+            .with_devices(|playback_devices, capture_devices| {
+                found.0 = playback_devices.to_vec();
+                found.1 = capture_devices.to_vec();
+            })
+            .map_err(|err| backend_error("failed to list devices", err))?;
+        Ok(DeviceList {
+            playback: self.describe(DeviceType::Playback, &found.0),
+            capture: self.describe(DeviceType::Capture, &found.1),
+        })
+    }
+
+    fn open_playback(
+        &self,
+        device: Option<&str>,
+        callback: DataCallback,
+    ) -> Result<Box<dyn Device>, DeviceError> {
+        let device_id = match device {
+            Some(name) => Some(self.find(DeviceType::Playback, name)?.id().clone()),
+            None => None,
+        };
+        let mut device_config = miniaudio::DeviceConfig::new(DeviceType::Playback);
+        device_config.playback_mut().set_device_id(device_id);
+        // Unknown leaves the format to the device
+        device_config
+            .playback_mut()
+            .set_format(miniaudio::Format::Unknown);
+        self.open(device_config, callback)
+    }
+
+    fn open_duplex(
+        &self,
+        input: Option<&str>,
+        output: &str,
+        callback: DataCallback,
+    ) -> Result<Box<dyn Device>, DeviceError> {
+        let loop_device = self.find(DeviceType::Playback, output)?;
+        let loop_info = self
+            .context
+            .get_device_info(DeviceType::Playback, loop_device.id(), ShareMode::Shared)
+            .map_err(|err| backend_error("failed to get device info:", err))?;
+
+        let mut device_config = miniaudio::DeviceConfig::new(DeviceType::Duplex);
+        let format = loop_info.formats()[0];
+        info!("duplex: format {:?}", format);
+        device_config.capture_mut().set_format(format);
+        let channels = loop_info.max_channels();
+        info!("duplex: channels {}", channels);
+        device_config.capture_mut().set_channels(channels);
+        if let Some(input) = input {
+            let input_device = self.find(DeviceType::Capture, input)?;
+            device_config
+                .capture_mut()
+                .set_device_id(Some(input_device.id().clone()));
+        }
+
+        let sample_rate = {
+            let default_sample_rate = 48000;
+
+            if loop_info.min_sample_rate() <= default_sample_rate
+                && loop_info.max_sample_rate() >= default_sample_rate
+            {
+                default_sample_rate
+            } else {
+                loop_info.min_sample_rate()
+            }
+        };
+        info!("duplex: sample_rate {}", sample_rate);
+        device_config.set_sample_rate(sample_rate);
+        device_config
+            .playback_mut()
+            .set_device_id(Some(loop_device.id().clone()));
+        self.open(device_config, callback)
+    }
+}
+
+/// The backend's id of the device in hex, trailing zeros left out
+fn stable_id(id: &miniaudio::DeviceId) -> String {
+    // The id is a plain union of strings and integers, which miniaudio zeroes before filling in
+    let bytes = unsafe {
+        std::slice::from_raw_parts(
+            id as *const miniaudio::DeviceId as *const u8,
+            std::mem::size_of::<miniaudio::DeviceId>(),
+        )
+    };
+    let end = bytes
+        .iter()
+        .rposition(|byte| *byte != 0)
+        .map_or(0, |last| last + 1);
+    bytes[..end]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+struct MiniaudioDevice {
+    device: miniaudio::Device,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Device for MiniaudioDevice {
+    fn start(&self) -> Result<(), DeviceError> {
+        self.device
+            .start()
+            .map_err(|err| backend_error("Could not start device", err))?;
+        self.stopped.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), DeviceError> {
+        self.stopped.store(true, Ordering::Relaxed);
+        if self.device.is_started() {
+            self.device
+                .stop()
+                .map_err(|err| backend_error("Could not stop device", err))?;
+        }
+        Ok(())
+    }
+
+    fn is_started(&self) -> bool {
+        self.device.is_started()
+    }
+
+    fn stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    fn volume(&self) -> Result<f32, DeviceError> {
+        self.device
+            .get_master_volume()
+            .map_err(|err| backend_error("Could not get volume", err))
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<(), DeviceError> {
+        self.device
+            .set_master_volume(volume)
+            .map_err(|err| backend_error("Could not set volume", err))
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.device.sample_rate()
+    }
+
+    fn channels(&self) -> usize {
+        self.device.playback().channels() as usize
+    }
+}
+
+/// A backend without a sound card, for running the sound loop in tests.
+///
+/// Its devices only pull audio when `advance` is called. Everything they play is kept, per
+/// playback device, until `take_output` is called.
+#[derive(Clone)]
+pub struct NullBackend {
+    sample_rate: u32,
+    channels: u16,
+    state: Arc<parking_lot::Mutex<NullState>>,
+}
+
+struct NullState {
+    playback: Vec<String>,
+    capture: Vec<String>,
+    streams: Vec<Weak<NullStream>>,
+    input: HashMap<String, VecDeque<i16>>,
+    output: HashMap<String, Vec<i16>>,
+    unplugged: Vec<(String, DeviceType)>,
+}
+
+impl NullState {
+    fn is_plugged(&self, stream: &NullStream) -> bool {
+        self.playback.contains(&stream.output)
+            && stream
+                .input
+                .iter()
+                .all(|input| self.capture.contains(input))
+    }
+}
+
+struct NullStream {
+    input: Option<String>,
+    output: String,
+    callback: DataCallback,
+    started: AtomicBool,
+    stopped: AtomicBool,
+    volume: parking_lot::Mutex<f32>,
+}
+
+impl NullBackend {
+    /// A backend with the given device names, the first of each list being the default one.
+    /// Every device runs at 48 kHz in stereo.
+    pub fn new(playback: &[&str], capture: &[&str]) -> Self {
+        Self {
+            sample_rate: 48000,
+            channels: 2,
+            state: Arc::new(parking_lot::Mutex::new(NullState {
+                playback: playback.iter().map(|name| name.to_string()).collect(),
+                capture: capture.iter().map(|name| name.to_string()).collect(),
+                streams: Vec::new(),
+                input: HashMap::new(),
+                output: HashMap::new(),
+                unplugged: Vec::new(),
+            })),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    /// Queues interleaved samples to be captured from the input device `device`
+    pub fn push_input(&self, device: &str, samples: &[i16]) {
+        let mut state = self.state.lock();
+        state
+            .input
+            .entry(device.to_string())
+            .or_default()
+            .extend(samples);
+    }
+
+    /// Takes the interleaved samples played on `device` so far
+    pub fn take_output(&self, device: &str) -> Vec<i16> {
+        let mut state = self.state.lock();
+        state.output.remove(device).unwrap_or_default()
+    }
+
+    /// Removes `device` like a headset being unplugged, stopping every stream using it
+    pub fn unplug(&self, device: &str) {
+        let mut guard = self.state.lock();
+        let state = &mut *guard;
+        for (devices, device_type) in [
+            (&mut state.playback, DeviceType::Playback),
+            (&mut state.capture, DeviceType::Capture),
+        ] {
+            if let Some(index) = devices.iter().position(|name| name == device) {
+                devices.remove(index);
+                state.unplugged.push((device.to_string(), device_type));
+            }
+        }
+        for stream in state.streams.iter().filter_map(Weak::upgrade) {
+            if !state.is_plugged(&stream) {
+                stream.started.store(false, Ordering::Relaxed);
+                stream.stopped.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Brings back a device removed with `unplug`, after the other devices of its kind
+    pub fn plug(&self, device: &str) {
+        let mut state = self.state.lock();
+        let unplugged = std::mem::take(&mut state.unplugged);
+        for (name, device_type) in unplugged {
+            if name != device {
+                state.unplugged.push((name, device_type));
+            } else if device_type == DeviceType::Capture {
+                state.capture.push(name);
+            } else {
+                state.playback.push(name);
+            }
+        }
+    }
+
+    /// Runs every started stream for `frames` frames.
+    ///
+    /// Streams on the same playback device are mixed together, and every playback device
+    /// records the same number of frames so their outputs line up.
+    pub fn advance(&self, frames: usize) {
+        let sample_count = frames * self.channels as usize;
+        let (streams, inputs) = {
+            let mut state = self.state.lock();
+            state.streams.retain(|stream| stream.strong_count() > 0);
+            let streams: Vec<Arc<NullStream>> = state
+                .streams
+                .iter()
+                .filter_map(Weak::upgrade)
+                .filter(|stream| stream.started.load(Ordering::Relaxed))
+                .collect();
+            let mut inputs = HashMap::new();
+            for name in state.capture.clone() {
+                let queue = state.input.entry(name.clone()).or_default();
+                let mut samples: Vec<i16> = queue.drain(..sample_count.min(queue.len())).collect();
+                samples.resize(sample_count, 0);
+                inputs.insert(name, samples);
+            }
+            (streams, inputs)
+        };
+
+        // Called without holding the state so callbacks are free to use the backend
+        let silence = vec![0; sample_count];
+        let mut mixes: HashMap<String, Vec<i16>> = HashMap::new();
+        for stream in streams {
+            let input = stream
+                .input
+                .as_ref()
+                .and_then(|name| inputs.get(name))
+                .unwrap_or(&silence);
+            let mut output = vec![0i16; sample_count];
+            (stream.callback)(
+                self.sample_rate,
+                &mut FramesMut::wrap(&mut output, miniaudio::Format::S16, self.channels as u32),
+                &Frames::wrap(input, miniaudio::Format::S16, self.channels as u32),
+            );
+            let volume = *stream.volume.lock();
+            let mix = mixes
+                .entry(stream.output.clone())
+                .or_insert_with(|| vec![0; sample_count]);
+            for (mixed, sample) in mix.iter_mut().zip(output) {
+                *mixed = mixed.saturating_add((sample as f32 * volume).round() as i16);
+            }
+        }
+
+        let mut state = self.state.lock();
+        for name in state.playback.clone() {
+            let mix = mixes.remove(&name).unwrap_or_else(|| vec![0; sample_count]);
+            state.output.entry(name).or_default().extend(mix);
+        }
+    }
+
+    fn open_stream(
+        &self,
+        input: Option<String>,
+        output: String,
+        callback: DataCallback,
+    ) -> Box<dyn Device> {
+        let stream = Arc::new(NullStream {
+            input,
+            output,
+            callback,
+            started: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            volume: parking_lot::Mutex::new(1.0),
+        });
+        self.state.lock().streams.push(Arc::downgrade(&stream));
+        Box::new(NullDevice {
+            stream,
+            state: Arc::clone(&self.state),
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+        })
+    }
+
+    /// `name`, or the default device of `devices` when `None`
+    fn resolve(devices: &[String], name: Option<&str>) -> Result<String, DeviceError> {
+        match name {
+            Some(name) if devices.iter().any(|device| device == name) => Ok(name.to_string()),
+            Some(name) => Err(DeviceError::NotFound(name.to_string())),
+            None => devices.first().cloned().ok_or(DeviceError::NoDefault),
+        }
+    }
+}
+
+impl Backend for NullBackend {
+    /// Ids are the names prefixed with `null:`, every device supports 48 kHz stereo in 16 bit
+    fn devices(&self) -> Result<DeviceList, DeviceError> {
+        let describe = |devices: &[String]| {
+            devices
+                .iter()
+                .enumerate()
+                .map(|(index, name)| DeviceInfo {
+                    name: name.clone(),
+                    id: format!("null:{}", name),
+                    sample_rates: self.sample_rate..=self.sample_rate,
+                    channels: self.channels as u32..=self.channels as u32,
+                    formats: vec![Format::S16],
+                    is_default: index == 0,
+                })
+                .collect()
+        };
+        let state = self.state.lock();
+        Ok(DeviceList {
+            playback: describe(&state.playback),
+            capture: describe(&state.capture),
+        })
+    }
+
+    fn open_playback(
+        &self,
+        device: Option<&str>,
+        callback: DataCallback,
+    ) -> Result<Box<dyn Device>, DeviceError> {
+        let output = Self::resolve(&self.state.lock().playback, device)?;
+        Ok(self.open_stream(None, output, callback))
+    }
+
+    fn open_duplex(
+        &self,
+        input: Option<&str>,
+        output: &str,
+        callback: DataCallback,
+    ) -> Result<Box<dyn Device>, DeviceError> {
+        let (input, output) = {
+            let state = self.state.lock();
+            (
+                Self::resolve(&state.capture, input).ok(),
+                Self::resolve(&state.playback, Some(output))?,
+            )
+        };
+        if input.is_none() {
+            warn!("null backend has no capture device, the duplex stream captures silence");
+        }
+        Ok(self.open_stream(input, output, callback))
+    }
+}
+
+struct NullDevice {
+    stream: Arc<NullStream>,
+    state: Arc<parking_lot::Mutex<NullState>>,
+    sample_rate: u32,
+    channels: u16,
+}
+
+impl Device for NullDevice {
+    fn start(&self) -> Result<(), DeviceError> {
+        if !self.state.lock().is_plugged(&self.stream) {
+            return Err(DeviceError::NotFound(self.stream.output.clone()));
+        }
+        self.stream.started.store(true, Ordering::Relaxed);
+        self.stream.stopped.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), DeviceError> {
+        self.stream.started.store(false, Ordering::Relaxed);
+        self.stream.stopped.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn is_started(&self) -> bool {
+        self.stream.started.load(Ordering::Relaxed)
+    }
+
+    fn stopped(&self) -> bool {
+        self.stream.stopped.load(Ordering::Relaxed)
+    }
+
+    fn volume(&self) -> Result<f32, DeviceError> {
+        Ok(*self.stream.volume.lock())
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<(), DeviceError> {
+        *self.stream.volume.lock() = volume;
+        Ok(())
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.channels as usize
+    }
+}
@@ -3,7 +3,9 @@ use std::io::{Read, Seek, SeekFrom};
 use std::mem;
 use std::time::Duration;
 
-use crate::sound::source::Source;
+use super::shared::SharedReader;
+use crate::sound::sample::int_to_f32;
+use crate::sound::source::{SeekError, Source};
 
 use claxon::FlacReader;
 
@@ -12,8 +14,12 @@ pub struct FlacDecoder<R>
 where
     R: Read + Seek,
 {
-    reader: FlacReader<R>,
+    reader: FlacReader<SharedReader<R>>,
+    shared: SharedReader<R>,
+    stream_start: u64,
     current_block: Vec<i32>,
+    /// Number of sample frames in the stream before `current_block`
+    frames_before_current: u64,
     current_block_channel_len: usize,
     current_block_off: usize,
     bits_per_sample: u32,
@@ -32,14 +38,19 @@ where
             return Err(data);
         }
 
-        let reader = FlacReader::new(data).unwrap();
+        let stream_start = data.stream_position().unwrap();
+        let shared = SharedReader::new(data);
+        let reader = FlacReader::new(shared.clone()).unwrap();
         let spec = reader.streaminfo();
 
         Ok(FlacDecoder {
             reader,
+            shared,
+            stream_start,
             current_block: Vec::with_capacity(
                 spec.max_block_size as usize * spec.channels as usize,
             ),
+            frames_before_current: 0,
             current_block_channel_len: 1,
             current_block_off: 0,
             bits_per_sample: spec.bits_per_sample,
@@ -48,6 +59,45 @@ where
             samples: spec.samples,
         })
     }
+
+    /// Seeks to the sample frame closest to `pos`
+    ///
+    /// Whole blocks are skipped until `pos` is reached, seeking backwards starts again from the
+    /// beginning of the stream.
+    pub fn seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let target = (pos.as_secs_f64() * self.sample_rate as f64) as u64;
+        if target < self.frames_before_current {
+            self.shared.rewind_to(self.stream_start)?;
+            self.reader = FlacReader::new(self.shared.clone())
+                .map_err(|err| SeekError::Io(err.to_string()))?;
+            self.current_block.clear();
+            self.current_block_off = 0;
+            self.frames_before_current = 0;
+        }
+        loop {
+            let block_frames = (self.current_block.len() / self.channels as usize) as u64;
+            if target < self.frames_before_current + block_frames {
+                self.current_block_off =
+                    (target - self.frames_before_current) as usize * self.channels as usize;
+                return Ok(());
+            }
+            let buffer = mem::replace(&mut self.current_block, Vec::new());
+            match self.reader.blocks().read_next_or_eof(buffer) {
+                Ok(Some(block)) => {
+                    self.frames_before_current += block_frames;
+                    self.current_block_channel_len = (block.len() / block.channels()) as usize;
+                    self.current_block = block.into_buffer();
+                    self.current_block_off = 0;
+                }
+                Ok(None) => {
+                    // Past the end, leave the decoder exhausted
+                    self.frames_before_current += block_frames;
+                    return Ok(());
+                }
+                Err(err) => return Err(SeekError::Io(err.to_string())),
+            }
+        }
+    }
 }
 
 impl<R> Source for FlacDecoder<R>
@@ -82,11 +132,10 @@ impl<R> Iterator for FlacDecoder<R>
 where
     R: Read + Seek,
 {
-    type Item = i16;
+    type Item = f32;
 
     #[inline]
-    fn next(&mut self) -> Option<i16> {
-        use std::cmp::Ordering;
+    fn next(&mut self) -> Option<f32> {
         loop {
             if self.current_block_off < self.current_block.len() {
                 // Read from current block.
@@ -95,17 +144,14 @@ where
                     + self.current_block_off / self.channels as usize;
                 let raw_val = self.current_block[real_offset];
                 self.current_block_off += 1;
-                let real_val = match self.bits_per_sample.cmp(&16) {
-                    Ordering::Greater => (raw_val >> (self.bits_per_sample - 16)) as i16,
-                    Ordering::Less => (raw_val << (16 - self.bits_per_sample)) as i16,
-                    Ordering::Equal => raw_val as i16,
-                };
-                return Some(real_val as i16);
+                return Some(int_to_f32(raw_val, self.bits_per_sample));
             }
 
+            let block_frames = (self.current_block.len() / self.channels as usize) as u64;
             let buffer = mem::replace(&mut self.current_block, Vec::new());
             match self.reader.blocks().read_next_or_eof(buffer) {
                 Ok(Some(block)) => {
+                    self.frames_before_current += block_frames;
                     self.current_block_channel_len = (block.len() / block.channels()) as usize;
                     self.current_block = block.into_buffer();
                     self.current_block_off = 0;
@@ -6,7 +6,7 @@ use std::fmt;
 use std::io::{Read, Seek};
 use std::time::Duration;
 
-use super::source::Source;
+use super::source::{SeekError, Source};
 
 #[cfg(feature = "flac")]
 mod flac;
@@ -21,6 +21,9 @@ mod wav;
 #[cfg(feature = "xm")]
 mod xm;
 
+#[cfg(any(feature = "flac", feature = "mp3"))]
+mod shared;
+
 /// Source of audio samples from decoding a file.
 ///
 /// Supports MP3, WAV, Vorbis and Flac.
@@ -155,9 +158,9 @@ impl<R> Iterator for Decoder<R>
 where
     R: Read + Seek,
 {
-    type Item = i16;
+    type Item = f32;
 
-    fn next(&mut self) -> Option<i16> {
+    fn next(&mut self) -> Option<f32> {
         None
     }
 }
@@ -174,10 +177,10 @@ impl<R> Iterator for Decoder<R>
 where
     R: Read + Seek,
 {
-    type Item = i16;
+    type Item = f32;
 
     #[inline]
-    fn next(&mut self) -> Option<i16> {
+    fn next(&mut self) -> Option<f32> {
         match self.0 {
             #[cfg(feature = "wav")]
             DecoderImpl::Wav(ref mut source) => source.next(),
@@ -321,6 +324,24 @@ where
             DecoderImpl::XM(ref source) => source.total_duration(),
         }
     }
+
+    #[inline]
+    fn seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        match self.0 {
+            #[cfg(feature = "wav")]
+            DecoderImpl::Wav(ref mut source) => source.seek(pos),
+            #[cfg(feature = "vorbis")]
+            DecoderImpl::Vorbis(ref mut source) => source.seek(pos),
+            #[cfg(feature = "opus")]
+            DecoderImpl::Opus(ref mut source) => source.seek(pos),
+            #[cfg(feature = "flac")]
+            DecoderImpl::Flac(ref mut source) => source.seek(pos),
+            #[cfg(feature = "mp3")]
+            DecoderImpl::Mp3(ref mut source) => source.seek(pos),
+            #[cfg(feature = "xm")]
+            DecoderImpl::XM(ref mut source) => source.seek(pos),
+        }
+    }
 }
 
 /// Error that can happen when creating a decoder.
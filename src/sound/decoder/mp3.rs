@@ -1,5 +1,7 @@
 // Initial version from Rodio APACHE LICENSE 2.0
-use crate::sound::source::Source;
+use super::shared::SharedReader;
+use crate::sound::sample::Sample;
+use crate::sound::source::{SeekError, Source};
 use log::{trace};
 use minimp3::{Decoder, Frame};
 use std::io::SeekFrom;
@@ -10,9 +12,13 @@ pub struct Mp3Decoder<R>
 where
     R: Read + Seek,
 {
-    decoder: Decoder<R>,
+    decoder: Decoder<SharedReader<R>>,
+    reader: SharedReader<R>,
+    stream_start: u64,
     current_frame: Frame,
     current_frame_offset: usize,
+    /// Number of sample frames in the stream before `current_frame`
+    frames_before_current: u64,
 }
 
 fn is_mp3<R>(mut data: R) -> bool
@@ -45,16 +51,58 @@ where
         if !is_mp3(data.by_ref()) {
             return Err(data);
         }
-        let mut decoder = Decoder::new(data);
+        let stream_start = data.stream_position().unwrap();
+        let reader = SharedReader::new(data);
+        let mut decoder = Decoder::new(reader.clone());
         let current_frame = decoder.next_frame().unwrap();
 
         Ok(Mp3Decoder {
             decoder,
+            reader,
+            stream_start,
             current_frame,
             current_frame_offset: 0,
+            frames_before_current: 0,
         })
     }
 
+    /// Seeks to the sample frame closest to `pos`
+    ///
+    /// MP3 has no index, so this decodes and discards whole frames until it reaches `pos`.
+    /// Seeking backwards restarts decoding from the beginning of the stream.
+    pub fn seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let target = (pos.as_secs_f64() * self.current_frame.sample_rate as f64) as u64;
+        if target < self.frames_before_current {
+            self.reader.rewind_to(self.stream_start)?;
+            self.decoder = Decoder::new(self.reader.clone());
+            self.current_frame = self
+                .decoder
+                .next_frame()
+                .map_err(|err| SeekError::Io(format!("{:?}", err)))?;
+            self.frames_before_current = 0;
+        }
+        loop {
+            let frame_len =
+                (self.current_frame.data.len() / self.current_frame.channels.max(1)) as u64;
+            if target < self.frames_before_current + frame_len {
+                self.current_frame_offset =
+                    (target - self.frames_before_current) as usize * self.current_frame.channels;
+                return Ok(());
+            }
+            match self.decoder.next_frame() {
+                Ok(frame) => {
+                    self.frames_before_current += frame_len;
+                    self.current_frame = frame;
+                }
+                Err(_) => {
+                    // Past the end, leave the decoder exhausted
+                    self.current_frame_offset = self.current_frame.data.len();
+                    return Ok(());
+                }
+            }
+        }
+    }
+
     pub fn total_duration_mut<T>(&self, reader: &mut T) -> Option<Duration>
     where
         T: std::io::Read,
@@ -127,13 +175,17 @@ impl<R> Iterator for Mp3Decoder<R>
 where
     R: Read + Seek,
 {
-    type Item = i16;
+    type Item = f32;
 
     #[inline]
-    fn next(&mut self) -> Option<i16> {
+    fn next(&mut self) -> Option<f32> {
         if self.current_frame_offset == self.current_frame.data.len() {
             match self.decoder.next_frame() {
-                Ok(frame) => self.current_frame = frame,
+                Ok(frame) => {
+                    self.frames_before_current +=
+                        (self.current_frame.data.len() / self.current_frame.channels.max(1)) as u64;
+                    self.current_frame = frame
+                }
                 _ => return None,
             }
             self.current_frame_offset = 0;
@@ -142,6 +194,6 @@ where
         let v = self.current_frame.data[self.current_frame_offset];
         self.current_frame_offset += 1;
 
-        Some(v)
+        Some(v.to_f32())
     }
 }
@@ -4,7 +4,8 @@ use std::io::{Read, Seek, SeekFrom};
 use std::time::Duration;
 use std::vec;
 
-use crate::sound::source::Source;
+use crate::sound::sample::Sample;
+use crate::sound::source::{SeekError, Source};
 
 use audiopus::coder::Decoder;
 use audiopus::TryFrom;
@@ -72,6 +73,47 @@ where
             current_data: decoded_data.into_iter(),
         })
     }
+
+    /// Seeks to the ogg page containing `pos`, then decodes and drops the samples before it
+    pub fn seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        // Opus granule positions are always counted at 48kHz
+        let target = (pos.as_secs_f64() * SAMPLE_RATE as f64) as u64;
+        self.packet_reader
+            .seek_absgp(None, target)
+            .map_err(|err| SeekError::Io(format!("{:?}", err)))?;
+        // The position is only known again at the end of a page, and is counted up packet by
+        // packet from there
+        let mut decoder = self.decoder.lock();
+        let mut decoded: Vec<i16> = Vec::new();
+        let mut end: Option<u64> = None;
+        while let Some(packet) = self
+            .packet_reader
+            .read_packet()
+            .map_err(|err| SeekError::Io(format!("{:?}", err)))?
+        {
+            let mut decoded_data: Vec<i16> = vec![0; MAX_BUFFER_SIZE];
+            let length = decoder
+                .decode(Some(&packet.data), &mut decoded_data, false)
+                .map_err(|err| SeekError::Io(err.to_string()))?;
+            decoded.extend_from_slice(&decoded_data[..length * CHANNELS]);
+            end = if packet.last_in_page() {
+                Some(packet.absgp_page())
+            } else {
+                end.map(|end| end + length as u64)
+            };
+            if let Some(end) = end {
+                let frames = (decoded.len() / CHANNELS) as u64;
+                if end >= target {
+                    let keep = (end - target).min(frames) as usize * CHANNELS;
+                    decoded.drain(..decoded.len() - keep);
+                    break;
+                }
+                decoded.clear();
+            }
+        }
+        self.current_data = decoded.into_iter();
+        Ok(())
+    }
     pub fn total_duration_mut<T>(&self, reader: &mut T) -> Option<Duration>
     where
         T: std::io::Read + std::io::Seek,
@@ -120,12 +162,12 @@ impl<R> Iterator for OpusDecoder<R>
 where
     R: Read + Seek,
 {
-    type Item = i16;
+    type Item = f32;
 
     #[inline]
-    fn next(&mut self) -> Option<i16> {
+    fn next(&mut self) -> Option<f32> {
         if let Some(sample) = self.current_data.next() {
-            Some(sample)
+            Some(sample.to_f32())
         } else {
             let input_data = match self.packet_reader.read_packet_expected() {
                 Ok(d) => d.data,
@@ -145,7 +187,7 @@ where
                 }
             }
             self.current_data = decoded_data.into_iter();
-            self.current_data.next()
+            self.current_data.next().map(|sample| sample.to_f32())
         }
     }
 
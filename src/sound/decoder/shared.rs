@@ -0,0 +1,52 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Arc;
+
+use crate::sound::source::SeekError;
+
+/// Reader that can be shared between a format decoder and its owner.
+///
+/// Some decoding libraries take ownership of the reader and never hand it back, so seeking
+/// backwards means building a fresh decoder on top of the same reader.
+pub struct SharedReader<R>(Arc<parking_lot::Mutex<R>>);
+
+impl<R> SharedReader<R>
+where
+    R: Read + Seek,
+{
+    pub fn new(reader: R) -> Self {
+        SharedReader(Arc::new(parking_lot::Mutex::new(reader)))
+    }
+
+    /// Moves the underlying reader, this should only be done while no decoder is reading from it
+    pub fn rewind_to(&self, pos: u64) -> Result<(), SeekError> {
+        self.0
+            .lock()
+            .seek(SeekFrom::Start(pos))
+            .map(|_| ())
+            .map_err(|err| SeekError::Io(err.to_string()))
+    }
+}
+
+impl<R> Clone for SharedReader<R> {
+    fn clone(&self) -> Self {
+        SharedReader(Arc::clone(&self.0))
+    }
+}
+
+impl<R> Read for SharedReader<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.lock().read(buf)
+    }
+}
+
+impl<R> Seek for SharedReader<R>
+where
+    R: Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.0.lock().seek(pos)
+    }
+}
@@ -4,7 +4,8 @@ use std::io::{Read, Seek, SeekFrom};
 use std::time::Duration;
 use std::vec;
 
-use crate::sound::source::Source;
+use crate::sound::sample::Sample;
+use crate::sound::source::{SeekError, Source};
 
 use lewton::inside_ogg::OggStreamReader;
 
@@ -45,6 +46,36 @@ where
             current_data: data.into_iter(),
         })
     }
+
+    /// Seeks to the ogg page containing `pos`, then decodes and drops the samples before it
+    pub fn seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let target = (pos.as_secs_f64() * self.sample_rate() as f64) as u64;
+        let channels = self.channels().max(1) as usize;
+        self.stream_reader
+            .seek_absgp_pg(target)
+            .map_err(|err| SeekError::Io(format!("{:?}", err)))?;
+        // The position is only known again at the end of a page, and is counted up packet by
+        // packet from there
+        let mut decoded: Vec<i16> = Vec::new();
+        while let Some(mut packet) = self
+            .stream_reader
+            .read_dec_packet_itl()
+            .map_err(|err| SeekError::Io(format!("{:?}", err)))?
+        {
+            decoded.append(&mut packet);
+            if let Some(end) = self.stream_reader.get_last_absgp() {
+                let frames = (decoded.len() / channels) as u64;
+                if end >= target {
+                    let keep = (end - target).min(frames) as usize * channels;
+                    decoded.drain(..decoded.len() - keep);
+                    break;
+                }
+                decoded.clear();
+            }
+        }
+        self.current_data = decoded.into_iter();
+        Ok(())
+    }
     pub fn total_duration_mut<T>(&self, reader: &mut T) -> Option<Duration>
     where
         T: std::io::Read + std::io::Seek,
@@ -93,10 +124,10 @@ impl<R> Iterator for VorbisDecoder<R>
 where
     R: Read + Seek,
 {
-    type Item = i16;
+    type Item = f32;
 
     #[inline]
-    fn next(&mut self) -> Option<i16> {
+    fn next(&mut self) -> Option<f32> {
         if let Some(sample) = self.current_data.next() {
             if self.current_data.len() == 0 {
                 if let Some(data) = self
@@ -108,7 +139,7 @@ where
                     self.current_data = data.into_iter();
                 }
             }
-            Some(sample)
+            Some(sample.to_f32())
         } else {
             if let Some(data) = self
                 .stream_reader
@@ -118,7 +149,7 @@ where
             {
                 self.current_data = data.into_iter();
             }
-            self.current_data.next()
+            self.current_data.next().map(|sample| sample.to_f32())
         }
     }
 
@@ -2,7 +2,8 @@
 use std::io::{Read, Seek, SeekFrom};
 use std::time::Duration;
 
-use crate::sound::source::Source;
+use crate::sound::sample::int_to_f32;
+use crate::sound::source::{SeekError, Source};
 
 use hound::{SampleFormat, WavReader};
 
@@ -39,6 +40,18 @@ where
             channels: spec.channels,
         })
     }
+
+    /// Seeks to the sample frame closest to `pos`
+    pub fn seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let frame = (pos.as_secs_f64() * self.sample_rate as f64) as u32;
+        let frame = frame.min(self.reader.reader.duration());
+        self.reader
+            .reader
+            .seek(frame)
+            .map_err(|err| SeekError::Io(err.to_string()))?;
+        self.reader.samples_read = frame * self.channels as u32;
+        Ok(())
+    }
 }
 
 struct SamplesIterator<R>
@@ -53,24 +66,23 @@ impl<R> Iterator for SamplesIterator<R>
 where
     R: Read + Seek,
 {
-    type Item = i16;
+    type Item = f32;
 
     #[inline]
-    fn next(&mut self) -> Option<i16> {
+    fn next(&mut self) -> Option<f32> {
         let spec = self.reader.spec();
         match (spec.sample_format, spec.bits_per_sample) {
+            // Kept past full scale, the mix is clipped once it goes out
             (SampleFormat::Float, 32) => self.reader.samples().next().map(|value| {
                 self.samples_read += 1;
-                f32_to_i16(value.unwrap_or(0.0))
-            }),
-            (SampleFormat::Int, 16) => self.reader.samples().next().map(|value| {
-                self.samples_read += 1;
-                value.unwrap_or(0)
-            }),
-            (SampleFormat::Int, 24) => self.reader.samples().next().map(|value| {
-                self.samples_read += 1;
-                i24_to_i16(value.unwrap_or(0))
+                value.unwrap_or(0.0)
             }),
+            (SampleFormat::Int, bits_per_sample) if bits_per_sample <= 32 => {
+                self.reader.samples().next().map(|value| {
+                    self.samples_read += 1;
+                    int_to_f32(value.unwrap_or(0), bits_per_sample as u32)
+                })
+            }
             (sample_format, bits_per_sample) => panic!(
                 "Unimplemented wav spec: {:?}, {}",
                 sample_format, bits_per_sample
@@ -117,10 +129,10 @@ impl<R> Iterator for WavDecoder<R>
 where
     R: Read + Seek,
 {
-    type Item = i16;
+    type Item = f32;
 
     #[inline]
-    fn next(&mut self) -> Option<i16> {
+    fn next(&mut self) -> Option<f32> {
         self.reader.next()
     }
 
@@ -148,19 +160,65 @@ where
     true
 }
 
-/// Returns a 32 bit WAV float as an i16. WAV floats are typically in the range of
-/// [-1.0, 1.0] while i16s are in the range [-32768, 32767]. Note that this
-/// function definitely causes precision loss but hopefully this isn't too
-/// audiable when actually playing?
-fn f32_to_i16(f: f32) -> i16 {
-    // prefer to clip the input rather than be excessively loud.
-    (f.max(-1.0).min(1.0) * i16::max_value() as f32) as i16
-}
+#[cfg(test)]
+mod test {
+    use super::WavDecoder;
+    use crate::sound::sample::Sample;
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    /// One second of stereo at 1kHz where the left channel counts up and the right counts down
+    fn ramp_wav() -> Cursor<Vec<u8>> {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 1000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut data = Cursor::new(Vec::new());
+        {
+            let mut writer = hound::WavWriter::new(&mut data, spec).unwrap();
+            for i in 0..1000i16 {
+                writer.write_sample(i).unwrap();
+                writer.write_sample(-i).unwrap();
+            }
+            writer.finalize().unwrap();
+        }
+        data.set_position(0);
+        data
+    }
+
+    #[test]
+    fn seek_forward_and_back() {
+        let mut decoder = WavDecoder::new(ramp_wav()).ok().unwrap();
+        decoder.seek(Duration::from_millis(500)).unwrap();
+        assert_eq!(decoder.next(), Some(500i16.to_f32()));
+        assert_eq!(decoder.next(), Some((-500i16).to_f32()));
+        decoder.seek(Duration::from_millis(10)).unwrap();
+        assert_eq!(decoder.next(), Some(10i16.to_f32()));
+        assert_eq!(decoder.len(), 1979);
+    }
 
-/// Returns a 24 bit WAV int as an i16. Note that this is a 24 bit integer, not a
-/// 32 bit one. 24 bit ints are in the range [−8,388,608, 8,388,607] while i16s
-/// are in the range [-32768, 32767]. Note that this function definitely causes
-/// precision loss but hopefully this isn't too audiable when actually playing?
-fn i24_to_i16(i: i32) -> i16 {
-    (i >> 8) as i16
+    #[test]
+    fn keeps_24_bit_depth() {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 1000,
+            bits_per_sample: 24,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut data = Cursor::new(Vec::new());
+        {
+            let mut writer = hound::WavWriter::new(&mut data, spec).unwrap();
+            for sample in &[1, -8_388_608, 8_388_607] {
+                writer.write_sample(*sample).unwrap();
+            }
+            writer.finalize().unwrap();
+        }
+        data.set_position(0);
+        let decoder = WavDecoder::new(data).ok().unwrap();
+        let samples: Vec<f32> = decoder.collect();
+        assert!(samples[0] > 0.0 && samples[0] < 1.0 / 32767.0);
+        assert_eq!(&samples[1..], &[-1.0, 1.0]);
+    }
 }
@@ -1,5 +1,5 @@
 // Initial version from Rodio APACHE LICENSE 2.0
-use crate::sound::source::Source;
+use crate::sound::source::{SeekError, Source};
 use libxm_soundboard::XMContext;
 use log::{error, info, trace, warn};
 use std::io::{Read, Seek, SeekFrom};
@@ -11,8 +11,11 @@ where
     R: Read + Seek,
 {
     context: XMContext,
+    data: Vec<u8>,
     current_frame_data: Box<[f32; 4096]>,
     current_frame_offset: usize,
+    /// Number of samples generated before `current_frame_data`
+    samples_before_current: u64,
     phantom: PhantomData<R>,
 }
 
@@ -53,11 +56,41 @@ where
 
         Ok(XMDecoder {
             context: xm,
+            data: data_buffer,
             phantom: PhantomData,
             current_frame_data: Box::new(buffer),
             current_frame_offset: 0,
+            samples_before_current: 0,
         })
     }
+
+    /// Seeks to the sample frame closest to `pos`
+    ///
+    /// The module is rendered from the start (or the current position when seeking forward) and
+    /// the samples before `pos` are discarded.
+    pub fn seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let target =
+            (pos.as_secs_f64() * self.sample_rate() as f64) as u64 * self.channels() as u64;
+        if target < self.samples_before_current {
+            let mut xm = XMContext::new(&self.data, 48000)
+                .map_err(|err| SeekError::Io(format!("{:?}", err)))?;
+            xm.set_max_loop_count(1);
+            xm.generate_samples(&mut *self.current_frame_data);
+            self.context = xm;
+            self.samples_before_current = 0;
+        }
+        let frame_len = self.current_frame_data.len() as u64;
+        while target >= self.samples_before_current + frame_len {
+            if self.context.loop_count() != 0 {
+                self.current_frame_offset = self.current_frame_data.len();
+                return Ok(());
+            }
+            self.context.generate_samples(&mut *self.current_frame_data);
+            self.samples_before_current += frame_len;
+        }
+        self.current_frame_offset = (target - self.samples_before_current) as usize;
+        Ok(())
+    }
 }
 
 impl<R> Source for XMDecoder<R>
@@ -96,23 +129,19 @@ where
     }
 }
 
-fn f32_to_i16(f: f32) -> i16 {
-    // prefer to clip the input rather than be excessively loud.
-    (f.max(-1.0).min(1.0) * i16::max_value() as f32) as i16
-}
-
 impl<R> Iterator for XMDecoder<R>
 where
     R: Read + Seek,
 {
-    type Item = i16;
+    type Item = f32;
 
     #[inline]
-    fn next(&mut self) -> Option<i16> {
+    fn next(&mut self) -> Option<f32> {
         if self.current_frame_offset == self.current_frame_data.len() {
             self.current_frame_offset = 0;
             if self.context.loop_count() == 0 {
                 self.context.generate_samples(&mut *self.current_frame_data);
+                self.samples_before_current += self.current_frame_data.len() as u64;
             } else {
                 return None;
             }
@@ -120,6 +149,6 @@ where
 
         let v = self.current_frame_data[self.current_frame_offset];
         self.current_frame_offset += 1;
-        Some(f32_to_i16(v))
+        Some(v)
     }
 }
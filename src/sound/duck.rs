@@ -0,0 +1,184 @@
+//! Lowers the microphone and the music bed while sounds play. The sound loop sets the
+//! `Sidechain` from the sounds that are playing, the duplex stream and the sinks follow it.
+
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+
+use super::filter::FrameFilter;
+use super::noise::ramp_samples;
+use crate::config;
+use crate::utils::db_to_gain;
+
+/// Whether the microphone and the music bed should be down, shared with the callbacks which
+/// mustn't wait on locks
+#[derive(Debug)]
+pub struct Sidechain {
+    /// Gain when fully down, as `f32` bits
+    floor: AtomicU32,
+    attack_ms: AtomicU32,
+    release_ms: AtomicU32,
+    mic: AtomicBool,
+    music_bed: AtomicBool,
+}
+
+impl Default for Sidechain {
+    fn default() -> Self {
+        let settings = config::Ducking::default();
+        Self {
+            floor: AtomicU32::new(1.0f32.to_bits()),
+            attack_ms: AtomicU32::new(settings.attack_ms.to_bits()),
+            release_ms: AtomicU32::new(settings.release_ms.to_bits()),
+            mic: AtomicBool::new(false),
+            music_bed: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Sidechain {
+    /// Ducks what `settings` asks for while `playing`. Without settings everything comes back
+    /// up at the last release time.
+    pub fn update(&self, settings: Option<config::Ducking>, playing: bool) {
+        let settings = match settings {
+            Some(settings) => settings,
+            None => {
+                self.mic.store(false, Ordering::Relaxed);
+                self.music_bed.store(false, Ordering::Relaxed);
+                return;
+            }
+        };
+        let floor = db_to_gain(-settings.amount_db.abs());
+        self.floor.store(floor.to_bits(), Ordering::Relaxed);
+        self.attack_ms
+            .store(settings.attack_ms.to_bits(), Ordering::Relaxed);
+        self.release_ms
+            .store(settings.release_ms.to_bits(), Ordering::Relaxed);
+        self.mic.store(playing && settings.mic, Ordering::Relaxed);
+        self.music_bed
+            .store(playing && settings.music_bed, Ordering::Relaxed);
+    }
+
+    fn target(&self, ducked: &AtomicBool, sample_rate: u32) -> Target {
+        let load = |value: &AtomicU32| f32::from_bits(value.load(Ordering::Relaxed));
+        let floor = load(&self.floor);
+        Target {
+            gain: if ducked.load(Ordering::Relaxed) {
+                floor
+            } else {
+                1.0
+            },
+            floor,
+            attack: ramp_samples(load(&self.attack_ms), sample_rate),
+            release: ramp_samples(load(&self.release_ms), sample_rate),
+        }
+    }
+
+    /// Where the microphone is heading
+    pub(super) fn mic(&self, sample_rate: u32) -> Target {
+        self.target(&self.mic, sample_rate)
+    }
+
+    /// Where the sounds of the music bed are heading
+    pub(super) fn music_bed(&self, sample_rate: u32) -> Target {
+        self.target(&self.music_bed, sample_rate)
+    }
+}
+
+/// Gain a ducked part heads to, and the frames taken to get there
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Target {
+    gain: f32,
+    floor: f32,
+    attack: f32,
+    release: f32,
+}
+
+/// Gain of a ducked part, moving towards its `Target` one frame at a time
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Ramp {
+    gain: f32,
+}
+
+impl Default for Ramp {
+    fn default() -> Self {
+        Self { gain: 1.0 }
+    }
+}
+
+impl Ramp {
+    /// Gain for the next frame. Going all the way down or back up takes the attack or release
+    /// time.
+    pub(super) fn next(&mut self, target: &Target) -> f32 {
+        // Still moves when the floor changed past the gain
+        let range = 1.0 - target.floor.min(self.gain);
+        self.gain = if self.gain > target.gain {
+            (self.gain - range / target.attack).max(target.gain)
+        } else {
+            (self.gain + range / target.release).min(target.gain)
+        };
+        self.gain
+    }
+}
+
+/// Ducks the microphone, as a stage of its `filter::Filter`
+pub struct MicDuck {
+    sidechain: Arc<Sidechain>,
+    ramp: Ramp,
+    sample_rate: u32,
+}
+
+impl MicDuck {
+    pub fn new(sidechain: Arc<Sidechain>) -> Self {
+        Self {
+            sidechain,
+            ramp: Ramp::default(),
+            sample_rate: 48000,
+        }
+    }
+}
+
+impl FrameFilter for MicDuck {
+    fn apply(&mut self, frame: &mut [f32]) {
+        let gain = self.ramp.next(&self.sidechain.mic(self.sample_rate));
+        for sample in frame.iter_mut() {
+            *sample *= gain;
+        }
+    }
+
+    fn reset(&mut self, sample_rate: u32, _channels: usize) {
+        self.sample_rate = sample_rate;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Ramp, Sidechain};
+    use crate::config;
+
+    #[test]
+    fn ramps_down_and_back_up() {
+        let sidechain = Sidechain::default();
+        let settings = config::Ducking {
+            amount_db: 20.0,
+            attack_ms: 10.0,
+            release_ms: 100.0,
+            mic: true,
+            music_bed: false,
+        };
+        let mut ramp = Ramp::default();
+
+        sidechain.update(Some(settings), true);
+        let down: Vec<f32> = (0..100).map(|_| ramp.next(&sidechain.mic(10000))).collect();
+        assert!((down[49] - 0.55).abs() < 0.01, "at {}", down[49]);
+        assert!((down[99] - 0.1).abs() < 1e-4);
+        // Only the microphone was asked for
+        let mut bed = Ramp::default();
+        assert!((bed.next(&sidechain.music_bed(10000)) - 1.0).abs() < 1e-6);
+
+        sidechain.update(Some(settings), false);
+        let up: Vec<f32> = (0..1000)
+            .map(|_| ramp.next(&sidechain.mic(10000)))
+            .collect();
+        assert!((up[499] - 0.55).abs() < 0.01, "at {}", up[499]);
+        assert!((up[999] - 1.0).abs() < 1e-4);
+    }
+}
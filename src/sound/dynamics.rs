@@ -0,0 +1,322 @@
+//! Compressor and look-ahead limiter for the mix of a sink, run as a stage of a
+//! `filter::Filter`
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use super::filter::FrameFilter;
+use super::meter::record_max;
+use crate::config;
+use crate::utils::db_to_gain;
+
+/// Most gain taken away by a `Dynamics`, shared with the sound loop
+#[derive(Debug, Default)]
+pub struct GainReduction(AtomicU32);
+
+impl GainReduction {
+    /// Most gain taken away since the last call, in decibels
+    pub fn take(&self) -> f32 {
+        f32::from_bits(self.0.swap(0, Ordering::Relaxed))
+    }
+
+    fn record(&self, reduction_db: f32) {
+        record_max(&self.0, reduction_db);
+    }
+}
+
+/// Share of the distance to `target` covered on every frame by a smoother taking `ms`
+/// milliseconds to get about two thirds of the way
+fn coefficient(ms: f32, sample_rate: u32) -> f32 {
+    let samples = ms.max(0.0) / 1000.0 * sample_rate as f32;
+    if samples < 1.0 {
+        1.0
+    } else {
+        1.0 - (-1.0 / samples).exp()
+    }
+}
+
+fn gain_to_db(gain: f32) -> f32 {
+    20.0 * gain.max(1e-6).log10()
+}
+
+/// Peak compressor with linked channels
+struct Compressor {
+    settings: config::Compressor,
+    attack: f32,
+    release: f32,
+    /// Gain taken away in decibels, before the makeup
+    reduction: f32,
+}
+
+impl Compressor {
+    fn new(settings: config::Compressor) -> Self {
+        Self {
+            settings,
+            attack: 1.0,
+            release: 1.0,
+            reduction: 0.0,
+        }
+    }
+
+    fn reset(&mut self, sample_rate: u32) {
+        self.attack = coefficient(self.settings.attack_ms, sample_rate);
+        self.release = coefficient(self.settings.release_ms, sample_rate);
+    }
+
+    /// Gain for a frame peaking at `level`
+    fn gain(&mut self, level: f32) -> f32 {
+        let over = gain_to_db(level) - self.settings.threshold_db;
+        let target = if over > 0.0 {
+            over * (1.0 - 1.0 / self.settings.ratio.max(1.0))
+        } else {
+            0.0
+        };
+        let speed = if target > self.reduction {
+            self.attack
+        } else {
+            self.release
+        };
+        self.reduction += (target - self.reduction) * speed;
+        db_to_gain(self.settings.makeup_db - self.reduction)
+    }
+}
+
+/// Look-ahead limiter with linked channels.
+///
+/// The gain needed by each frame is held for the length of the look-ahead and then averaged
+/// over it, so the gain ramps down smoothly and has reached what a peak needs by the time the
+/// peak comes out of the delay.
+struct Limiter {
+    settings: config::Limiter,
+    ceiling: f32,
+    release: f32,
+    /// Frames the gain is held and averaged over
+    length: usize,
+    /// Frames waiting to come out, `length - 1` of them
+    delay: VecDeque<f32>,
+    /// Frame index and gain needed of the frames that may still be the lowest of the hold
+    lowest: VecDeque<(u64, f32)>,
+    /// Held gains being averaged, and their sum
+    held: VecDeque<f32>,
+    sum: f64,
+    /// Held gain, following the release after a peak
+    gain: f32,
+    frame: u64,
+}
+
+impl Limiter {
+    fn new(settings: config::Limiter) -> Self {
+        Self {
+            settings,
+            ceiling: db_to_gain(settings.ceiling_db),
+            release: 1.0,
+            length: 1,
+            delay: VecDeque::new(),
+            lowest: VecDeque::new(),
+            held: VecDeque::new(),
+            sum: 0.0,
+            gain: 1.0,
+            frame: 0,
+        }
+    }
+
+    fn reset(&mut self, sample_rate: u32, channels: usize) {
+        self.release = coefficient(self.settings.release_ms, sample_rate);
+        self.length =
+            ((self.settings.lookahead_ms.max(0.0) / 1000.0 * sample_rate as f32) as usize).max(1);
+        // Sized once here, so nothing is allocated while filtering
+        self.delay = VecDeque::with_capacity(self.length * channels);
+        self.delay.resize((self.length - 1) * channels, 0.0);
+        self.lowest = VecDeque::with_capacity(self.length + 1);
+        self.held = VecDeque::with_capacity(self.length + 1);
+        self.held.resize(self.length, 1.0);
+        self.sum = self.length as f64;
+        self.gain = 1.0;
+        self.frame = 0;
+    }
+
+    /// Delays `frame` and limits the frame coming out in its place, returning its gain
+    fn limit(&mut self, frame: &mut [f32]) -> f32 {
+        let level = frame
+            .iter()
+            .fold(0f32, |level, sample| level.max(sample.abs()));
+        let needed = if level > self.ceiling {
+            self.ceiling / level
+        } else {
+            1.0
+        };
+
+        // Lowest gain needed by the frames of the hold
+        while matches!(self.lowest.back(), Some((_, gain)) if *gain >= needed) {
+            self.lowest.pop_back();
+        }
+        self.lowest.push_back((self.frame, needed));
+        let first = (self.frame + 1).saturating_sub(self.length as u64);
+        while matches!(self.lowest.front(), Some((index, _)) if *index < first) {
+            self.lowest.pop_front();
+        }
+        self.frame += 1;
+        let lowest = self.lowest.front().map_or(1.0, |(_, gain)| *gain);
+
+        // Stays at or below what the hold needs, so the average does too
+        self.gain = if lowest < self.gain {
+            lowest
+        } else {
+            self.gain + (lowest - self.gain) * self.release
+        };
+        self.held.push_back(self.gain);
+        self.sum += self.gain as f64;
+        self.sum -= self.held.pop_front().unwrap_or(1.0) as f64;
+        let gain = (self.sum / self.length as f64) as f32;
+
+        for sample in frame.iter_mut() {
+            self.delay.push_back(*sample);
+            let delayed = self.delay.pop_front().unwrap_or(0.0);
+            *sample = (delayed * gain).clamp(-self.ceiling, self.ceiling);
+        }
+        gain
+    }
+}
+
+/// The compressor and limiter of a sink
+pub struct Dynamics {
+    compressor: Option<Compressor>,
+    limiter: Option<Limiter>,
+    reduction: Arc<GainReduction>,
+}
+
+impl Dynamics {
+    /// Reports the gain taken away to `reduction`
+    pub fn new(settings: config::Dynamics, reduction: Arc<GainReduction>) -> Self {
+        Self {
+            compressor: settings.compressor.map(Compressor::new),
+            limiter: settings.limiter.map(Limiter::new),
+            reduction,
+        }
+    }
+}
+
+impl FrameFilter for Dynamics {
+    fn apply(&mut self, frame: &mut [f32]) {
+        let mut reduction = 0.0;
+        if let Some(compressor) = self.compressor.as_mut() {
+            let level = frame
+                .iter()
+                .fold(0f32, |level, sample| level.max(sample.abs()));
+            let gain = compressor.gain(level);
+            for sample in frame.iter_mut() {
+                *sample *= gain;
+            }
+            reduction += compressor.reduction;
+        }
+        if let Some(limiter) = self.limiter.as_mut() {
+            reduction -= gain_to_db(limiter.limit(frame));
+        }
+        self.reduction.record(reduction);
+    }
+
+    fn reset(&mut self, sample_rate: u32, channels: usize) {
+        if let Some(compressor) = self.compressor.as_mut() {
+            compressor.reset(sample_rate);
+        }
+        if let Some(limiter) = self.limiter.as_mut() {
+            limiter.reset(sample_rate, channels);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Dynamics, GainReduction};
+    use crate::config;
+    use crate::sound::filter::FrameFilter;
+    use std::f32::consts::PI;
+    use std::sync::Arc;
+
+    fn run(dynamics: &mut Dynamics, input: &[f32]) -> Vec<f32> {
+        input
+            .iter()
+            .map(|sample| {
+                let mut frame = [*sample, -*sample];
+                dynamics.apply(&mut frame);
+                assert_eq!(frame[0], -frame[1]);
+                frame[0]
+            })
+            .collect()
+    }
+
+    fn sine(amplitude: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (2.0 * PI * 100.0 * i as f32 / 8000.0).sin() * amplitude)
+            .collect()
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples
+            .iter()
+            .fold(0f32, |peak, sample| peak.max(sample.abs()))
+    }
+
+    #[test]
+    fn limiter_keeps_under_ceiling() {
+        let reduction = Arc::new(GainReduction::default());
+        let settings = config::Dynamics {
+            compressor: None,
+            limiter: Some(config::Limiter {
+                ceiling_db: -6.0,
+                release_ms: 50.0,
+                lookahead_ms: 5.0,
+            }),
+        };
+        let mut dynamics = Dynamics::new(settings, reduction.clone());
+        dynamics.reset(8000, 2);
+
+        // Quiet sounds come out as they went in, 40 frames later
+        let quiet = sine(0.25, 800);
+        let output = run(&mut dynamics, &quiet);
+        assert_eq!(&output[39..], &quiet[..800 - 39]);
+        assert_eq!(reduction.take(), 0.0);
+
+        // Two loud sounds at once
+        let loud = sine(1.8, 800);
+        let output = run(&mut dynamics, &loud);
+        let ceiling = crate::utils::db_to_gain(-6.0);
+        assert!(peak(&output) <= ceiling);
+        assert!(peak(&output[400..]) > ceiling * 0.95);
+        let taken = reduction.take();
+        assert!((taken - 11.1).abs() < 0.2, "reduced by {}", taken);
+
+        // Released over about 5 times its length
+        let output = run(&mut dynamics, &sine(0.25, 4000));
+        assert!(peak(&output[100..400]) < 0.2);
+        assert!((peak(&output[2000..]) - 0.25).abs() < 0.01);
+    }
+
+    #[test]
+    fn compressor_follows_ratio() {
+        let reduction = Arc::new(GainReduction::default());
+        let settings = config::Dynamics {
+            compressor: Some(config::Compressor {
+                threshold_db: -20.0,
+                ratio: 4.0,
+                attack_ms: 1.0,
+                release_ms: 50.0,
+                makeup_db: 0.0,
+            }),
+            limiter: None,
+        };
+        let mut dynamics = Dynamics::new(settings, reduction.clone());
+        dynamics.reset(8000, 2);
+
+        // 12 dB over the threshold comes out 3 dB over it
+        let output = run(&mut dynamics, &sine(0.4, 4000));
+        let level = 20.0 * peak(&output[3000..]).log10();
+        assert!((level + 17.0).abs() < 0.5, "compressed to {} dB", level);
+        assert!((reduction.take() - 9.0).abs() < 0.5);
+
+        let below = sine(0.05, 4000);
+        let output = run(&mut dynamics, &below);
+        assert!((peak(&output[3000..]) - 0.05).abs() < 0.001);
+    }
+}
@@ -0,0 +1,490 @@
+//! Voice changer effects for the microphone, run as stages of a `filter::Filter`
+
+use std::f32::consts::PI;
+
+use super::eq::Biquad;
+use super::filter::FrameFilter;
+use crate::config::{Effect, EqBand, EqKind};
+use crate::utils::db_to_gain;
+
+/// Sample rate assumed until the filter is reset for its stream
+const DEFAULT_SAMPLE_RATE: f32 = 48000.0;
+
+/// Makes the filter running `effect`
+pub fn filter(effect: Effect) -> Box<dyn FrameFilter> {
+    match effect {
+        Effect::PitchShift { semitones } => Box::new(PitchShift::new(semitones)),
+        Effect::Robot { frequency } => Box::new(Robot::new(frequency)),
+        Effect::Echo {
+            delay_ms,
+            feedback,
+            mix,
+        } => Box::new(Echo::new(delay_ms, feedback, mix)),
+        Effect::Reverb {
+            room_size,
+            damping,
+            mix,
+        } => Box::new(Reverb::new(room_size, damping, mix)),
+        Effect::Chorus {
+            rate_hz,
+            depth_ms,
+            mix,
+        } => Box::new(Chorus::new(rate_hz, depth_ms, mix)),
+        Effect::Distortion { drive_db } => Box::new(Distortion::new(drive_db)),
+        Effect::Telephone { low_hz, high_hz } => Box::new(Telephone::new(low_hz, high_hz)),
+    }
+}
+
+fn blend(dry: f32, wet: f32, mix: f32) -> f32 {
+    dry * (1.0 - mix) + wet * mix
+}
+
+/// The last samples of a channel
+struct DelayLine {
+    buffer: Vec<f32>,
+    /// Where the next sample goes
+    write: usize,
+}
+
+impl DelayLine {
+    /// Holds up to `max_delay` samples
+    fn new(max_delay: usize) -> Self {
+        Self {
+            buffer: vec![0.0; max_delay.max(1) + 1],
+            write: 0,
+        }
+    }
+
+    /// The sample pushed `delay` samples before the next one, interpolated between samples
+    fn read(&self, delay: f32) -> f32 {
+        let len = self.buffer.len();
+        let delay = delay.clamp(1.0, (len - 1) as f32);
+        let whole = delay.floor();
+        let fraction = delay - whole;
+        let newer = (self.write + len - whole as usize) % len;
+        let older = (newer + len - 1) % len;
+        self.buffer[newer] * (1.0 - fraction) + self.buffer[older] * fraction
+    }
+
+    fn push(&mut self, sample: f32) {
+        self.buffer[self.write] = sample;
+        self.write = (self.write + 1) % self.buffer.len();
+    }
+}
+
+/// Reads the voice back through two taps whose delays sweep at the speed that shifts the pitch,
+/// crossfading between them as each one jumps back
+struct PitchShift {
+    ratio: f32,
+    /// Length of the sweep in samples
+    window: f32,
+    /// Position in the sweep, from `0.0` to `1.0`
+    phase: f32,
+    lines: Vec<DelayLine>,
+}
+
+impl PitchShift {
+    /// Length of the sweep, long enough to keep low voices and short enough to not echo
+    const WINDOW_SECONDS: f32 = 0.04;
+
+    fn new(semitones: f32) -> Self {
+        Self {
+            ratio: 2f32.powf(semitones / 12.0),
+            window: Self::WINDOW_SECONDS * DEFAULT_SAMPLE_RATE,
+            phase: 0.0,
+            lines: Vec::new(),
+        }
+    }
+}
+
+impl FrameFilter for PitchShift {
+    fn apply(&mut self, frame: &mut [f32]) {
+        let first = self.phase;
+        let second = (first + 0.5).fract();
+        // Triangles, which add up to one at every phase
+        let fade = |phase: f32| 1.0 - (2.0 * phase - 1.0).abs();
+        for (line, sample) in self.lines.iter_mut().zip(frame.iter_mut()) {
+            line.push(*sample);
+            *sample = line.read(1.0 + first * self.window) * fade(first)
+                + line.read(1.0 + second * self.window) * fade(second);
+        }
+        self.phase = (self.phase + (1.0 - self.ratio) / self.window).rem_euclid(1.0);
+    }
+
+    fn reset(&mut self, sample_rate: u32, channels: usize) {
+        self.window = Self::WINDOW_SECONDS * sample_rate as f32;
+        self.phase = 0.0;
+        self.lines = (0..channels)
+            .map(|_| DelayLine::new(self.window as usize + 2))
+            .collect();
+    }
+}
+
+/// Ring modulation, which turns the voice metallic
+struct Robot {
+    frequency: f32,
+    sample_rate: f32,
+    phase: f32,
+}
+
+impl Robot {
+    fn new(frequency: f32) -> Self {
+        Self {
+            frequency,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            phase: 0.0,
+        }
+    }
+}
+
+impl FrameFilter for Robot {
+    fn apply(&mut self, frame: &mut [f32]) {
+        let carrier = (2.0 * PI * self.phase).sin();
+        for sample in frame.iter_mut() {
+            *sample *= carrier;
+        }
+        self.phase = (self.phase + self.frequency / self.sample_rate).fract();
+    }
+
+    fn reset(&mut self, sample_rate: u32, _channels: usize) {
+        self.sample_rate = sample_rate as f32;
+        self.phase = 0.0;
+    }
+}
+
+struct Echo {
+    delay_ms: f32,
+    /// Delay in samples
+    delay: f32,
+    feedback: f32,
+    mix: f32,
+    lines: Vec<DelayLine>,
+}
+
+impl Echo {
+    fn new(delay_ms: f32, feedback: f32, mix: f32) -> Self {
+        Self {
+            delay_ms: delay_ms.max(0.0),
+            delay: 1.0,
+            // A full feedback would never die out
+            feedback: feedback.clamp(0.0, 0.99),
+            mix,
+            lines: Vec::new(),
+        }
+    }
+}
+
+impl FrameFilter for Echo {
+    fn apply(&mut self, frame: &mut [f32]) {
+        for (line, sample) in self.lines.iter_mut().zip(frame.iter_mut()) {
+            let delayed = line.read(self.delay);
+            line.push(*sample + delayed * self.feedback);
+            *sample = blend(*sample, delayed, self.mix);
+        }
+    }
+
+    fn reset(&mut self, sample_rate: u32, channels: usize) {
+        self.delay = (self.delay_ms / 1000.0 * sample_rate as f32).max(1.0);
+        self.lines = (0..channels)
+            .map(|_| DelayLine::new(self.delay.ceil() as usize))
+            .collect();
+    }
+}
+
+/// A feedback delay with its high frequencies damped on every pass
+struct Comb {
+    line: DelayLine,
+    delay: f32,
+    damped: f32,
+}
+
+impl Comb {
+    fn new(delay: usize) -> Self {
+        Self {
+            line: DelayLine::new(delay),
+            delay: delay as f32,
+            damped: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.line.read(self.delay);
+        self.damped = output * (1.0 - damping) + self.damped * damping;
+        self.line.push(input + self.damped * feedback);
+        output
+    }
+}
+
+/// Spreads the echoes of the combs without colouring them
+struct AllPass {
+    line: DelayLine,
+    delay: f32,
+}
+
+impl AllPass {
+    fn new(delay: usize) -> Self {
+        Self {
+            line: DelayLine::new(delay),
+            delay: delay as f32,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.line.read(self.delay);
+        self.line.push(input + delayed * 0.5);
+        delayed - input
+    }
+}
+
+/// Parallel combs followed by all-passes, as in Freeverb
+struct Reverb {
+    feedback: f32,
+    damping: f32,
+    mix: f32,
+    /// The combs and all-passes of every channel
+    channels: Vec<(Vec<Comb>, Vec<AllPass>)>,
+}
+
+impl Reverb {
+    /// Delays in samples at 44100Hz, apart so their echoes don't line up
+    const COMBS: [usize; 4] = [1116, 1188, 1277, 1356];
+    const ALL_PASSES: [usize; 2] = [556, 441];
+    /// Added to the delays of every channel after the first, to widen the room
+    const SPREAD: usize = 23;
+    /// Keeps the sum of the combs around the level of the input
+    const INPUT_GAIN: f32 = 0.05;
+
+    fn new(room_size: f32, damping: f32, mix: f32) -> Self {
+        Self {
+            feedback: 0.7 + 0.28 * room_size.clamp(0.0, 1.0),
+            damping: damping.clamp(0.0, 1.0) * 0.4,
+            mix,
+            channels: Vec::new(),
+        }
+    }
+}
+
+impl FrameFilter for Reverb {
+    fn apply(&mut self, frame: &mut [f32]) {
+        let (feedback, damping) = (self.feedback, self.damping);
+        for ((combs, all_passes), sample) in self.channels.iter_mut().zip(frame.iter_mut()) {
+            let input = *sample * Self::INPUT_GAIN;
+            let mut wet: f32 = combs
+                .iter_mut()
+                .map(|comb| comb.process(input, feedback, damping))
+                .sum();
+            for all_pass in all_passes.iter_mut() {
+                wet = all_pass.process(wet);
+            }
+            *sample = blend(*sample, wet, self.mix);
+        }
+    }
+
+    fn reset(&mut self, sample_rate: u32, channels: usize) {
+        let scale = sample_rate as f32 / 44100.0;
+        let delay = |delay: usize, channel: usize| {
+            ((delay + Self::SPREAD * channel.min(1)) as f32 * scale) as usize
+        };
+        self.channels = (0..channels)
+            .map(|channel| {
+                let combs = Self::COMBS
+                    .iter()
+                    .map(|comb| Comb::new(delay(*comb, channel)))
+                    .collect();
+                let all_passes = Self::ALL_PASSES
+                    .iter()
+                    .map(|all_pass| AllPass::new(delay(*all_pass, channel)))
+                    .collect();
+                (combs, all_passes)
+            })
+            .collect();
+    }
+}
+
+/// Mixes in copies of the voice with slowly wavering delays
+struct Chorus {
+    rate_hz: f32,
+    depth_ms: f32,
+    mix: f32,
+    sample_rate: f32,
+    phase: f32,
+    lines: Vec<DelayLine>,
+}
+
+impl Chorus {
+    /// Delay of the copies when the waver is at its lowest
+    const BASE_DELAY_MS: f32 = 15.0;
+
+    fn new(rate_hz: f32, depth_ms: f32, mix: f32) -> Self {
+        Self {
+            rate_hz,
+            depth_ms: depth_ms.max(0.0),
+            mix,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            phase: 0.0,
+            lines: Vec::new(),
+        }
+    }
+}
+
+impl FrameFilter for Chorus {
+    fn apply(&mut self, frame: &mut [f32]) {
+        let samples_per_ms = self.sample_rate / 1000.0;
+        for (channel, (line, sample)) in self.lines.iter_mut().zip(frame.iter_mut()).enumerate() {
+            // Channels waver apart, which widens the sound
+            let phase = self.phase + channel as f32 * 0.25;
+            let waver = 0.5 + 0.5 * (2.0 * PI * phase).sin();
+            let delay = (Self::BASE_DELAY_MS + self.depth_ms * waver) * samples_per_ms;
+            line.push(*sample);
+            *sample = blend(*sample, line.read(delay), self.mix);
+        }
+        self.phase = (self.phase + self.rate_hz / self.sample_rate).fract();
+    }
+
+    fn reset(&mut self, sample_rate: u32, channels: usize) {
+        self.sample_rate = sample_rate as f32;
+        self.phase = 0.0;
+        let max_delay = (Self::BASE_DELAY_MS + self.depth_ms) * self.sample_rate / 1000.0;
+        self.lines = (0..channels)
+            .map(|_| DelayLine::new(max_delay.ceil() as usize + 2))
+            .collect();
+    }
+}
+
+struct Distortion {
+    drive: f32,
+    /// Brings a full scale input back to full scale
+    makeup: f32,
+}
+
+impl Distortion {
+    fn new(drive_db: f32) -> Self {
+        let drive = db_to_gain(drive_db.max(0.0));
+        Self {
+            drive,
+            makeup: 1.0 / drive.tanh(),
+        }
+    }
+}
+
+impl FrameFilter for Distortion {
+    fn apply(&mut self, frame: &mut [f32]) {
+        for sample in frame.iter_mut() {
+            *sample = (*sample * self.drive).tanh() * self.makeup;
+        }
+    }
+}
+
+struct Telephone {
+    low_hz: f32,
+    high_hz: f32,
+    /// The high-pass and low-pass of every channel
+    filters: Vec<(Biquad, Biquad)>,
+}
+
+impl Telephone {
+    fn new(low_hz: f32, high_hz: f32) -> Self {
+        Self {
+            low_hz,
+            high_hz,
+            filters: Vec::new(),
+        }
+    }
+}
+
+impl FrameFilter for Telephone {
+    fn apply(&mut self, frame: &mut [f32]) {
+        for ((high_pass, low_pass), sample) in self.filters.iter_mut().zip(frame.iter_mut()) {
+            *sample = low_pass.process(high_pass.process(*sample));
+        }
+    }
+
+    fn reset(&mut self, sample_rate: u32, channels: usize) {
+        let sample_rate = sample_rate as f32;
+        let band = |kind, frequency| EqBand {
+            kind,
+            frequency,
+            gain_db: 0.0,
+            q: std::f32::consts::FRAC_1_SQRT_2,
+        };
+        let filters = (
+            Biquad::new(&band(EqKind::HighPass, self.low_hz), sample_rate),
+            Biquad::new(&band(EqKind::LowPass, self.high_hz), sample_rate),
+        );
+        self.filters = vec![filters; channels];
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::filter;
+    use crate::config::Effect;
+    use std::f32::consts::PI;
+
+    /// Runs a mono `input` through `effect` at 48000Hz
+    fn run(effect: Effect, input: &[f32]) -> Vec<f32> {
+        let mut effect = filter(effect);
+        effect.reset(48000, 1);
+        input
+            .iter()
+            .map(|sample| {
+                let mut frame = [*sample];
+                effect.apply(&mut frame);
+                frame[0]
+            })
+            .collect()
+    }
+
+    fn sine(frequency: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (2.0 * PI * frequency * i as f32 / 48000.0).sin() * 0.5)
+            .collect()
+    }
+
+    /// Rising zero crossings of `samples`, twice the frequency over a second
+    fn crossings(samples: &[f32]) -> usize {
+        samples
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count()
+    }
+
+    #[test]
+    fn echo_repeats_after_delay() {
+        let mut input = vec![0.0; 4800];
+        input[0] = 1.0;
+        let echo = Effect::Echo {
+            delay_ms: 10.0,
+            feedback: 0.5,
+            mix: 0.5,
+        };
+        let output = run(echo, &input);
+        assert_eq!(output[0], 0.5);
+        assert_eq!(output[480], 0.5);
+        assert_eq!(output[960], 0.25);
+        assert!(output[1..480].iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn pitch_shift_changes_frequency() {
+        let input = sine(440.0, 48000);
+        let up = run(Effect::PitchShift { semitones: 12.0 }, &input);
+        let down = run(Effect::PitchShift { semitones: -12.0 }, &input);
+        let up = crossings(&up[4800..]) as f32 / crossings(&input[4800..]) as f32;
+        let down = crossings(&down[4800..]) as f32 / crossings(&input[4800..]) as f32;
+        assert!((up - 2.0).abs() < 0.1, "up by {}", up);
+        assert!((down - 0.5).abs() < 0.1, "down by {}", down);
+    }
+
+    #[test]
+    fn telephone_cuts_outside_band() {
+        let level = |samples: &[f32]| samples[4800..].iter().fold(0f32, |a, b| a.max(b.abs()));
+        let telephone = Effect::Telephone {
+            low_hz: 300.0,
+            high_hz: 3400.0,
+        };
+        assert!(level(&run(telephone, &sine(1000.0, 9600))) > 0.45);
+        assert!(level(&run(telephone, &sine(50.0, 9600))) < 0.05);
+        assert!(level(&run(telephone, &sine(12000.0, 9600))) < 0.05);
+    }
+}
@@ -0,0 +1,183 @@
+//! Parametric EQ made of biquad filters, run as a stage of a `filter::Filter`
+
+use std::f32::consts::PI;
+
+use super::filter::FrameFilter;
+use crate::config::{EqBand, EqKind};
+
+/// Second order filter in transposed direct form II, with coefficients from the Audio EQ
+/// Cookbook
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    pub(super) fn new(band: &EqBand, sample_rate: f32) -> Self {
+        let w0 = 2.0 * PI * band.frequency.clamp(1.0, sample_rate * 0.49) / sample_rate;
+        let cos = w0.cos();
+        let alpha = w0.sin() / (2.0 * band.q.max(0.01));
+        // Square root of the gain, as shelves and peaks are split between the poles and zeros
+        let a = 10f32.powf(band.gain_db / 40.0);
+        let (b, a) = match band.kind {
+            EqKind::LowShelf => {
+                let shelf = 2.0 * a.sqrt() * alpha;
+                (
+                    [
+                        a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                        2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                        a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                    ],
+                    [
+                        (a + 1.0) + (a - 1.0) * cos + shelf,
+                        -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                        (a + 1.0) + (a - 1.0) * cos - shelf,
+                    ],
+                )
+            }
+            EqKind::HighShelf => {
+                let shelf = 2.0 * a.sqrt() * alpha;
+                (
+                    [
+                        a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                        -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                        a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                    ],
+                    [
+                        (a + 1.0) - (a - 1.0) * cos + shelf,
+                        2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                        (a + 1.0) - (a - 1.0) * cos - shelf,
+                    ],
+                )
+            }
+            EqKind::Peaking => (
+                [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+                [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+            ),
+            EqKind::LowPass => (
+                [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+            EqKind::HighPass => (
+                [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+            EqKind::Notch => (
+                [1.0, -2.0 * cos, 1.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+        };
+        Self::from_coefficients(b, a)
+    }
+
+    /// Filter with the transfer function `b / a`, normalized by `a[0]`
+    pub(super) fn from_coefficients(b: [f32; 3], a: [f32; 3]) -> Self {
+        Self {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+            ..Self::default()
+        }
+    }
+
+    pub(super) fn process(&mut self, input: f32) -> f32 {
+        let output = self.b0 * input + self.z1;
+        self.z1 = self.b1 * input - self.a1 * output + self.z2;
+        self.z2 = self.b2 * input - self.a2 * output;
+        output
+    }
+}
+
+/// Runs the bands one after the other on every channel
+#[derive(Debug, Clone)]
+pub struct Equalizer {
+    bands: Vec<EqBand>,
+    /// The filters of every channel
+    channels: Vec<Vec<Biquad>>,
+}
+
+impl Equalizer {
+    pub fn new(bands: Vec<EqBand>) -> Self {
+        Self {
+            bands,
+            channels: Vec::new(),
+        }
+    }
+}
+
+impl FrameFilter for Equalizer {
+    fn apply(&mut self, frame: &mut [f32]) {
+        for (filters, sample) in self.channels.iter_mut().zip(frame.iter_mut()) {
+            for filter in filters.iter_mut() {
+                *sample = filter.process(*sample);
+            }
+        }
+    }
+
+    fn reset(&mut self, sample_rate: u32, channels: usize) {
+        let filters: Vec<Biquad> = self
+            .bands
+            .iter()
+            .map(|band| Biquad::new(band, sample_rate as f32))
+            .collect();
+        self.channels = vec![filters; channels];
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Equalizer;
+    use crate::config::{EqBand, EqKind};
+    use crate::sound::filter::FrameFilter;
+    use std::f32::consts::PI;
+
+    /// Level in decibels of a sine at `frequency` once it went through `band`
+    fn response(band: EqBand, frequency: f32) -> f32 {
+        let mut eq = Equalizer::new(vec![band]);
+        eq.reset(48000, 1);
+        let peak = (0..48000)
+            .map(|i| {
+                let mut frame = [(2.0 * PI * frequency * i as f32 / 48000.0).sin() * 0.1];
+                eq.apply(&mut frame);
+                frame[0].abs()
+            })
+            .skip(24000)
+            .fold(0f32, f32::max);
+        20.0 * (peak / 0.1).log10()
+    }
+
+    fn band(kind: EqKind, frequency: f32, gain_db: f32) -> EqBand {
+        EqBand {
+            kind,
+            frequency,
+            gain_db,
+            q: std::f32::consts::FRAC_1_SQRT_2,
+        }
+    }
+
+    #[test]
+    fn shapes_bands() {
+        let peak = band(EqKind::Peaking, 1000.0, 6.0);
+        assert!((response(peak, 1000.0) - 6.0).abs() < 0.2);
+        assert!(response(peak, 10000.0).abs() < 0.5);
+
+        let shelf = band(EqKind::LowShelf, 200.0, -12.0);
+        assert!((response(shelf, 30.0) + 12.0).abs() < 0.5);
+        assert!(response(shelf, 5000.0).abs() < 0.2);
+
+        let high_pass = band(EqKind::HighPass, 100.0, 0.0);
+        assert!(response(high_pass, 20.0) < -24.0);
+        assert!(response(high_pass, 1000.0).abs() < 0.2);
+
+        let notch = band(EqKind::Notch, 50.0, 0.0);
+        assert!(response(notch, 50.0) < -30.0);
+    }
+}
@@ -1,129 +1,465 @@
-use miniaudio::Format;
-use miniaudio::{Frames, FramesMut};
-use super::sample::Sample as SelfSample;
-// use super::freq::{FrequencyAnalyser, Frequencies, ApplyKind};
+use super::sink::{read_samples, write_mix};
+use miniaudio::{Format, Frames, FramesMut};
 
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
-use crate::utils::IdMap;
+/// A frame filter is applied to a whole frame, a sample per channel, in place.
+///
+/// Filters run in the audio callback, so they shouldn't allocate or wait on locks.
+pub trait FrameFilter: Send {
+    fn apply(&mut self, frame: &mut [f32]);
 
-/// A filter is simply a function applied to the height of the sound wave
-/// The sample filter is just applied to sigle values
-/// The frame filter is applied to a whole frame (&mut [Sample]), an array of samples
-#[derive(Clone)]
-pub struct Filter
-{
-    // sample_filters: IdMap<Arc<Box<dyn Fn(f32) -> f32 + Send + Sync>>>,
-    frame_filters: IdMap<Arc<Box<dyn Fn(Vec<&mut f32>) + Send + Sync>>>,
-    // freq_filters: IdMap<(Arc<Box<dyn Fn(f32, f32) -> f32 + Send + Sync>>, ApplyKind, f32)>
+    /// Called before the first frame and whenever the stream changes, for filters that
+    /// depend on the sample rate or keep state for each channel. A `FilterChain` calls it
+    /// outside the audio callback, so it may allocate.
+    fn reset(&mut self, _sample_rate: u32, _channels: usize) {}
 }
 
-impl Filter
+impl<F> FrameFilter for F
+where
+    F: FnMut(&mut [f32]) + Send,
 {
+    fn apply(&mut self, frame: &mut [f32]) {
+        self(frame)
+    }
+}
+
+/// Identifies a filter in its chain, unique across every chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FilterId(u64);
+
+impl FilterId {
+    pub fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Default for FilterId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Makes the filter added with `Message::AddFilter`, so the message can be cloned
+#[derive(Clone)]
+pub struct NewFilter(Arc<dyn Fn() -> Box<dyn FrameFilter> + Send + Sync>);
+
+impl NewFilter {
+    pub fn new<F>(make: F) -> Self
+    where
+        F: Fn() -> Box<dyn FrameFilter> + Send + Sync + 'static,
+    {
+        Self(Arc::new(make))
+    }
+
+    /// Makes copies of `filter`, each keeping its own state
+    pub fn from_filter<F>(filter: F) -> Self
+    where
+        F: FrameFilter + Clone + Sync + 'static,
+    {
+        Self::new(move || Box::new(filter.clone()))
+    }
+
+    /// Like `from_filter` for a closure
+    pub fn from_fn<F>(f: F) -> Self
+    where
+        F: FnMut(&mut [f32]) + Clone + Send + Sync + 'static,
+    {
+        Self::from_filter(f)
+    }
+
+    pub(super) fn make(&self) -> Box<dyn FrameFilter> {
+        (self.0)()
+    }
+}
+
+impl std::fmt::Debug for NewFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "NewFilter")
+    }
+}
+
+impl PartialEq for NewFilter {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+struct Stage {
+    id: FilterId,
+    bypassed: bool,
+    filter: Box<dyn FrameFilter>,
+}
+
+/// Filters removed from a `Filter` that its chain hasn't dropped yet, past it they are dropped
+/// in the audio callback
+const REMOVED_CAPACITY: usize = 16;
+
+/// Changes sent from a `FilterChain` to its `Filter`
+enum Edit {
+    /// With the sample rate and channels the filter was reset for
+    Add(FilterId, Box<dyn FrameFilter>, Option<(u32, usize)>),
+    Remove(FilterId),
+    Move(FilterId, usize),
+    Bypass(FilterId, bool),
+}
+
+/// The filters applied to a stream, in order, by its audio callback.
+///
+/// A `Filter` made with `FilterChain::new` picks up the changes made to the chain at the start
+/// of every `apply`.
+pub struct Filter {
+    stages: Vec<Stage>,
+    edits: crossbeam_channel::Receiver<Edit>,
+    /// Gives the removed filters back to the chain
+    removed: Option<crossbeam_channel::Sender<Box<dyn FrameFilter>>>,
+    /// Sample rate and channels the filters were reset for
+    stream: Option<(u32, usize)>,
+    /// The frame being filtered, for formats other than `f32`
+    frame: Vec<f32>,
+}
+
+impl Filter {
     pub fn new() -> Self {
         Self {
-            // sample_filters: IdMap::new(),
-            frame_filters: IdMap::new(),
-            // freq_filters: IdMap::new(),
+            stages: Vec::new(),
+            edits: crossbeam_channel::never(),
+            removed: None,
+            stream: None,
+            frame: Vec::new(),
         }
     }
 
-    // pub fn add_sample_filter<SampleFilter: Fn(f32) -> f32 + Send + Sync + 'static>(
-    //     &mut self,
-    //     f: SampleFilter,
-    // ) -> usize {
-    //     self.sample_filters.add(Arc::new(Box::new(f)))
-    // }
+    pub fn add_frame_filter<F: FrameFilter + 'static>(&mut self, f: F) -> FilterId {
+        let id = FilterId::new();
+        self.add(id, Box::new(f), None);
+        id
+    }
 
-    pub fn add_frame_filter<FrameFilter: Fn(Vec<&mut f32>) + Send + Sync + 'static>(
-        &mut self,
-        f: FrameFilter,
-    ) -> usize {
-        self.frame_filters.add(Arc::new(Box::new(f)))
-    }
-
-    // pub fn add_freq_filter<FreqFilter: Fn(f32, f32) -> f32 + Send + Sync + 'static>(
-    //     &mut self,
-    //     kind: ApplyKind,
-    //     freq: f32,
-    //     f: FreqFilter,
-    // ) -> usize {
-    //     self.freq_filters.add((Arc::new(Box::new(f)), kind, freq))
-    // }
-
-    // pub fn remove_sample_filter(&mut self, id: usize) -> Result<(), ()> {
-    //     self.sample_filters.remove(id).ok_or(()).map(|_| ())
-    // }
-
-    pub fn remove_frame_filter(&mut self, id: usize) -> Result<(), ()> {
-        self.frame_filters.remove(id).ok_or(()).map(|_| ())
-    }
-
-    // pub fn remove_freq_filter(&mut self, id: usize) -> Result<(), ()> {
-    //     self.freq_filters.remove(id).ok_or(()).map(|_| ())
-    // }
-
-    pub fn apply(&self, sample_rate: usize, input: &Frames, output: &mut FramesMut) {
-        output.as_bytes_mut().copy_from_slice(input.as_bytes());
-        match output.format() {
-            Format::F32 => {
-                for frame in output.frames_mut::<f32>() {
-                    for filter in self.frame_filters.iter() {
-                        filter(frame.iter_mut().collect())
-                    }
+    #[allow(clippy::result_unit_err)]
+    pub fn remove_frame_filter(&mut self, id: FilterId) -> Result<(), ()> {
+        let index = self.position(id).ok_or(())?;
+        self.stages.remove(index);
+        Ok(())
+    }
 
-                    // let mut analyser = FrequencyAnalyser::new(sample_rate, frame.len());
-                    // analyser.analise(frame);
+    fn position(&self, id: FilterId) -> Option<usize> {
+        self.stages.iter().position(|stage| stage.id == id)
+    }
 
-                    // let mut frequencies = analyser.freq().unwrap();
+    fn add(
+        &mut self,
+        id: FilterId,
+        mut filter: Box<dyn FrameFilter>,
+        reset_for: Option<(u32, usize)>,
+    ) {
+        if let Some((sample_rate, channels)) =
+            self.stream.filter(|stream| reset_for != Some(*stream))
+        {
+            filter.reset(sample_rate, channels);
+        }
+        self.stages.push(Stage {
+            id,
+            bypassed: false,
+            filter,
+        });
+    }
 
-                    // for (filter, kind, freq) in self.freq_filters.iter() {
-                    //     frequencies.apply(kind, freq, filter.as_ref());
-                    // }
-                    // let res = analyser.result().unwrap();
-                    // for i in 0..frame.len() {
-                    //     frame[i] = res[i]
-                    // }
+    fn edit(&mut self, edit: Edit) {
+        match edit {
+            Edit::Add(id, filter, reset_for) => self.add(id, filter, reset_for),
+            Edit::Remove(id) => {
+                if let Some(index) = self.position(id) {
+                    let stage = self.stages.remove(index);
+                    // Dropped by the chain, so its buffers aren't freed here
+                    if let Some(removed) = self.removed.as_ref() {
+                        let _ = removed.try_send(stage.filter);
+                    }
+                }
+            }
+            Edit::Move(id, index) => {
+                if let Some(from) = self.position(id) {
+                    let stage = self.stages.remove(from);
+                    let index = index.min(self.stages.len());
+                    self.stages.insert(index, stage);
                 }
             }
+            Edit::Bypass(id, bypassed) => {
+                if let Some(index) = self.position(id) {
+                    self.stages[index].bypassed = bypassed;
+                }
+            }
+        }
+    }
 
-            Format::S16 => {
-                for frame in output.frames_mut::<i16>() {
-                    let mut frame_f32: Vec<f32> = frame.iter().map(|x|x.to_f32()).collect();
-                    for filter in self.frame_filters.iter() {
-                        filter(frame_f32.iter_mut().collect())
-                    }
-                    
+    /// Takes in the edits of the chain and resets the filters for a stream
+    fn set_stream(&mut self, sample_rate: u32, channels: usize) {
+        while let Ok(edit) = self.edits.try_recv() {
+            self.edit(edit);
+        }
+        self.stream = Some((sample_rate, channels));
+        self.frame.resize(channels, 0.0);
+        for stage in self.stages.iter_mut() {
+            stage.filter.reset(sample_rate, channels);
+        }
+    }
 
-                    
-                   
-                    for (x, y) in frame.iter_mut().zip(frame_f32) {
-                        *x = y.to_i16()
-                    }
-                }
+    /// Takes in the edits of the chain and resets the filters when the stream changed. Returns
+    /// whether any filter needs to be applied.
+    fn prepare(&mut self, sample_rate: u32, channels: usize) -> bool {
+        if self.stream == Some((sample_rate, channels)) {
+            while let Ok(edit) = self.edits.try_recv() {
+                self.edit(edit);
+            }
+        } else {
+            // Only when the stream wasn't given to `FilterChain::set_stream` before it started
+            self.set_stream(sample_rate, channels);
+        }
+        !self.stages.iter().all(|stage| stage.bypassed)
+    }
+
+    fn apply_frame(stages: &mut [Stage], frame: &mut [f32]) {
+        for stage in stages.iter_mut().filter(|stage| !stage.bypassed) {
+            stage.filter.apply(frame)
+        }
+    }
+
+    /// Filters interleaved `samples` in place
+    pub fn apply_samples(&mut self, sample_rate: u32, channels: usize, samples: &mut [f32]) {
+        if !self.prepare(sample_rate, channels) {
+            return;
+        }
+        for frame in samples.chunks_exact_mut(channels) {
+            Self::apply_frame(&mut self.stages, frame)
+        }
+    }
+
+    /// Filters `output` in place, in whatever format it is
+    pub fn apply(&mut self, sample_rate: u32, output: &mut FramesMut) {
+        let channels = output.channels() as usize;
+        let format = output.format();
+        if format == Format::F32 {
+            self.apply_samples(sample_rate, channels, output.as_samples_mut::<f32>());
+            return;
+        }
+        if !self.prepare(sample_rate, channels) || output.sample_count() == 0 {
+            return;
+        }
+        let frame_size = output.as_bytes().len() / output.sample_count() * channels;
+        for bytes in output.as_bytes_mut().chunks_exact_mut(frame_size) {
+            read_samples(
+                &Frames::wrap(&*bytes, format, channels as u32),
+                &mut self.frame,
+            );
+            Self::apply_frame(&mut self.stages, &mut self.frame);
+            write_mix(
+                &self.frame,
+                &mut FramesMut::wrap(bytes, format, channels as u32),
+                None,
+            );
+        }
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Changes the filters of a `Filter` running in an audio callback without waiting on it.
+///
+/// The filters are reset and dropped by the chain, so the callback doesn't allocate or free
+/// them.
+pub struct FilterChain {
+    edits: crossbeam_channel::Sender<Edit>,
+    removed: crossbeam_channel::Receiver<Box<dyn FrameFilter>>,
+    /// The filters in order, with whether they are bypassed
+    filters: Vec<(FilterId, bool)>,
+    /// Sample rate and channels of the stream, which the filters are reset for before they
+    /// are sent
+    stream: Option<(u32, usize)>,
+}
+
+impl FilterChain {
+    /// A chain with no filters and the `Filter` following it
+    pub fn new() -> (Self, Filter) {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let (removed_sender, removed) = crossbeam_channel::bounded(REMOVED_CAPACITY);
+        let filter = Filter {
+            edits: receiver,
+            removed: Some(removed_sender),
+            ..Filter::new()
+        };
+        let chain = Self {
+            edits: sender,
+            removed,
+            filters: Vec::new(),
+            stream: None,
+        };
+        (chain, filter)
+    }
+
+    /// Resets the filters of `filter`, the `Filter` of this chain, for a stream before it
+    /// starts. The filters added from now on are reset for it before they are sent.
+    pub fn set_stream(&mut self, filter: &mut Filter, sample_rate: u32, channels: usize) {
+        self.stream = Some((sample_rate, channels));
+        filter.set_stream(sample_rate, channels);
+        self.removed.try_iter().for_each(drop);
+    }
+
+    /// The filters in the order they are applied, with whether they are bypassed
+    pub fn filters(&self) -> &[(FilterId, bool)] {
+        &self.filters
+    }
+
+    fn position(&self, id: FilterId) -> Option<usize> {
+        self.filters.iter().position(|(filter, _)| *filter == id)
+    }
 
-                
+    fn send(&self, edit: Edit) {
+        self.removed.try_iter().for_each(drop);
+        // The filter only goes away together with the stream, which takes the edits with it
+        let _ = self.edits.send(edit);
+    }
 
-                // let mut analyser = FrequencyAnalyser::new(sample_rate, output.sample_count());
-                // let v: Vec<f32> = output.as_samples::<i16>().into_iter().map(|x| x.to_f32()).collect();
-                // analyser.analise(&v);
+    /// Appends `filter`, replacing the filter with the same id
+    pub fn add(&mut self, id: FilterId, mut filter: Box<dyn FrameFilter>) {
+        if self.remove(id) {
+            log::warn!("replacing filter {:?}", id);
+        }
+        if let Some((sample_rate, channels)) = self.stream {
+            filter.reset(sample_rate, channels);
+        }
+        self.filters.push((id, false));
+        self.send(Edit::Add(id, filter, self.stream));
+    }
 
-                // let mut frequencies = analyser.freq().unwrap();
+    /// Returns false when there is no such filter
+    pub fn remove(&mut self, id: FilterId) -> bool {
+        match self.position(id) {
+            Some(index) => {
+                self.filters.remove(index);
+                self.send(Edit::Remove(id));
+                true
+            }
+            None => false,
+        }
+    }
 
-                // for (filter, kind, freq) in self.freq_filters.iter() {
-                //     frequencies.apply(kind, freq, filter.as_ref());
-                // }
-                // let res = analyser.result().unwrap();
-                // let samples_mut = output.as_samples_mut::<i16>();
-                // for i in 0..res.len() {
-                //     samples_mut[i] = res[i].to_i16()
-                // }
+    /// Moves the filter to `index`, the end of the chain when past it. Returns false when there
+    /// is no such filter.
+    pub fn move_to(&mut self, id: FilterId, index: usize) -> bool {
+        match self.position(id) {
+            Some(from) => {
+                let filter = self.filters.remove(from);
+                let index = index.min(self.filters.len());
+                self.filters.insert(index, filter);
+                self.send(Edit::Move(id, index));
+                true
             }
+            None => false,
+        }
+    }
 
-            f => {
-                panic!("Unexpected format: {:?}", f)
+    /// Skips the filter while `bypassed`, keeping its place and its state. Returns false when
+    /// there is no such filter.
+    pub fn bypass(&mut self, id: FilterId, bypassed: bool) -> bool {
+        match self.position(id) {
+            Some(index) => {
+                self.filters[index].1 = bypassed;
+                self.send(Edit::Bypass(id, bypassed));
+                true
             }
+            None => false,
         }
-        
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FilterChain, FilterId, FrameFilter};
+    use miniaudio::{Format, FramesMut};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn apply(filter: &mut super::Filter, samples: &[i16]) -> Vec<i16> {
+        let mut output = samples.to_vec();
+        filter.apply(48000, &mut FramesMut::wrap(&mut output, Format::S16, 2));
+        output
+    }
+
+    #[test]
+    fn follows_chain_edits() {
+        let (mut chain, mut filter) = FilterChain::new();
+        let half = FilterId::new();
+        let offset = FilterId::new();
+        chain.add(
+            half,
+            Box::new(|frame: &mut [f32]| frame.iter_mut().for_each(|x| *x *= 0.5)),
+        );
+        chain.add(
+            offset,
+            Box::new(|frame: &mut [f32]| frame.iter_mut().for_each(|x| *x += 0.5)),
+        );
+        // Halved, then offset, rounded like the mix of a sink
+        assert_eq!(apply(&mut filter, &[0, 0]), vec![16384, 16384]);
+
+        assert!(chain.move_to(offset, 0));
+        assert_eq!(chain.filters(), &[(offset, false), (half, false)]);
+        assert_eq!(apply(&mut filter, &[0, 0]), vec![8192, 8192]);
+
+        assert!(chain.bypass(offset, true));
+        assert_eq!(apply(&mut filter, &[16384, 16384]), vec![8192, 8192]);
+
+        assert!(chain.remove(half));
+        assert!(!chain.remove(half));
+        assert_eq!(apply(&mut filter, &[16384, 16384]), vec![16384, 16384]);
+    }
+
+    #[test]
+    fn filters_24_bit() {
+        let (mut chain, mut filter) = FilterChain::new();
+        chain.add(
+            FilterId::new(),
+            Box::new(|frame: &mut [f32]| frame.iter_mut().for_each(|x| *x *= 0.5)),
+        );
+        // 0x400000 and -0x400000, a half and minus a half
+        let mut output = [0x00, 0x00, 0x40, 0x00, 0x00, 0xc0];
+        filter.apply(48000, &mut FramesMut::wrap(&mut output, Format::S24, 2));
+        assert_eq!(output, [0x00, 0x00, 0x20, 0x00, 0x00, 0xe0]);
+    }
+
+    /// Counts its resets in a counter shared with the test
+    struct Resets(Arc<AtomicUsize>);
+
+    impl FrameFilter for Resets {
+        fn apply(&mut self, _frame: &mut [f32]) {}
+
+        fn reset(&mut self, _sample_rate: u32, _channels: usize) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn resets_and_drops_outside_the_stream() {
+        let (mut chain, mut filter) = FilterChain::new();
+        chain.set_stream(&mut filter, 48000, 2);
+        let resets = Arc::new(AtomicUsize::new(0));
+        let id = FilterId::new();
+        chain.add(id, Box::new(Resets(resets.clone())));
+        assert_eq!(resets.load(Ordering::Relaxed), 1);
+
+        apply(&mut filter, &[0, 0]);
+        assert_eq!(resets.load(Ordering::Relaxed), 1);
+
+        chain.remove(id);
+        apply(&mut filter, &[0, 0]);
+        // Given back to the chain, which drops it on its next edit
+        assert_eq!(Arc::strong_count(&resets), 2);
+        chain.add(FilterId::new(), Box::new(|_: &mut [f32]| ()));
+        assert_eq!(Arc::strong_count(&resets), 1);
     }
 }
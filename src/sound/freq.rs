@@ -2,66 +2,78 @@ use rustfft::{num_complex::Complex, num_traits::Zero, FFTplanner, FFT};
 
 use std::sync::Arc;
 
+use super::filter::FrameFilter;
+
+/// Frequency of bin `i`. The bins past the middle hold the negative frequencies, which mirror
+/// the positive ones for real signals.
 fn freq(i: usize, sample_rate: usize, bin_size: usize) -> f32 {
-	i as f32 * sample_rate as f32 / bin_size as f32
+    i.min(bin_size - i) as f32 * sample_rate as f32 / bin_size as f32
 }
 
+/// Transforms a whole block of samples at once, use a `Stft` for streams
 pub struct FrequencyAnalyser {
     sample_rate: usize,
     bin_size: usize,
     ffft: Arc<dyn FFT<f32>>,
-	ifft: Arc<dyn FFT<f32>>,
-	values: Option<Vec<Complex<f32>>>
+    ifft: Arc<dyn FFT<f32>>,
+    values: Option<Vec<Complex<f32>>>,
 }
 
 impl FrequencyAnalyser {
     pub fn new(sample_rate: usize, bin_size: usize) -> Self {
-		// println!("{}: {}", sample_rate, bin_size);
         let ffft = FFTplanner::new(false).plan_fft(bin_size);
         let ifft = FFTplanner::new(true).plan_fft(bin_size);
         Self {
             sample_rate,
             bin_size,
             ffft,
-			ifft,
-			values: None
+            ifft,
+            values: None,
+        }
+    }
+
+    pub fn analise(&mut self, v: &[f32]) {
+        assert!(
+            v.len() == self.bin_size,
+            "Data to analise shouldnt be different from the bin size"
+        );
+        let mut v: Vec<Complex<f32>> = v.iter().copied().map(|x| Complex::new(x, 0.)).collect();
+        let mut out = vec![Complex::zero(); self.bin_size];
+        self.ffft.process(&mut v, &mut out);
+        self.values = Some(out);
+    }
+
+    pub fn freq(&mut self) -> Option<Frequencies<'_>> {
+        let (sample_rate, bin_size) = (self.sample_rate, self.bin_size);
+        self.values
+            .as_mut()
+            .map(|x| Frequencies::new(x.as_mut_slice(), sample_rate, bin_size))
+    }
+
+    pub fn result(&mut self) -> Option<Vec<f32>> {
+        if let Some(x) = &mut self.values {
+            let mut out = vec![Complex::zero(); self.bin_size];
+            self.ifft.process(x.as_mut_slice(), &mut out);
+            Some(
+                out.into_iter()
+                    .map(|x| x.re / self.bin_size as f32)
+                    .collect(),
+            )
+        } else {
+            None
         }
-	}
-	
-	pub fn analise(&mut self, v: &[f32]) {
-		assert!(v.len() == self.bin_size, "Data to analise shouldnt be different from the bin size");
-		let mut v: Vec<Complex<f32>> = v.iter().copied().map(|x|Complex::new(x, 0.)).collect();
-		let mut out = vec![Complex::zero(); self.bin_size];
-		self.ffft.process(&mut v, &mut out);
-		self.values = Some(out);
-	}
-
-    pub fn freq<'a>(&'a mut self) -> Option<Frequencies<'a>> {
-		if let Some(x) = &mut self.values {
-			Some(Frequencies::new(x.as_mut_slice(), self.sample_rate, self.bin_size))
-		}else{
-			None
-		}
-	}
-
-	pub fn result(&mut self) -> Option<Vec<f32>> {
-		if let Some(x) = &mut self.values {
-			let mut out = vec![Complex::zero(); self.bin_size];
-			self.ifft.process(x.as_mut_slice(), &mut out);
-			Some(out.into_iter().map(|x| x.re / self.bin_size as f32).collect())
-		}else{
-			None
-		}
-	}
+    }
 }
 
-#[derive(Clone, Copy)]
+/// Which frequencies `Frequencies::apply` changes, compared with the frequency it is given
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ApplyKind {
-	Less,
-	LessEq,
-	Eq(f32),
-	GreaterEq,
-	Greater
+    Less,
+    LessEq,
+    /// Within the given distance
+    Eq(f32),
+    GreaterEq,
+    Greater,
 }
 
 pub struct Frequencies<'a> {
@@ -77,24 +89,268 @@ impl<'a> Frequencies<'a> {
             sample_rate,
             bin_size,
         }
-	}
-	
-	/// Applies a function to all frequencies that match
-	/// The function takes for input the curreent frequency and its amplitude, and returns the new value
-	pub fn apply<F: Fn(f32, f32) -> f32>(&mut self, kind: ApplyKind, frequency: f32, f: F) {
-		for i in 0..self.f.len() {
-			let curr_freq = freq(i, self.sample_rate, self.bin_size);
-			let op = match kind {
-				ApplyKind::Less => curr_freq < frequency,
-				ApplyKind::LessEq => curr_freq <= frequency,
-				ApplyKind::Eq(epsilon) => (curr_freq - frequency).abs() < epsilon,
-				ApplyKind::GreaterEq => curr_freq >= frequency,
-				ApplyKind::Greater => curr_freq > frequency,
-			};
-			if op {
-				let v = self.f[i];
-				self.f[i] = Complex::new(f(curr_freq, v.re), f(curr_freq, v.im))
-			}
-		}
-	}
+    }
+
+    /// Applies a function to all frequencies that match.
+    /// The function takes for input the current frequency and its amplitude, and returns the new
+    /// amplitude. The phase is kept.
+    pub fn apply<F: Fn(f32, f32) -> f32>(&mut self, kind: ApplyKind, frequency: f32, f: F) {
+        for i in 0..self.f.len() {
+            let curr_freq = freq(i, self.sample_rate, self.bin_size);
+            let op = match kind {
+                ApplyKind::Less => curr_freq < frequency,
+                ApplyKind::LessEq => curr_freq <= frequency,
+                ApplyKind::Eq(epsilon) => (curr_freq - frequency).abs() < epsilon,
+                ApplyKind::GreaterEq => curr_freq >= frequency,
+                ApplyKind::Greater => curr_freq > frequency,
+            };
+            if op {
+                let amplitude = self.f[i].norm();
+                if amplitude > 0.0 {
+                    self.f[i] *= f(curr_freq, amplitude) / amplitude;
+                }
+            }
+        }
+    }
+
+    /// Multiplies every bin up to the middle one by its gain, along with the bin mirroring it
+    pub fn scale(&mut self, gains: &[f32]) {
+        let len = self.f.len();
+        for (i, gain) in gains.iter().enumerate().take(len / 2 + 1) {
+            self.f[i] *= gain;
+            if i != 0 && i != len - i {
+                self.f[len - i] *= gain;
+            }
+        }
+    }
+
+    /// The frequency and amplitude of every bin up to the middle one, the others mirror them
+    pub fn amplitudes(&self) -> impl Iterator<Item = (f32, f32)> + '_ {
+        self.f[..=self.bin_size / 2]
+            .iter()
+            .enumerate()
+            .map(move |(i, v)| (freq(i, self.sample_rate, self.bin_size), v.norm()))
+    }
+}
+
+/// One channel of a `Stft`
+#[derive(Clone)]
+struct StftChannel {
+    /// The last `fft_size` samples
+    input: Vec<f32>,
+    /// Samples ready to go out
+    output: Vec<f32>,
+    /// Overlapping blocks being added up
+    sum: Vec<f32>,
+}
+
+/// Short-time Fourier transform of a stream, for each channel. Blocks of `fft_size` samples
+/// overlapping by three quarters go through a Hann window, get changed in the frequency domain
+/// and are added back together, delaying the stream by `fft_size` samples.
+#[derive(Clone)]
+pub struct Stft {
+    fft_size: usize,
+    hop: usize,
+    sample_rate: usize,
+    /// Samples taken since the last block
+    filled: usize,
+    window: Vec<f32>,
+    forward: Arc<dyn FFT<f32>>,
+    inverse: Arc<dyn FFT<f32>>,
+    buffer: Vec<Complex<f32>>,
+    spectrum: Vec<Complex<f32>>,
+    channels: Vec<StftChannel>,
+}
+
+impl Stft {
+    /// Sum of the squared Hann windows overlapping at any point, taken out when adding up
+    const OVERLAP_GAIN: f32 = 1.5;
+
+    /// Makes the transform for `fft_size` samples, rounded up to a multiple of 4 for the
+    /// overlap
+    pub fn new(fft_size: usize, sample_rate: u32, channels: usize) -> Self {
+        let fft_size = (fft_size.max(16) + 3) & !3;
+        let window = (0..fft_size)
+            .map(|i| {
+                let phase = i as f32 / fft_size as f32;
+                0.5 - 0.5 * (2.0 * std::f32::consts::PI * phase).cos()
+            })
+            .collect();
+        let channels = (0..channels)
+            .map(|_| StftChannel {
+                input: vec![0.0; fft_size],
+                output: vec![0.0; fft_size / 4],
+                sum: vec![0.0; fft_size],
+            })
+            .collect();
+        Self {
+            fft_size,
+            hop: fft_size / 4,
+            sample_rate: sample_rate as usize,
+            filled: 0,
+            window,
+            forward: FFTplanner::new(false).plan_fft(fft_size),
+            inverse: FFTplanner::new(true).plan_fft(fft_size),
+            buffer: vec![Complex::zero(); fft_size],
+            spectrum: vec![Complex::zero(); fft_size],
+            channels,
+        }
+    }
+
+    pub fn fft_size(&self) -> usize {
+        self.fft_size
+    }
+
+    /// Takes a frame in and gives out the one from `fft_size` samples before, changed by
+    /// `change` whenever a block is complete. `change` gets the channel and its frequencies.
+    pub fn process<F>(&mut self, frame: &mut [f32], mut change: F)
+    where
+        F: FnMut(usize, &mut Frequencies),
+    {
+        let start = self.fft_size - self.hop;
+        for (channel, sample) in self.channels.iter_mut().zip(frame.iter_mut()) {
+            channel.input[start + self.filled] = *sample;
+            *sample = channel.output[self.filled];
+        }
+        self.filled += 1;
+        if self.filled < self.hop {
+            return;
+        }
+        self.filled = 0;
+
+        let scale = 1.0 / (self.fft_size as f32 * Self::OVERLAP_GAIN);
+        for (index, channel) in self.channels.iter_mut().enumerate() {
+            for ((value, sample), window) in self
+                .buffer
+                .iter_mut()
+                .zip(channel.input.iter())
+                .zip(self.window.iter())
+            {
+                *value = Complex::new(sample * window, 0.0);
+            }
+            self.forward.process(&mut self.buffer, &mut self.spectrum);
+            change(
+                index,
+                &mut Frequencies::new(&mut self.spectrum, self.sample_rate, self.fft_size),
+            );
+            self.inverse.process(&mut self.spectrum, &mut self.buffer);
+            for ((sum, value), window) in channel
+                .sum
+                .iter_mut()
+                .zip(self.buffer.iter())
+                .zip(self.window.iter())
+            {
+                *sum += value.re * window * scale;
+            }
+
+            channel.output.copy_from_slice(&channel.sum[..self.hop]);
+            channel.sum.copy_within(self.hop.., 0);
+            let end = channel.sum.len() - self.hop;
+            channel.sum[end..].iter_mut().for_each(|sum| *sum = 0.0);
+            channel.input.copy_within(self.hop.., 0);
+        }
+    }
+}
+
+/// Changes the amplitude of the frequencies picked by an `ApplyKind`, as a stage of a
+/// `filter::Filter`
+#[derive(Clone)]
+pub struct FrequencyFilter {
+    fft_size: usize,
+    kind: ApplyKind,
+    frequency: f32,
+    f: Arc<dyn Fn(f32, f32) -> f32 + Send + Sync>,
+    /// Made once the stream is known
+    stft: Option<Stft>,
+}
+
+impl FrequencyFilter {
+    /// Runs `f` on the frequencies that match `kind` and `frequency`, see `Frequencies::apply`.
+    ///
+    /// Bigger `fft_size`s tell frequencies apart better but delay the stream more.
+    pub fn new<F>(fft_size: usize, kind: ApplyKind, frequency: f32, f: F) -> Self
+    where
+        F: Fn(f32, f32) -> f32 + Send + Sync + 'static,
+    {
+        Self {
+            fft_size,
+            kind,
+            frequency,
+            f: Arc::new(f),
+            stft: None,
+        }
+    }
+}
+
+impl FrameFilter for FrequencyFilter {
+    fn apply(&mut self, frame: &mut [f32]) {
+        let (kind, frequency, f) = (self.kind, self.frequency, &self.f);
+        if let Some(stft) = self.stft.as_mut() {
+            stft.process(frame, |_, frequencies| {
+                frequencies.apply(kind, frequency, |freq, amplitude| f(freq, amplitude))
+            });
+        }
+    }
+
+    fn reset(&mut self, sample_rate: u32, channels: usize) {
+        self.stft = Some(Stft::new(self.fft_size, sample_rate, channels));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ApplyKind, FrequencyFilter, Stft};
+    use crate::sound::filter::FrameFilter;
+    use std::f32::consts::PI;
+
+    fn sine(frequency: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (2.0 * PI * frequency * i as f32 / 8000.0).sin() * 0.5)
+            .collect()
+    }
+
+    #[test]
+    fn rebuilds_stream_delayed() {
+        let input = sine(440.0, 1024);
+        let mut stft = Stft::new(64, 8000, 1);
+        let output: Vec<f32> = input
+            .iter()
+            .map(|sample| {
+                let mut frame = [*sample];
+                stft.process(&mut frame, |_, _| ());
+                frame[0]
+            })
+            .collect();
+        for (out, sample) in output[256..].iter().zip(input[256 - 64..].iter()) {
+            assert!(
+                (out - sample).abs() < 0.001,
+                "{} instead of {}",
+                out,
+                sample
+            );
+        }
+    }
+
+    #[test]
+    fn cuts_matching_frequencies() {
+        let low = sine(250.0, 2048);
+        let high = sine(2000.0, 2048);
+        let mut filter = FrequencyFilter::new(128, ApplyKind::Less, 1000.0, |_, _| 0.0);
+        filter.reset(8000, 2);
+        let mut peaks = [0f32; 2];
+        for (i, (low, high)) in low.iter().zip(high.iter()).enumerate() {
+            let mut frame = [*low, *high];
+            filter.apply(&mut frame);
+            if i >= 512 {
+                peaks[0] = peaks[0].max(frame[0].abs());
+                peaks[1] = peaks[1].max(frame[1].abs());
+            }
+        }
+        assert!(peaks[0] < 0.02, "low left at {}", peaks[0]);
+        assert!(
+            (peaks[1] - 0.5).abs() < 0.02,
+            "high changed to {}",
+            peaks[1]
+        );
+    }
 }
@@ -0,0 +1,464 @@
+//! Loudness of the sounds as EBU R128 measures it (ITU-R BS.1770), so sounds mastered at
+//! different levels can be played back at the same one. Measuring decodes the whole sound, so
+//! the results are cached on disk until the file changes.
+
+use anyhow::Result;
+use directories::ProjectDirs;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::decoder::Decoder;
+use super::eq::Biquad;
+use super::source::Source;
+use crate::config;
+
+/// Integrated loudness and true peak of a sound
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS, `None` when the sound is silent
+    pub integrated_lufs: Option<f32>,
+    /// Highest peak of the waveform, between the samples too, in decibels relative to full scale
+    pub true_peak_db: f32,
+}
+
+impl Loudness {
+    /// Gain in decibels bringing the sound to the target of `normalization`, lowered when the
+    /// true peak would go over its ceiling
+    pub fn gain_db(&self, normalization: &config::Normalization) -> f32 {
+        match self.integrated_lufs {
+            Some(integrated) => (normalization.target_lufs - integrated)
+                .min(normalization.true_peak_db - self.true_peak_db),
+            None => 0.0,
+        }
+    }
+}
+
+/// The K-weighting filter of BS.1770: a high shelf modelling the head, then a high-pass.
+/// The coefficients are given for 48 kHz only, so they are worked out again from the analog
+/// prototypes for other rates.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let sample_rate = sample_rate as f64;
+    let to_f32 = |values: [f64; 3]| [values[0] as f32, values[1] as f32, values[2] as f32];
+
+    let k = (PI * 1681.974450955533 / sample_rate).tan();
+    let q = 0.7071752369554196;
+    let high = 10f64.powf(3.999843853973347 / 20.0);
+    let band = high.powf(0.4996667741545416);
+    let shelf = Biquad::from_coefficients(
+        to_f32([
+            high + band * k / q + k * k,
+            2.0 * (k * k - high),
+            high - band * k / q + k * k,
+        ]),
+        to_f32([
+            1.0 + k / q + k * k,
+            2.0 * (k * k - 1.0),
+            1.0 - k / q + k * k,
+        ]),
+    );
+
+    let k = (PI * 38.13547087602444 / sample_rate).tan();
+    let q = 0.5003270373238773;
+    let high_pass = Biquad::from_coefficients(
+        [1.0, -2.0, 1.0],
+        to_f32([
+            1.0 + k / q + k * k,
+            2.0 * (k * k - 1.0),
+            1.0 - k / q + k * k,
+        ]),
+    );
+    [shelf, high_pass]
+}
+
+/// Weight of each channel in the sum, the surround ones count more and the low frequency one
+/// not at all
+fn channel_weights(channels: usize) -> Vec<f64> {
+    match channels {
+        5 => vec![1.0, 1.0, 1.0, 1.41, 1.41],
+        6 => vec![1.0, 1.0, 1.0, 0.0, 1.41, 1.41],
+        _ => vec![1.0; channels],
+    }
+}
+
+fn to_lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+fn mean(powers: &[f64]) -> f64 {
+    powers.iter().sum::<f64>() / powers.len() as f64
+}
+
+/// Peak of one channel oversampled 4 times with a windowed sinc, which finds the peaks that
+/// fall between the samples
+struct TruePeak {
+    /// Newest sample first
+    history: [f32; TruePeak::TAPS],
+    peak: f32,
+}
+
+impl TruePeak {
+    const OVERSAMPLING: usize = 4;
+    const TAPS: usize = 12;
+
+    /// Interpolation filter of each position between two samples
+    fn kernels() -> [[f32; Self::TAPS]; Self::OVERSAMPLING] {
+        let half = (Self::TAPS / 2) as f64;
+        let mut kernels = [[0.0; Self::TAPS]; Self::OVERSAMPLING];
+        for (phase, kernel) in kernels.iter_mut().enumerate() {
+            for (tap, weight) in kernel.iter_mut().enumerate() {
+                let distance = half - tap as f64 - phase as f64 / Self::OVERSAMPLING as f64;
+                let sinc = if distance == 0.0 {
+                    1.0
+                } else {
+                    (PI * distance).sin() / (PI * distance)
+                };
+                let window = 0.5 * (1.0 + (PI * distance / (half + 1.0)).cos());
+                *weight = (sinc * window) as f32;
+            }
+        }
+        kernels
+    }
+
+    fn new() -> Self {
+        Self {
+            history: [0.0; Self::TAPS],
+            peak: 0.0,
+        }
+    }
+
+    fn push(&mut self, sample: f32, kernels: &[[f32; Self::TAPS]; Self::OVERSAMPLING]) {
+        self.history.copy_within(..Self::TAPS - 1, 1);
+        self.history[0] = sample;
+        for kernel in kernels.iter() {
+            let value: f32 = kernel
+                .iter()
+                .zip(self.history.iter())
+                .map(|(weight, sample)| weight * sample)
+                .sum();
+            self.peak = self.peak.max(value.abs());
+        }
+    }
+}
+
+/// Measures interleaved frames as they come
+struct Meter {
+    weights: Vec<f64>,
+    filters: Vec<[Biquad; 2]>,
+    peaks: Vec<TruePeak>,
+    kernels: [[f32; TruePeak::TAPS]; TruePeak::OVERSAMPLING],
+    /// Frames in a 100 ms step, blocks are 4 steps long
+    step_frames: usize,
+    frames: usize,
+    /// Weighted sum of squares of the step being filled
+    power: f64,
+    /// Weighted sums of squares of the last steps
+    steps: VecDeque<f64>,
+    /// Mean weighted power of every block
+    blocks: Vec<f64>,
+    /// For sounds shorter than a block, which are measured as a whole
+    total_power: f64,
+    total_frames: usize,
+}
+
+impl Meter {
+    const BLOCK_STEPS: usize = 4;
+
+    fn new(sample_rate: u32, channels: usize) -> Self {
+        Self {
+            weights: channel_weights(channels),
+            filters: vec![k_weighting(sample_rate); channels],
+            peaks: (0..channels).map(|_| TruePeak::new()).collect(),
+            kernels: TruePeak::kernels(),
+            step_frames: (sample_rate as usize / 10).max(1),
+            frames: 0,
+            power: 0.0,
+            steps: VecDeque::with_capacity(Self::BLOCK_STEPS + 1),
+            blocks: Vec::new(),
+            total_power: 0.0,
+            total_frames: 0,
+        }
+    }
+
+    fn push(&mut self, frame: &[f32]) {
+        for (((sample, filters), weight), peak) in frame
+            .iter()
+            .zip(self.filters.iter_mut())
+            .zip(self.weights.iter())
+            .zip(self.peaks.iter_mut())
+        {
+            let shelved = filters[0].process(*sample);
+            let weighted = filters[1].process(shelved) as f64;
+            self.power += weight * weighted * weighted;
+            peak.push(*sample, &self.kernels);
+        }
+        self.frames += 1;
+        if self.frames < self.step_frames {
+            return;
+        }
+        self.steps.push_back(self.power);
+        self.total_power += self.power;
+        self.total_frames += self.frames;
+        self.power = 0.0;
+        self.frames = 0;
+        if self.steps.len() > Self::BLOCK_STEPS {
+            self.steps.pop_front();
+        }
+        if self.steps.len() == Self::BLOCK_STEPS {
+            let frames = (Self::BLOCK_STEPS * self.step_frames) as f64;
+            self.blocks.push(self.steps.iter().sum::<f64>() / frames);
+        }
+    }
+
+    fn finish(mut self) -> Loudness {
+        if self.blocks.is_empty() && self.total_frames + self.frames > 0 {
+            let frames = (self.total_frames + self.frames) as f64;
+            self.blocks.push((self.total_power + self.power) / frames);
+        }
+        // The last samples are still in the middle of the interpolation filters
+        for peak in self.peaks.iter_mut() {
+            for _ in 0..TruePeak::TAPS / 2 {
+                peak.push(0.0, &self.kernels);
+            }
+        }
+        let peak = self.peaks.iter().fold(0f32, |max, peak| max.max(peak.peak));
+
+        // Gated twice, first on silence and then on the parts well below the rest
+        let loud: Vec<f64> = self
+            .blocks
+            .into_iter()
+            .filter(|power| to_lufs(*power) > -70.0)
+            .collect();
+        let integrated_lufs = if loud.is_empty() {
+            None
+        } else {
+            let relative = to_lufs(mean(&loud)) - 10.0;
+            let kept: Vec<f64> = loud
+                .into_iter()
+                .filter(|power| to_lufs(*power) > relative)
+                .collect();
+            Some(to_lufs(mean(&kept)) as f32)
+        };
+        Loudness {
+            integrated_lufs,
+            true_peak_db: 20.0 * peak.max(1e-6).log10(),
+        }
+    }
+}
+
+/// Measures a source until it ends
+pub(super) fn measure<S>(source: S) -> Loudness
+where
+    S: Source<Item = f32>,
+{
+    let channels = (source.channels() as usize).max(1);
+    let mut meter = Meter::new(source.sample_rate(), channels);
+    let mut frame = Vec::with_capacity(channels);
+    for sample in source {
+        frame.push(sample);
+        if frame.len() == channels {
+            meter.push(&frame);
+            frame.clear();
+        }
+    }
+    meter.finish()
+}
+
+/// Decodes the sound at `path` and measures it
+pub fn measure_file(path: &Path) -> Result<Loudness> {
+    let reader = std::io::BufReader::with_capacity(1000 * 50, std::fs::File::open(path)?);
+    Ok(measure(Decoder::new(reader)?))
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+struct CacheEntry {
+    /// When the file was last changed as it was measured
+    modified: SystemTime,
+    loudness: Loudness,
+}
+
+/// Loudness of the sounds measured so far by their path, forgotten once their file changes
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct LoudnessCache {
+    sounds: HashMap<PathBuf, CacheEntry>,
+}
+
+fn modified(path: &Path) -> Result<SystemTime> {
+    Ok(std::fs::metadata(path)?.modified()?)
+}
+
+impl LoudnessCache {
+    fn path() -> PathBuf {
+        let project_dirs = ProjectDirs::from("", "", "MrLlamasWonderfulSoundboard").unwrap();
+        project_dirs.cache_dir().join("loudness.ron")
+    }
+
+    /// The cache saved last, empty when there is none or it can't be read
+    pub fn load() -> Self {
+        let path = Self::path();
+        if !path.exists() {
+            return Self::default();
+        }
+        let loaded = std::fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|text| Ok(ron::from_str(&text)?));
+        match loaded {
+            Ok(cache) => cache,
+            Err(err) => {
+                warn!("could not read the loudness cache {}", err);
+                Self::default()
+            }
+        }
+    }
+
+    /// Saves the cache, merged into the saved one so the sounds measured elsewhere meanwhile
+    /// are kept
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut saved = Self::load();
+        saved.merge(self.clone());
+        std::fs::write(path, ron::to_string(&saved)?)?;
+        Ok(())
+    }
+
+    /// Adds the sounds of `other`, keeping the latest measure of the ones in both
+    pub fn merge(&mut self, other: Self) {
+        for (path, entry) in other.sounds {
+            match self.sounds.get(&path) {
+                Some(kept) if kept.modified > entry.modified => (),
+                _ => {
+                    self.sounds.insert(path, entry);
+                }
+            }
+        }
+    }
+
+    /// Loudness of the sound at `path`, if it was measured since it last changed
+    pub fn get(&self, path: &Path) -> Option<Loudness> {
+        let entry = self.sounds.get(path)?;
+        match modified(path) {
+            Ok(modified) if modified == entry.modified => Some(entry.loudness),
+            _ => None,
+        }
+    }
+
+    /// Keeps the loudness of the sound at `path` as it is now
+    pub fn insert(&mut self, path: &Path, loudness: Loudness) -> Result<()> {
+        let entry = CacheEntry {
+            modified: modified(path)?,
+            loudness,
+        };
+        self.sounds.insert(path.to_path_buf(), entry);
+        Ok(())
+    }
+
+    /// Loudness of the sound at `path`, measured unless it is cached
+    pub fn measure(&mut self, path: &Path) -> Result<Loudness> {
+        if let Some(loudness) = self.get(path) {
+            return Ok(loudness);
+        }
+        let loudness = measure_file(path)?;
+        self.insert(path, loudness)?;
+        Ok(loudness)
+    }
+
+    /// Measures every sound of `sounds` that isn't cached, returning the ones that failed.
+    /// Nothing is saved until `save` is called.
+    pub fn measure_all(
+        &mut self,
+        sounds: &config::SoundConfig,
+    ) -> Vec<(config::Sound, anyhow::Error)> {
+        let mut failed = Vec::new();
+        for sound in sounds.sounds.values().flat_map(|repo| repo.values()) {
+            if let Err(err) = self.measure(&sound.wav) {
+                failed.push((sound.clone(), err));
+            }
+        }
+        failed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CacheEntry, Loudness, LoudnessCache, Meter};
+    use std::f32::consts::PI;
+    use std::time::{Duration, SystemTime};
+
+    fn measure(sample_rate: u32, frames: impl Iterator<Item = [f32; 2]>) -> super::Loudness {
+        let mut meter = Meter::new(sample_rate, 2);
+        for frame in frames {
+            meter.push(&frame);
+        }
+        meter.finish()
+    }
+
+    #[test]
+    fn sine_reads_its_level() {
+        // A 1 kHz sine in both channels reads its peak level in LUFS
+        let amplitude = crate::utils::db_to_gain(-23.0);
+        let sine = (0..48000 * 5).map(|i| {
+            let value = (2.0 * PI * 1000.0 * i as f32 / 48000.0).sin() * amplitude;
+            [value, value]
+        });
+        let loudness = measure(48000, sine);
+        let integrated = loudness.integrated_lufs.unwrap();
+        assert!(
+            (integrated + 23.0).abs() < 0.1,
+            "measured {} LUFS",
+            integrated
+        );
+
+        // Silence is gated away
+        let silence = measure(44100, (0..44100).map(|_| [0.0, 0.0]));
+        assert_eq!(silence.integrated_lufs, None);
+    }
+
+    #[test]
+    fn finds_peaks_between_samples() {
+        // Every sample lands halfway between the peaks, 3 dB under them
+        let sine = (0..8000).map(|i| {
+            let value = (PI / 2.0 * i as f32 + PI / 4.0).sin() * 0.5;
+            [value, -value]
+        });
+        let loudness = measure(8000, sine);
+        assert!(
+            (loudness.true_peak_db + 6.02).abs() < 0.3,
+            "true peak {} dB",
+            loudness.true_peak_db
+        );
+    }
+
+    #[test]
+    fn merge_keeps_latest_measure() {
+        let entry = |secs, lufs| CacheEntry {
+            modified: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            loudness: Loudness {
+                integrated_lufs: Some(lufs),
+                true_peak_db: 0.0,
+            },
+        };
+        let cache = |entries: Vec<(&str, CacheEntry)>| LoudnessCache {
+            sounds: entries
+                .into_iter()
+                .map(|(path, entry)| (path.into(), entry))
+                .collect(),
+        };
+        let mut saved = cache(vec![("a", entry(2, -20.0)), ("b", entry(1, -18.0))]);
+        saved.merge(cache(vec![("a", entry(1, -30.0)), ("c", entry(1, -14.0))]));
+
+        let lufs = |path: &str| {
+            saved.sounds[std::path::Path::new(path)]
+                .loudness
+                .integrated_lufs
+        };
+        assert_eq!(lufs("a"), Some(-20.0));
+        assert_eq!(lufs("b"), Some(-18.0));
+        assert_eq!(lufs("c"), Some(-14.0));
+    }
+}
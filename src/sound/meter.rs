@@ -0,0 +1,148 @@
+//! Peak and RMS meters for the audio callbacks, read by the sound loop at the interval set with
+//! `Message::SetMeterInterval`
+
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+/// Channels metered, the ones past it are left out
+const MAX_CHANNELS: usize = 8;
+/// Level counted as full scale, a 16 bit step under it so gains rounding a full scale sound
+/// down a bit still count
+const CLIP_LEVEL: f32 = 1.0 - 1.0 / 32_768.0;
+
+/// Raises the `f32` stored as bits in `max` to `level` if it is higher. Levels are positive, so
+/// their bits order the same way they do.
+pub(super) fn record_max(max: &AtomicU32, level: f32) {
+    max.fetch_max(level.max(0.0).to_bits(), Ordering::Relaxed);
+}
+
+/// Level of one channel since the last reading
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelLevel {
+    /// Highest sample, over `1.0` when a sink's mix was clipped
+    pub peak: f32,
+    pub rms: f32,
+    /// A sample reached full scale
+    pub clipped: bool,
+}
+
+/// Levels of the samples of one callback, gathered before they are added to a `LevelMeter`
+pub(super) struct Block {
+    channels: usize,
+    channel: usize,
+    frames: u64,
+    peaks: [f32; MAX_CHANNELS],
+    squares: [f64; MAX_CHANNELS],
+}
+
+impl Block {
+    pub(super) fn new(channels: usize) -> Self {
+        Self {
+            channels: channels.max(1),
+            channel: 0,
+            frames: 0,
+            peaks: [0.0; MAX_CHANNELS],
+            squares: [0.0; MAX_CHANNELS],
+        }
+    }
+
+    /// Adds the next interleaved sample
+    pub(super) fn push(&mut self, sample: f32) {
+        if let Some(peak) = self.peaks.get_mut(self.channel) {
+            *peak = peak.max(sample.abs());
+            self.squares[self.channel] += (sample * sample) as f64;
+        }
+        self.channel += 1;
+        if self.channel == self.channels {
+            self.channel = 0;
+            self.frames += 1;
+        }
+    }
+}
+
+/// Levels of every channel of a stream, shared with its callback which mustn't wait on locks
+#[derive(Debug, Default)]
+pub struct LevelMeter {
+    channels: AtomicUsize,
+    frames: AtomicU64,
+    /// Highest samples, as `f32` bits
+    peaks: [AtomicU32; MAX_CHANNELS],
+    /// Sums of squares, as `f64` bits
+    squares: [AtomicU64; MAX_CHANNELS],
+}
+
+impl LevelMeter {
+    pub(super) fn record(&self, block: &Block) {
+        self.channels.store(block.channels, Ordering::Relaxed);
+        for ((peak, squares), (block_peak, block_squares)) in self
+            .peaks
+            .iter()
+            .zip(self.squares.iter())
+            .zip(block.peaks.iter().zip(block.squares.iter()))
+            .take(block.channels)
+        {
+            record_max(peak, *block_peak);
+            let _ = squares.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+                Some((f64::from_bits(sum) + block_squares).to_bits())
+            });
+        }
+        self.frames.fetch_add(block.frames, Ordering::Relaxed);
+    }
+
+    /// Meters interleaved samples
+    pub(super) fn record_samples(&self, samples: &[f32], channels: usize) {
+        let mut block = Block::new(channels);
+        for sample in samples {
+            block.push(*sample);
+        }
+        self.record(&block);
+    }
+
+    /// Level of each channel since the last call, empty when nothing was metered
+    pub fn take(&self) -> Vec<ChannelLevel> {
+        let frames = self.frames.swap(0, Ordering::Relaxed);
+        if frames == 0 {
+            return Vec::new();
+        }
+        let channels = self.channels.load(Ordering::Relaxed).min(MAX_CHANNELS);
+        self.peaks
+            .iter()
+            .zip(self.squares.iter())
+            .take(channels)
+            .map(|(peak, squares)| {
+                let peak = f32::from_bits(peak.swap(0, Ordering::Relaxed));
+                let squares = f64::from_bits(squares.swap(0, Ordering::Relaxed));
+                ChannelLevel {
+                    peak,
+                    rms: (squares / frames as f64).sqrt() as f32,
+                    clipped: peak >= CLIP_LEVEL,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LevelMeter;
+
+    #[test]
+    fn meters_each_channel() {
+        let meter = LevelMeter::default();
+        let samples: Vec<f32> = (0..200)
+            .flat_map(|i| {
+                let square = if i % 2 == 0 { 0.5 } else { -0.5 };
+                vec![square, 0.0, if i == 100 { 1.2 } else { 0.1 }]
+            })
+            .collect();
+        meter.record_samples(&samples, 3);
+
+        let levels = meter.take();
+        assert_eq!(levels.len(), 3);
+        assert_eq!(levels[0].peak, 0.5);
+        assert!((levels[0].rms - 0.5).abs() < 1e-6);
+        assert!(!levels[0].clipped);
+        assert_eq!(levels[1].rms, 0.0);
+        assert!(levels[2].clipped);
+        assert!(meter.take().is_empty());
+    }
+}
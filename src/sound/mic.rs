@@ -0,0 +1,86 @@
+//! Gain, gating and metering of the microphone passed through to the loop device
+
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use super::meter::{Block, ChannelLevel, LevelMeter};
+
+/// Controls of the passthrough, shared with the duplex callback which mustn't wait on locks
+pub(super) struct MicState {
+    /// Linear gain, as `f32` bits
+    gain: AtomicU32,
+    /// Lets the microphone through, push to talk and push to mute close it
+    open: AtomicBool,
+    levels: LevelMeter,
+}
+
+impl MicState {
+    pub(super) fn new() -> Self {
+        Self {
+            gain: AtomicU32::new(1.0f32.to_bits()),
+            open: AtomicBool::new(true),
+            levels: LevelMeter::default(),
+        }
+    }
+
+    pub(super) fn set_gain(&self, gain: f32) {
+        self.gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    pub(super) fn is_open(&self) -> bool {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn set_open(&self, open: bool) {
+        self.open.store(open, Ordering::Relaxed);
+    }
+
+    /// Level of each channel of the microphone after the gain since the last call. It is
+    /// metered whether the microphone is let through or not.
+    pub(super) fn take_levels(&self) -> Vec<ChannelLevel> {
+        self.levels.take()
+    }
+
+    /// Applies the gain to interleaved `samples` and meters them, then silences them while
+    /// closed
+    pub(super) fn pass(&self, samples: &mut [f32], channels: usize) {
+        let gain = f32::from_bits(self.gain.load(Ordering::Relaxed));
+        let open = self.open.load(Ordering::Relaxed);
+        let mut block = Block::new(channels);
+        for sample in samples.iter_mut() {
+            let value = (*sample * gain).clamp(-1.0, 1.0);
+            block.push(value);
+            *sample = if open { value } else { 0.0 };
+        }
+        self.levels.record(&block);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MicState;
+
+    fn pass(state: &MicState, input: &[f32]) -> Vec<f32> {
+        let mut samples = input.to_vec();
+        state.pass(&mut samples, 2);
+        samples
+    }
+
+    fn peak(state: &MicState) -> f32 {
+        let levels = state.take_levels();
+        levels.iter().map(|level| level.peak).fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn applies_gain_and_gate() {
+        let state = MicState::new();
+        state.set_gain(0.5);
+        assert_eq!(pass(&state, &[0.5, -0.5]), vec![0.25, -0.25]);
+        assert!((peak(&state) - 0.25).abs() < 0.001);
+
+        state.set_open(false);
+        assert_eq!(pass(&state, &[0.5, -0.5]), vec![0.0, 0.0]);
+        // Still metered while closed
+        assert!((peak(&state) - 0.25).abs() < 0.001);
+        assert_eq!(peak(&state), 0.0);
+    }
+}
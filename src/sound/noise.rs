@@ -0,0 +1,309 @@
+//! Noise gate and spectral noise suppression for the microphone, run as stages of a
+//! `filter::Filter`
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::filter::FrameFilter;
+use super::freq::Stft;
+use crate::config;
+use crate::utils::db_to_gain;
+
+/// Samples taken by a ramp of `ms` milliseconds, at least one
+pub(super) fn ramp_samples(ms: f32, sample_rate: u32) -> f32 {
+    (ms / 1000.0 * sample_rate as f32).max(1.0)
+}
+
+pub struct NoiseGate {
+    settings: config::NoiseGate,
+    threshold: f32,
+    /// Gain added or taken away on every frame while opening or closing
+    attack_step: f32,
+    release_step: f32,
+    /// Frames the gate stays open for after the level went below the threshold
+    hold: usize,
+    /// How much of the envelope is left after a frame, so it follows the peaks of a waveform
+    decay: f32,
+    envelope: f32,
+    held: usize,
+    gain: f32,
+}
+
+impl NoiseGate {
+    /// How long the envelope takes to fall to a third after a peak
+    const ENVELOPE_MS: f32 = 10.0;
+
+    pub fn new(settings: config::NoiseGate) -> Self {
+        let mut gate = Self {
+            settings,
+            threshold: db_to_gain(settings.threshold_db),
+            attack_step: 1.0,
+            release_step: 1.0,
+            hold: 0,
+            decay: 0.0,
+            envelope: 0.0,
+            held: 0,
+            gain: 0.0,
+        };
+        gate.reset(48000, 1);
+        gate
+    }
+}
+
+impl FrameFilter for NoiseGate {
+    fn apply(&mut self, frame: &mut [f32]) {
+        // The channels open and close together
+        let level = frame
+            .iter()
+            .fold(0f32, |level, sample| level.max(sample.abs()));
+        self.envelope = level.max(self.envelope * self.decay);
+        if self.envelope >= self.threshold {
+            self.held = self.hold;
+        }
+        if self.envelope >= self.threshold || self.held > 0 {
+            self.held = self.held.saturating_sub(1);
+            self.gain = (self.gain + self.attack_step).min(1.0);
+        } else {
+            self.gain = (self.gain - self.release_step).max(0.0);
+        }
+        for sample in frame.iter_mut() {
+            *sample *= self.gain;
+        }
+    }
+
+    fn reset(&mut self, sample_rate: u32, _channels: usize) {
+        self.attack_step = 1.0 / ramp_samples(self.settings.attack_ms, sample_rate);
+        self.release_step = 1.0 / ramp_samples(self.settings.release_ms, sample_rate);
+        self.hold = (self.settings.hold_ms.max(0.0) / 1000.0 * sample_rate as f32) as usize;
+        self.decay = (-1.0 / ramp_samples(Self::ENVELOPE_MS, sample_rate)).exp();
+    }
+}
+
+/// Tells a `NoiseSuppressor` to learn the noise, shared with the sound loop
+#[derive(Debug, Default)]
+pub struct NoiseLearning(AtomicBool);
+
+impl NoiseLearning {
+    /// While `learning` the suppressor lets the microphone through as it is and measures it.
+    /// Once it stops the noise measured is taken out.
+    pub fn set(&self, learning: bool) {
+        self.0.store(learning, Ordering::Relaxed);
+    }
+
+    pub fn is_learning(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The noise of one channel, and what is taken out of it
+#[derive(Clone)]
+struct NoiseProfile {
+    /// Average amplitude of each frequency, only used once `learnt`
+    noise: Vec<f32>,
+    learnt: bool,
+    /// Amplitudes added up while learning
+    sum: Vec<f32>,
+    blocks: u32,
+    gains: Vec<f32>,
+}
+
+/// Spectral subtraction of a learnt noise profile
+pub struct NoiseSuppressor {
+    settings: config::NoiseSuppression,
+    learning: Arc<NoiseLearning>,
+    was_learning: bool,
+    /// Made once the stream is known
+    stft: Option<Stft>,
+    channels: Vec<NoiseProfile>,
+}
+
+impl NoiseSuppressor {
+    /// Takes out a bit more than the average noise, as the noise goes over its average half
+    /// the time
+    const OVER_SUBTRACTION: f32 = 2.0;
+    /// Share of the gain kept from the block before when it falls, so the leftover noise
+    /// doesn't twinkle
+    const SMOOTHING: f32 = 0.5;
+
+    pub fn new(settings: config::NoiseSuppression, learning: Arc<NoiseLearning>) -> Self {
+        Self {
+            settings,
+            learning,
+            was_learning: false,
+            stft: None,
+            channels: Vec::new(),
+        }
+    }
+}
+
+impl FrameFilter for NoiseSuppressor {
+    fn apply(&mut self, frame: &mut [f32]) {
+        let learning = self.learning.is_learning();
+        if learning != self.was_learning {
+            self.was_learning = learning;
+            for profile in self.channels.iter_mut() {
+                if learning {
+                    profile.sum.iter_mut().for_each(|sum| *sum = 0.0);
+                    profile.blocks = 0;
+                } else if profile.blocks > 0 {
+                    let blocks = profile.blocks as f32;
+                    for (noise, sum) in profile.noise.iter_mut().zip(profile.sum.iter()) {
+                        *noise = sum / blocks;
+                    }
+                    profile.learnt = true;
+                }
+            }
+        }
+
+        let floor = db_to_gain(-self.settings.reduction_db.abs());
+        let channels = &mut self.channels;
+        let stft = match self.stft.as_mut() {
+            Some(stft) => stft,
+            None => return,
+        };
+        stft.process(frame, |channel, frequencies| {
+            let profile = &mut channels[channel];
+            if learning {
+                for (sum, (_, amplitude)) in profile.sum.iter_mut().zip(frequencies.amplitudes()) {
+                    *sum += amplitude;
+                }
+                profile.blocks += 1;
+                return;
+            }
+            if !profile.learnt {
+                return;
+            }
+            for ((gain, noise), (_, amplitude)) in profile
+                .gains
+                .iter_mut()
+                .zip(profile.noise.iter())
+                .zip(frequencies.amplitudes())
+            {
+                let target = if amplitude > 0.0 {
+                    (1.0 - Self::OVER_SUBTRACTION * noise / amplitude).max(floor)
+                } else {
+                    floor
+                };
+                *gain = if target > *gain {
+                    target
+                } else {
+                    *gain * Self::SMOOTHING + target * (1.0 - Self::SMOOTHING)
+                };
+            }
+            frequencies.scale(&profile.gains);
+        });
+    }
+
+    fn reset(&mut self, sample_rate: u32, channels: usize) {
+        let stft = Stft::new(self.settings.fft_size, sample_rate, channels);
+        let bins = stft.fft_size() / 2 + 1;
+        self.stft = Some(stft);
+        // The noise learnt is kept as long as it still fits
+        let fits = self.channels.len() == channels
+            && self
+                .channels
+                .iter()
+                .all(|profile| profile.gains.len() == bins);
+        if !fits {
+            let profile = NoiseProfile {
+                noise: vec![0.0; bins],
+                learnt: false,
+                sum: vec![0.0; bins],
+                blocks: 0,
+                gains: vec![1.0; bins],
+            };
+            self.channels = vec![profile; channels];
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{NoiseGate, NoiseLearning, NoiseSuppressor};
+    use crate::config;
+    use crate::sound::filter::FrameFilter;
+    use std::f32::consts::PI;
+    use std::sync::Arc;
+
+    fn sine(amplitude: f32, frequency: f32, sample_rate: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (2.0 * PI * frequency * i as f32 / sample_rate).sin() * amplitude)
+            .collect()
+    }
+
+    /// White noise from a xorshift generator, the same on every call
+    fn noise(amplitude: f32, len: usize) -> Vec<f32> {
+        let mut state = 0x2545_f491u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state as f32 / u32::MAX as f32 * 2.0 - 1.0) * amplitude
+            })
+            .collect()
+    }
+
+    fn run(filter: &mut dyn FrameFilter, input: &[f32]) -> Vec<f32> {
+        input
+            .iter()
+            .map(|sample| {
+                let mut frame = [*sample];
+                filter.apply(&mut frame);
+                frame[0]
+            })
+            .collect()
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|x| x * x).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn gate_opens_above_threshold() {
+        let mut gate = NoiseGate::new(config::NoiseGate {
+            threshold_db: -30.0,
+            attack_ms: 1.0,
+            hold_ms: 20.0,
+            release_ms: 10.0,
+        });
+        gate.reset(48000, 1);
+        let quiet = sine(0.01, 440.0, 48000.0, 4800);
+        assert!(run(&mut gate, &quiet).iter().all(|sample| *sample == 0.0));
+
+        let loud = sine(0.5, 440.0, 48000.0, 4800);
+        let opened = run(&mut gate, &loud);
+        assert_eq!(&opened[480..], &loud[480..]);
+
+        // Held, then released
+        let closed = run(&mut gate, &quiet);
+        assert_eq!(&closed[..480], &quiet[..480]);
+        assert!(closed[3600..].iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn suppressor_takes_out_learnt_noise() {
+        let learning = Arc::new(NoiseLearning::default());
+        let mut suppressor = NoiseSuppressor::new(
+            config::NoiseSuppression {
+                reduction_db: 20.0,
+                fft_size: 128,
+            },
+            learning.clone(),
+        );
+        suppressor.reset(8000, 1);
+        let hiss = noise(0.05, 4000);
+
+        learning.set(true);
+        let learnt = run(&mut suppressor, &hiss);
+        assert!((rms(&learnt[1000..]) - rms(&hiss[1000..])).abs() < 0.005);
+        learning.set(false);
+
+        let suppressed = run(&mut suppressor, &hiss);
+        assert!(rms(&suppressed[1000..]) < rms(&hiss) / 4.0);
+
+        let voice = sine(0.3, 1000.0, 8000.0, 4000);
+        let kept = run(&mut suppressor, &voice);
+        assert!((rms(&kept[1000..]) - rms(&voice)).abs() < 0.02);
+    }
+}
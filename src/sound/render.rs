@@ -0,0 +1,220 @@
+//! Mixes a timeline of sounds into a WAV file without opening an audio device
+
+use anyhow::Result;
+use miniaudio::{Format, FramesMut};
+use std::collections::HashMap;
+use std::io::{Seek, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use super::decoder::Decoder;
+use super::sink::{self, Mix, PlaySettings, PlayingSource};
+use crate::config;
+
+/// Frames mixed at a time, about what a device asks for in one callback
+const BLOCK_FRAMES: usize = 512;
+
+/// A sound placed on the timeline given to `render`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimelineEvent {
+    /// When the sound starts, from the beginning of the render
+    pub time: Duration,
+    pub sound: config::Sound,
+    /// Gain in decibels, on top of the sound's own settings
+    pub gain_db: f32,
+}
+
+/// Sample rate and channel count of a rendered file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl Default for RenderFormat {
+    fn default() -> Self {
+        Self {
+            sample_rate: 48000,
+            channels: 2,
+        }
+    }
+}
+
+/// Renders `events` into a 16 bit WAV file at `path`, see `render`
+pub fn render_to_wav(events: &[TimelineEvent], format: RenderFormat, path: &Path) -> Result<()> {
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    render(events, format, file)
+}
+
+/// Renders `events` as a 16 bit WAV into `writer`.
+///
+/// The sounds go through the same decoding, resampling and mixing as they do when played on a
+/// `Sink`. The render stops once the last sound has ended.
+pub fn render<W>(events: &[TimelineEvent], format: RenderFormat, writer: W) -> Result<()>
+where
+    W: Write + Seek,
+{
+    let spec = hound::WavSpec {
+        channels: format.channels,
+        sample_rate: format.sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut wav = hound::WavWriter::new(writer, spec)?;
+
+    let mut pending: Vec<(u64, &TimelineEvent)> = events
+        .iter()
+        .map(|event| {
+            let frame = (event.time.as_secs_f64() * format.sample_rate as f64).round() as u64;
+            (frame, event)
+        })
+        .collect();
+    pending.sort_by_key(|(frame, _)| *frame);
+    let mut pending = pending.into_iter().peekable();
+
+    let channels = format.channels as usize;
+    let mut mix = vec![0f32; BLOCK_FRAMES * channels];
+    let mut buffer = vec![0i16; BLOCK_FRAMES * channels];
+    let mut sources = HashMap::new();
+    let mut next_key = 0usize;
+    let mut frame = 0u64;
+    loop {
+        while let Some((_, event)) = pending.peek().filter(|(start, _)| *start <= frame) {
+            sources.insert(next_key, vec![start(event)?]);
+            next_key += 1;
+            pending.next();
+        }
+        // Blocks end where the next sound starts, so every sound starts on its exact frame
+        let block = match pending.peek() {
+            Some((start, _)) => BLOCK_FRAMES.min((start - frame) as usize),
+            None if sources.is_empty() => break,
+            None => BLOCK_FRAMES,
+        };
+
+        let mix = &mut mix[..block * channels];
+        mix.iter_mut().for_each(|sample| *sample = 0.0);
+        sink::mix_sources(
+            &mut sources,
+            format.sample_rate,
+            channels,
+            mix,
+            None,
+            |_, _| {},
+        );
+        let samples = &mut buffer[..block * channels];
+        sink::write_mix(
+            mix,
+            &mut FramesMut::wrap(samples, Format::S16, channels as u32),
+            None,
+        );
+        for sample in samples.iter() {
+            wav.write_sample(*sample)?;
+        }
+        frame += block as u64;
+    }
+    wav.finalize()?;
+    Ok(())
+}
+
+fn start(
+    event: &TimelineEvent,
+) -> Result<PlayingSource<Decoder<std::io::BufReader<std::fs::File>>>> {
+    let reader =
+        std::io::BufReader::with_capacity(1000 * 50, std::fs::File::open(&event.sound.wav)?);
+    let decoder = Decoder::new(reader)?;
+    let settings = PlaySettings {
+        mix: Mix {
+            gain: event.sound.settings.gain() * crate::utils::db_to_gain(event.gain_db),
+            pan: event.sound.settings.pan,
+            ..Mix::default()
+        },
+        ..PlaySettings::default()
+    };
+    PlayingSource::with_settings(decoder, 0, settings)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{render, RenderFormat, TimelineEvent};
+    use crate::config;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    /// Writes a stereo 8 kHz sawtooth, matching the render format so no resampling happens
+    fn write_saw(path: &Path, period: i32, frames: i32) {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for frame in 0..frames {
+            let value = ((frame % period) * 2000 / period - 1000) as i16;
+            writer.write_sample(value).unwrap();
+            writer.write_sample(-value).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    fn sound(wav: PathBuf) -> config::Sound {
+        config::Sound {
+            wav,
+            ..config::Sound::default()
+        }
+    }
+
+    /// Compares against `tests/golden/render_timeline.wav`, set `MLWS_BLESS` to write it again
+    #[test]
+    fn render_matches_golden() {
+        let dir = std::env::temp_dir().join("mlws_render_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        write_saw(&dir.join("low.wav"), 80, 4000);
+        write_saw(&dir.join("high.wav"), 20, 1000);
+
+        let events = vec![
+            TimelineEvent {
+                time: Duration::from_millis(0),
+                sound: sound(dir.join("low.wav")),
+                gain_db: 0.0,
+            },
+            TimelineEvent {
+                time: Duration::from_millis(250),
+                sound: sound(dir.join("high.wav")),
+                gain_db: -6.0,
+            },
+            TimelineEvent {
+                time: Duration::from_millis(400),
+                sound: sound(dir.join("high.wav")),
+                gain_db: 0.0,
+            },
+        ];
+        let format = RenderFormat {
+            sample_rate: 8000,
+            channels: 2,
+        };
+        let mut rendered = std::io::Cursor::new(Vec::new());
+        render(&events, format, &mut rendered).unwrap();
+
+        let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/render_timeline.wav");
+        if std::env::var_os("MLWS_BLESS").is_some() {
+            std::fs::create_dir_all(golden.parent().unwrap()).unwrap();
+            std::fs::write(&golden, rendered.get_ref()).unwrap();
+        }
+        let read = |data: Vec<u8>| {
+            let reader = hound::WavReader::new(std::io::Cursor::new(data)).unwrap();
+            let spec = reader.spec();
+            let samples: Vec<i16> = reader.into_samples().map(Result::unwrap).collect();
+            (spec, samples)
+        };
+        let (spec, samples) = read(rendered.into_inner());
+        let (golden_spec, golden_samples) = read(std::fs::read(&golden).unwrap());
+        assert_eq!(spec, golden_spec);
+        assert_eq!(samples.len(), golden_samples.len());
+        assert!(
+            samples == golden_samples,
+            "render differs from {:?}",
+            golden
+        );
+    }
+}
@@ -77,10 +77,22 @@ unsafe impl Sample for f32 {
     }
 }
 
+/// Turns a signed integer sample `bits` wide into a floating-point one, with the same scale
+/// `i16::to_f32` uses so 16 bit samples come out the same either way.
+#[inline]
+pub fn int_to_f32(value: i32, bits: u32) -> f32 {
+    let full_scale = (1i64 << (bits.clamp(2, 32) - 1)) as f32;
+    if value < 0 {
+        value as f32 / full_scale
+    } else {
+        value as f32 / (full_scale - 1.0)
+    }
+}
+
 #[cfg(test)]
 #[allow(clippy::float_cmp)]
 mod test {
-    use super::Sample;
+    use super::{int_to_f32, Sample};
 
     #[test]
     fn i16_to_i16() {
@@ -112,4 +124,14 @@ mod test {
         assert_eq!((-0.7f32).to_f32(), -0.7);
         assert_eq!(1.0f32.to_f32(), 1.0);
     }
+
+    #[test]
+    fn int_to_f32_keeps_depth() {
+        assert_eq!(int_to_f32(-16384, 16), (-16384i16).to_f32());
+        assert_eq!(int_to_f32(32767, 16), 32767i16.to_f32());
+        assert_eq!(int_to_f32(-8_388_608, 24), -1.0);
+        assert_eq!(int_to_f32(8_388_607, 24), 1.0);
+        // Below the smallest step of 16 bit
+        assert!(int_to_f32(1, 24) > 0.0);
+    }
 }
@@ -1,14 +1,20 @@
 // Initial version from Rodio APACHE LICENSE 2.0
 
 use anyhow::{anyhow, Result};
-use miniaudio::{DeviceType, Frames, FramesMut};
-use std::sync::atomic::AtomicBool;
+use miniaudio::{Format, Frames, FramesMut};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
+use super::backend::{Backend, DataCallback, Device, DeviceError};
+use super::duck::{Ramp, Sidechain};
+use super::filter::{Filter, FilterChain};
+use super::meter::{ChannelLevel, LevelMeter};
 use super::sample::Sample;
-use super::source::Source;
+use super::source::{SeekError, Source};
+use log::debug;
 use std::collections::HashMap;
 use std::collections::VecDeque;
+use std::time::Duration;
 
 /// Handle to an device that outputs sounds.
 ///
@@ -19,186 +25,213 @@ struct ConverterWrapper(pub miniaudio::DataConverter);
 unsafe impl Sync for ConverterWrapper {}
 unsafe impl Send for ConverterWrapper {}
 
-type SourcesType<T, S> = std::sync::Arc<
-    parking_lot::Mutex<
-        HashMap<T, Vec<(S, VecDeque<i16>, Option<ConverterWrapper>, f32, f32, f32)>>,
-    >,
->;
+/// Gain and stereo position applied to a source while it is mixed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mix {
+    /// Linear amplitude multiplier
+    pub gain: f32,
+    /// Stereo position, from `-1.0` (left) to `1.0` (right)
+    pub pan: f32,
+    /// Linear multiplier bringing the source to the loudness of `config::Normalization`, kept
+    /// apart from `gain` so changing the gain keeps it
+    pub normalization: f32,
+}
 
-pub struct Sink<T, S>
+impl Default for Mix {
+    fn default() -> Self {
+        Self {
+            gain: 1.0,
+            pan: 0.0,
+            normalization: 1.0,
+        }
+    }
+}
+
+impl Mix {
+    /// Multiplier for the left and right channels, outputs that aren't stereo get the gain on
+    /// every channel.
+    ///
+    /// The side panned away from fades out along a quarter cosine while the other one stays as
+    /// it is, so centred sounds are untouched and panning never raises a channel.
+    fn channel_gains(&self, channels: usize) -> [f32; 2] {
+        let gain = self.gain * self.normalization;
+        if channels != 2 {
+            return [gain; 2];
+        }
+        let pan = self.pan.clamp(-1.0, 1.0);
+        let fade = |amount: f32| (amount.max(0.0) * std::f32::consts::FRAC_PI_2).cos();
+        [gain * fade(pan), gain * fade(-pan)]
+    }
+}
+
+/// Shortest fade used when stopping a sound, so cutting it off doesn't click
+const DECLICK_SECONDS: f32 = 0.005;
+
+/// Volume ramps, in seconds, applied when a source starts and before it ends
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Fades {
+    pub fade_in: f32,
+    pub fade_out: f32,
+}
+
+/// Section of a source that is repeated instead of letting the source end
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Looping {
+    /// Seconds into the source where each repeat starts
+    pub start: f32,
+    /// Seconds into the source where playback jumps back to `start`, the end of the source when
+    /// `None`
+    pub end: Option<f32>,
+    /// Number of jumps back left, repeats until stopped when `None`
+    pub repeats: Option<u32>,
+}
+
+/// How `Sink::play` should play a source
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PlaySettings {
+    pub mix: Mix,
+    pub fades: Fades,
+    pub looping: Option<Looping>,
+    /// Seconds into the source to start playing from
+    pub start: Option<f32>,
+    /// Seconds into the source to stop playing at
+    pub end: Option<f32>,
+    /// Lowered while the sidechain of the sink ducks the music bed
+    pub music_bed: bool,
+}
+
+/// Tracks where a source is within its fades
+struct Envelope {
+    fades: Fades,
+    /// Seconds mixed since the source started playing
+    played: f32,
+    /// Seconds left and total length of a fade started by `Sink::fade_out`
+    stopping: Option<(f32, f32)>,
+}
+
+impl Envelope {
+    fn new(fades: Fades) -> Self {
+        Self {
+            fades,
+            played: 0.0,
+            stopping: None,
+        }
+    }
+
+    /// Starts fading out over `length` seconds, unless a shorter fade out is already running
+    fn stop(&mut self, length: f32) {
+        let length = length.max(DECLICK_SECONDS);
+        match self.stopping {
+            Some((left, _)) if left <= length => {}
+            _ => self.stopping = Some((length, length)),
+        }
+    }
+
+    /// Has the fade started by `stop` reached silence
+    fn stopped(&self) -> bool {
+        matches!(self.stopping, Some((left, _)) if left <= 0.0)
+    }
+
+    /// Gain for the next frame and advances the envelope by `frame_time` seconds.
+    ///
+    /// `remaining` is the time left until the source reaches its end.
+    fn next(&mut self, frame_time: f32, remaining: f32) -> f32 {
+        let mut gain = 1.0;
+        if self.played < self.fades.fade_in {
+            gain *= self.played / self.fades.fade_in;
+        }
+        if remaining < self.fades.fade_out {
+            gain *= (remaining / self.fades.fade_out).max(0.0);
+        }
+        if let Some((left, length)) = self.stopping.as_mut() {
+            gain *= (*left / *length).max(0.0);
+            *left -= frame_time;
+        }
+        self.played += frame_time;
+        gain
+    }
+}
+
+/// Tells apart copies of the same sound playing at once
+pub type InstanceId = u64;
+
+type SourcesType<T, S> = std::sync::Arc<parking_lot::Mutex<HashMap<T, Vec<PlayingSource<S>>>>>;
+
+/// A source queued on a `Sink` together with its playback state
+pub(super) struct PlayingSource<S>
 where
-    S: Source + Send + Sync + 'static,
+    S: Source,
     S::Item: Sample,
-    S::Item: Send,
-    T: std::cmp::Eq,
-    T: std::hash::Hash,
 {
-    device: miniaudio::Device,
-    stopped: Arc<AtomicBool>,
-    sources: SourcesType<T, S>,
+    source: S,
+    instance: InstanceId,
+    buffer: VecDeque<f32>,
+    resampler: Option<ConverterWrapper>,
+    mix: Mix,
+    envelope: Envelope,
+    looping: Option<Looping>,
+    end: f32,
+    current_duration: f32,
+    /// Samples taken from the source since its start, used to find the loop end
+    source_samples: u64,
+    /// The loop section, kept as it is first played so the repeats don't seek the source
+    loop_samples: Vec<f32>,
+    /// Where the repeat playing is in `loop_samples`, `None` while reading the source
+    replay: Option<usize>,
+    music_bed: bool,
+    duck: Ramp,
+    paused: bool,
+    finished: bool,
 }
 
-impl<T, S> Sink<T, S>
+impl<S> PlayingSource<S>
 where
-    S: Source + Send + Sync + 'static,
+    S: Source,
     S::Item: Sample,
-    S::Item: Send,
-    T: std::cmp::Eq,
-    T: std::hash::Hash,
-    T: Clone + Send + 'static,
-    T: std::fmt::Debug,
 {
-    /// Builds a new `Sink`
-    #[inline]
-    pub fn new(
-        context: &miniaudio::Context,
-        device_id: Option<miniaudio::DeviceId>,
-    ) -> Result<Self> {
-        let mut device_config = miniaudio::DeviceConfig::new(DeviceType::Playback);
-        device_config.playback_mut().set_device_id(device_id);
-        device_config
-            .playback_mut()
-            .set_format(miniaudio::Format::S16);
-
-        let hash_map = SourcesType::<T, S>::default();
-        let hash_map_clone = hash_map.clone();
-        let stopped = Arc::new(AtomicBool::new(false));
-
-        device_config.set_data_callback(move |device, output, _input| {
-            let mut remove_keys = Vec::new();
-            let mut unlocked = hash_map_clone.lock();
-
-            for (key, sources) in unlocked.iter_mut() {
-                for (source, buffer, resampler, start, end, current_duration) in sources {
-                    if *start > 0.0 {
-                        source.nth(
-                            ((*start * source.sample_rate() as f32) * source.channels() as f32)
-                                as usize,
-                        );
-                        *current_duration = *start;
-                        *start = 0.0;
-                    }
-                    if current_duration >= end {
-                        remove_keys.push(key.clone());
-                        continue;
-                    }
-
-                    *current_duration += ((output.sample_count() / output.channels() as usize)
-                        as f32)
-                        / device.sample_rate() as f32;
-
-                    if source.sample_rate() != device.sample_rate()
-                        || source.channels() != output.channels() as u16
-                    {
-                        if resampler.is_none() {
-                            let config = miniaudio::DataConverterConfig::new(
-                                miniaudio::Format::S16,
-                                miniaudio::Format::S16,
-                                source.channels() as u32,
-                                output.channels(),
-                                source.sample_rate(),
-                                device.sample_rate(),
-                            );
-                            *resampler = Some(ConverterWrapper(
-                                miniaudio::DataConverter::new(&config).unwrap(),
-                            ));
-                        }
-                        let mut old_samples: Vec<i16> = Vec::with_capacity(output.sample_count());
-                        let mut filled_count = 0;
-                        for _ in 0..output.sample_count() {
-                            if let Some(item) = buffer.pop_front() {
-                                old_samples.push(item);
-                                continue;
-                            }
-                            let next = source.next();
-                            if let Some(next) = next {
-                                old_samples.push(next.to_i16());
-                            } else {
-                                filled_count = output.sample_count() - old_samples.len();
-                                old_samples.resize(output.sample_count() as usize, 0);
-                                break;
-                            }
-                        }
-                        let mut new_samples_mut: Vec<i16> = vec![0; output.sample_count()];
-                        let (_output_frame_count, input_frame_count) = resampler
-                            .as_mut()
-                            .unwrap()
-                            .0
-                            .process_pcm_frames(
-                                &mut FramesMut::wrap(
-                                    &mut new_samples_mut,
-                                    output.format(),
-                                    output.channels() as u32,
-                                ),
-                                &Frames::wrap(
-                                    &old_samples,
-                                    miniaudio::Format::S16,
-                                    source.channels() as u32,
-                                ),
-                            )
-                            .expect("resampling failed");
-                        let mut iterator = new_samples_mut.iter();
-                        for item in output.as_samples_mut::<i16>() {
-                            if let Some(value) = iterator.next() {
-                                *item = item.saturating_add(*value);
-                            } else {
-                                break;
-                            }
-                        }
-                        for item in old_samples
-                            .iter()
-                            .skip((input_frame_count * source.channels() as u64) as usize)
-                            .skip(filled_count)
-                        {
-                            buffer.push_back(*item);
-                        }
-                        if filled_count > 0 && buffer.is_empty() {
-                            remove_keys.push(key.clone());
-                        }
-                    } else {
-                        for item in output.as_samples_mut::<i16>() {
-                            if let Some(value) = source.next() {
-                                *item = item.saturating_add(value.to_i16());
-                            } else {
-                                remove_keys.push(key.clone());
-                                break;
-                            }
-                        }
-                    }
-                }
-            }
-            for key in &remove_keys {
-                let entry: &mut Vec<_> = unlocked.get_mut(key).unwrap();
-                entry.remove(0);
-                if entry.is_empty() {
-                    unlocked.remove(key);
-                }
-            }
-        });
-        let stopped_clone = Arc::clone(&stopped);
-        device_config.set_stop_callback(move |_device| {
-            stopped_clone.store(true, std::sync::atomic::Ordering::Relaxed);
-        });
-        let device = miniaudio::Device::new(Some(context.clone()), &device_config)
-            .expect("failed to create miniaudio device");
-        Ok(Sink {
-            device,
-            stopped,
-            sources: hash_map,
-        })
+    fn new(
+        source: S,
+        instance: InstanceId,
+        mix: Mix,
+        fades: Fades,
+        looping: Option<Looping>,
+        end: f32,
+    ) -> Self {
+        Self {
+            source,
+            instance,
+            buffer: VecDeque::new(),
+            resampler: None,
+            mix,
+            envelope: Envelope::new(fades),
+            looping,
+            end,
+            current_duration: 0.0,
+            source_samples: 0,
+            loop_samples: Vec::new(),
+            replay: None,
+            music_bed: false,
+            duck: Ramp::default(),
+            paused: false,
+            finished: false,
+        }
     }
 
-    pub fn play(&mut self, key: T, source: S, start: Option<f32>, end: Option<f32>) -> Result<()> {
-        let mut unlocked = self.sources.lock();
+    /// Checks `settings` and moves the source to its start timestamp
+    pub(super) fn with_settings(
+        source: S,
+        instance: InstanceId,
+        settings: PlaySettings,
+    ) -> Result<Self> {
         let start_float = {
-            let start = start.unwrap_or_default();
+            let start = settings.start.unwrap_or_default();
             if start < 0.0 {
                 return Err(anyhow!("supplied start timestamp is negative {}", start));
             }
             start
         };
         let end_float = {
-            if let Some(end_duration) = end {
+            if let Some(end_duration) = settings.end {
                 if end_duration < 0.0 {
                     return Err(anyhow!(
                         "supplied end timestamp is negative {}",
@@ -210,33 +243,794 @@ where
                 f32::INFINITY
             }
         };
-        match unlocked.entry(key) {
-            std::collections::hash_map::Entry::Occupied(mut entry) => {
-                let entry = entry.get_mut();
-                entry.push((source, VecDeque::new(), None, start_float, end_float, 0.0));
-            }
-            std::collections::hash_map::Entry::Vacant(entry) => {
-                entry.insert(vec![(
-                    source,
-                    VecDeque::new(),
-                    None,
-                    start_float,
-                    end_float,
-                    0.0,
-                )]);
+        if let Some(looping) = settings.looping {
+            if looping.start < 0.0 {
+                return Err(anyhow!("supplied loop start is negative {}", looping.start));
+            }
+            if let Some(loop_end) = looping.end {
+                if loop_end <= looping.start {
+                    return Err(anyhow!(
+                        "supplied loop end {} is not after the loop start {}",
+                        loop_end,
+                        looping.start
+                    ));
+                }
+            }
+        }
+        let mut playing = Self::new(
+            source,
+            instance,
+            settings.mix,
+            settings.fades,
+            settings.looping,
+            end_float,
+        );
+        playing.music_bed = settings.music_bed;
+        if let Some(looping) = settings.looping {
+            let length = looping
+                .end
+                .map(|end| end - looping.start)
+                .or_else(|| {
+                    let total = playing.source.total_duration()?;
+                    Some(total.as_secs_f32() - looping.start)
+                })
+                .unwrap_or_default();
+            // Sized once here, so the audio thread doesn't allocate while playing the loop
+            playing
+                .loop_samples
+                .reserve(playing.sample_offset(length.max(0.0)) as usize);
+        }
+        let read_ahead = match settings.looping {
+            Some(looping) if start_float > looping.start => {
+                playing.read_loop_ahead(looping, start_float)
+            }
+            _ => false,
+        };
+        if start_float > 0.0 && !read_ahead {
+            if let Err(err) = playing.seek(start_float) {
+                debug!("falling back to skipping samples, {}", err);
+                let source = &mut playing.source;
+                source.nth(
+                    ((start_float * source.sample_rate() as f32) * source.channels() as f32)
+                        as usize,
+                );
+                playing.current_duration = start_float;
+                playing.source_samples = playing.sample_offset(start_float);
             }
         }
+        Ok(playing)
+    }
+
+    /// Keeps the loop section when playback starts past its start, where the first pass
+    /// can't. Returns whether playback was moved to `start` inside the section.
+    fn read_loop_ahead(&mut self, looping: Looping, start: f32) -> bool {
+        if let Err(err) = self.seek(looping.start) {
+            debug!("can't read loop ahead, {}", err);
+            return false;
+        }
+        let end = looping.end.map(|end| self.sample_offset(end));
+        while end != Some(self.source_samples) {
+            if self.pull_sample().is_none() {
+                break;
+            }
+        }
+        // The source stays at the end of the section, where playback carries on after the
+        // repeats
+        let offset = self.sample_offset(start);
+        if offset >= self.source_samples {
+            return false;
+        }
+        self.replay = Some((offset - self.sample_offset(looping.start)) as usize);
+        self.source_samples = offset;
+        self.current_duration = start;
+        true
+    }
+
+    /// Moves the source to `pos`, dropping anything buffered for the old position
+    fn seek(&mut self, pos: f32) -> std::result::Result<(), SeekError> {
+        self.source.seek(Duration::from_secs_f32(pos))?;
+        self.replay = None;
+        self.buffer.clear();
+        self.resampler = None;
+        self.current_duration = pos;
+        self.source_samples = self.sample_offset(pos);
         Ok(())
     }
 
+    /// Index of the first sample of the frame at `pos` seconds
+    fn sample_offset(&self, pos: f32) -> u64 {
+        (pos * self.source.sample_rate() as f32) as u64 * self.source.channels() as u64
+    }
+
+    /// Will the source jump back to its loop start once more
+    fn repeats_left(&self) -> bool {
+        matches!(self.looping, Some(Looping { repeats, .. }) if repeats != Some(0))
+    }
+
+    /// Goes back to the loop start if any repeats are left, returns whether it did.
+    ///
+    /// Repeats play the samples kept from the first pass. The source is only seeked when some
+    /// of the section was skipped by seeking into it.
+    fn restart_loop(&mut self) -> bool {
+        if !self.repeats_left() {
+            return false;
+        }
+        let looping = self.looping.as_mut().unwrap();
+        if let Some(repeats) = looping.repeats.as_mut() {
+            *repeats -= 1;
+        }
+        let start = looping.start;
+        let wrapped_at = self.source_samples as f32
+            / (self.source.sample_rate() as f32 * self.source.channels() as f32);
+        let kept = self.replay.is_some()
+            || self.source_samples.checked_sub(self.sample_offset(start))
+                == Some(self.loop_samples.len() as u64);
+        if kept && !self.loop_samples.is_empty() {
+            self.replay = Some(0);
+        } else if let Err(err) = self.source.seek(Duration::from_secs_f32(start)) {
+            debug!("can't loop source, {}", err);
+            self.looping = None;
+            return false;
+        }
+        self.source_samples = self.sample_offset(start);
+        self.current_duration -= wrapped_at - start;
+        true
+    }
+
+    /// Next sample of the source, following the loop section while repeats are left
+    fn next_sample(&mut self) -> Option<f32> {
+        if let Some(index) = self.replay {
+            if let Some(value) = self.loop_samples.get(index) {
+                self.replay = Some(index + 1);
+                self.source_samples += 1;
+                return Some(*value);
+            }
+            // The source is still where the section ends
+            self.replay = None;
+            if self.restart_loop() {
+                return self.next_sample();
+            }
+        }
+        if let Some(Looping { end: Some(end), .. }) = self.looping {
+            if self.source_samples >= self.sample_offset(end) && self.restart_loop() {
+                return self.next_sample();
+            }
+        }
+        self.pull_sample().or_else(|| {
+            if self.restart_loop() {
+                self.next_sample()
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Next sample straight from the source, kept while it is part of the loop section
+    fn pull_sample(&mut self) -> Option<f32> {
+        let value = self.source.next()?.to_f32();
+        if let Some(looping) = self.looping.filter(|_| self.repeats_left()) {
+            let start = self.sample_offset(looping.start);
+            if self.source_samples.checked_sub(start) == Some(self.loop_samples.len() as u64) {
+                self.loop_samples.push(value);
+            }
+        }
+        self.source_samples += 1;
+        Some(value)
+    }
+
+    /// Mixes the next chunk of the source into `output`.
+    ///
+    /// Sets `finished` once the source has run out, reached its end timestamp or faded out
+    /// after being stopped. A looping source only ends after its last repeat. Sources of the
+    /// music bed follow `sidechain`.
+    fn mix_into(
+        &mut self,
+        sample_rate: u32,
+        channels: usize,
+        output: &mut [f32],
+        sidechain: Option<&Sidechain>,
+    ) {
+        if (self.current_duration >= self.end && !self.repeats_left()) || self.envelope.stopped() {
+            self.finished = true;
+            return;
+        }
+
+        let chunk_length = ((output.len() / channels) as f32) / sample_rate as f32;
+        self.current_duration += chunk_length;
+
+        let samples = if self.source.sample_rate() != sample_rate
+            || self.source.channels() != channels as u16
+        {
+            self.read_resampled(sample_rate, channels, output.len())
+        } else {
+            self.read(output.len())
+        };
+
+        // Reading may have jumped back to the loop start, which moves the position too
+        let chunk_start = self.current_duration - chunk_length;
+        let repeating = self.repeats_left();
+        let gains = self.mix.channel_gains(channels);
+        let frame_time = 1.0 / sample_rate as f32;
+        let ducking = sidechain
+            .filter(|_| self.music_bed)
+            .map(|sidechain| sidechain.music_bed(sample_rate));
+        for (index, (frame, values)) in output
+            .chunks_mut(channels)
+            .zip(samples.chunks(channels))
+            .enumerate()
+        {
+            let remaining = if repeating {
+                f32::INFINITY
+            } else {
+                self.end - (chunk_start + index as f32 * frame_time)
+            };
+            let mut envelope = self.envelope.next(frame_time, remaining);
+            if let Some(target) = ducking.as_ref() {
+                envelope *= self.duck.next(target);
+            }
+            for (channel, (item, value)) in frame.iter_mut().zip(values).enumerate() {
+                *item += value * gains[channel.min(1)] * envelope;
+            }
+        }
+    }
+
+    /// Reads up to `sample_count` samples straight from the source
+    fn read(&mut self, sample_count: usize) -> Vec<f32> {
+        let mut samples = Vec::with_capacity(sample_count);
+        for _ in 0..sample_count {
+            if let Some(value) = self.next_sample() {
+                samples.push(value);
+            } else {
+                self.finished = true;
+                break;
+            }
+        }
+        samples
+    }
+
+    /// Reads `sample_count` samples converted to the output's sample rate and channel count
+    fn read_resampled(
+        &mut self,
+        sample_rate: u32,
+        channels: usize,
+        sample_count: usize,
+    ) -> Vec<f32> {
+        let source_channels = self.source.channels();
+        if self.resampler.is_none() {
+            let config = miniaudio::DataConverterConfig::new(
+                miniaudio::Format::F32,
+                miniaudio::Format::F32,
+                source_channels as u32,
+                channels as u32,
+                self.source.sample_rate(),
+                sample_rate,
+            );
+            self.resampler = Some(ConverterWrapper(
+                miniaudio::DataConverter::new(&config).unwrap(),
+            ));
+        }
+        let mut old_samples: Vec<f32> = Vec::with_capacity(sample_count);
+        let mut filled_count = 0;
+        for _ in 0..sample_count {
+            if let Some(item) = self.buffer.pop_front() {
+                old_samples.push(item);
+                continue;
+            }
+            if let Some(next) = self.next_sample() {
+                old_samples.push(next);
+            } else {
+                filled_count = sample_count - old_samples.len();
+                old_samples.resize(sample_count, 0.0);
+                break;
+            }
+        }
+        let mut new_samples_mut: Vec<f32> = vec![0.0; sample_count];
+        let (_output_frame_count, input_frame_count) = self
+            .resampler
+            .as_mut()
+            .unwrap()
+            .0
+            .process_pcm_frames(
+                &mut FramesMut::wrap(
+                    &mut new_samples_mut,
+                    miniaudio::Format::F32,
+                    channels as u32,
+                ),
+                &Frames::wrap(&old_samples, miniaudio::Format::F32, source_channels as u32),
+            )
+            .expect("resampling failed");
+        for item in old_samples
+            .iter()
+            .skip((input_frame_count * source_channels as u64) as usize)
+            .skip(filled_count)
+        {
+            self.buffer.push_back(*item);
+        }
+        if filled_count > 0 && self.buffer.is_empty() {
+            self.finished = true;
+        }
+        new_samples_mut
+    }
+}
+
+/// Mixes every source that isn't paused into the interleaved samples of `output`, which can go
+/// past full scale until the mix is written out.
+///
+/// Sources that finished are dropped and passed to `on_finished`, keys left without sources are
+/// removed. The music bed follows `sidechain` when given.
+pub(super) fn mix_sources<T, S>(
+    sources: &mut HashMap<T, Vec<PlayingSource<S>>>,
+    sample_rate: u32,
+    channels: usize,
+    output: &mut [f32],
+    sidechain: Option<&Sidechain>,
+    mut on_finished: impl FnMut(&T, InstanceId),
+) where
+    S: Source,
+    S::Item: Sample,
+    T: std::cmp::Eq + std::hash::Hash,
+{
+    for (key, sources) in sources.iter_mut() {
+        for playing in sources.iter_mut().filter(|playing| !playing.paused) {
+            playing.mix_into(sample_rate, channels, output, sidechain);
+        }
+        for playing in sources.iter().filter(|playing| playing.finished) {
+            on_finished(key, playing.instance);
+        }
+        sources.retain(|playing| !playing.finished);
+    }
+    sources.retain(|_, sources| !sources.is_empty());
+}
+
+/// Triangular noise of up to one step of the device format either way, added to a mix before it
+/// is rounded so the rounding error turns into a steady hiss instead of following the sound
+pub(super) struct Dither {
+    state: u32,
+}
+
+impl Dither {
+    pub(super) fn new() -> Self {
+        Self { state: 0x2545_f491 }
+    }
+
+    /// From `0.0` to `1.0`, from a xorshift generator
+    fn uniform(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f32 / u32::MAX as f32
+    }
+
+    /// Noise in steps of the device format
+    fn next(&mut self) -> f32 {
+        self.uniform() - self.uniform()
+    }
+}
+
+/// Writes a mix to a device in its format, clipping it to full scale. Integer formats are
+/// rounded, after adding `dither` when given.
+pub(super) fn write_mix(mix: &[f32], output: &mut FramesMut, mut dither: Option<&mut Dither>) {
+    // Scaled below and above zero the way `Sample::to_f32` does, so 16 bit samples come back
+    // unchanged
+    let mut quantize = |sample: &f32, negative: f32, positive: f32| {
+        let sample = sample.clamp(-1.0, 1.0);
+        let scale = if sample < 0.0 { negative } else { positive };
+        let noise = dither.as_mut().map_or(0.0, |dither| dither.next());
+        (sample * scale + noise).round().clamp(-negative, positive)
+    };
+    match output.format() {
+        Format::F32 => {
+            for (out, sample) in output.as_samples_mut::<f32>().iter_mut().zip(mix) {
+                *out = sample.clamp(-1.0, 1.0);
+            }
+        }
+        Format::S16 => {
+            for (out, sample) in output.as_samples_mut::<i16>().iter_mut().zip(mix) {
+                *out = quantize(sample, 32_768.0, 32_767.0) as i16;
+            }
+        }
+        Format::S32 => {
+            for (out, sample) in output.as_samples_mut::<i32>().iter_mut().zip(mix) {
+                *out = quantize(sample, 2_147_483_648.0, 2_147_483_647.0) as i32;
+            }
+        }
+        Format::S24 => {
+            for (out, sample) in output.as_bytes_mut().chunks_exact_mut(3).zip(mix) {
+                let value = quantize(sample, 8_388_608.0, 8_388_607.0) as i32;
+                out.copy_from_slice(&value.to_le_bytes()[..3]);
+            }
+        }
+        Format::U8 => {
+            for (out, sample) in output.as_samples_mut::<u8>().iter_mut().zip(mix) {
+                *out = (quantize(sample, 128.0, 127.0) + 128.0) as u8;
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Reads the samples of a device in its format into `samples`, scaled the way `write_mix`
+/// writes them back. Formats it doesn't know read as silence.
+pub(super) fn read_samples(input: &Frames, samples: &mut [f32]) {
+    let scale = |value: f32, negative: f32, positive: f32| {
+        value / if value < 0.0 { negative } else { positive }
+    };
+    match input.format() {
+        Format::F32 => {
+            for (sample, value) in samples.iter_mut().zip(input.as_samples::<f32>()) {
+                *sample = *value;
+            }
+        }
+        Format::S16 => {
+            for (sample, value) in samples.iter_mut().zip(input.as_samples::<i16>()) {
+                *sample = value.to_f32();
+            }
+        }
+        Format::S32 => {
+            for (sample, value) in samples.iter_mut().zip(input.as_samples::<i32>()) {
+                *sample = scale(*value as f32, 2_147_483_648.0, 2_147_483_647.0);
+            }
+        }
+        Format::S24 => {
+            for (sample, bytes) in samples.iter_mut().zip(input.as_bytes().chunks_exact(3)) {
+                let value = i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8;
+                *sample = scale(value as f32, 8_388_608.0, 8_388_607.0);
+            }
+        }
+        Format::U8 => {
+            for (sample, value) in samples.iter_mut().zip(input.as_samples::<u8>()) {
+                *sample = scale(*value as f32 - 128.0, 128.0, 127.0);
+            }
+        }
+        _ => samples.iter_mut().for_each(|sample| *sample = 0.0),
+    }
+}
+
+/// What the device of a sink mixes into and applies, only ever locked by the device
+struct Mixdown {
+    mix: Vec<f32>,
+    filter: Filter,
+    dynamics: Filter,
+    dither: Dither,
+    sidechain: Option<Arc<Sidechain>>,
+}
+
+pub struct Sink<T, S>
+where
+    S: Source + Send + Sync + 'static,
+    S::Item: Sample,
+    S::Item: Send,
+    T: std::cmp::Eq,
+    T: std::hash::Hash,
+{
+    /// `None` after the device was lost
+    device: Option<Box<dyn Device>>,
+    volume: f32,
+    sources: SourcesType<T, S>,
+    mixdown: Arc<parking_lot::Mutex<Mixdown>>,
+    filters: FilterChain,
+    dynamics: FilterChain,
+    dithering: Arc<AtomicBool>,
+    levels: Arc<LevelMeter>,
+    finished_sender: crossbeam_channel::Sender<(T, InstanceId)>,
+    finished: crossbeam_channel::Receiver<(T, InstanceId)>,
+}
+
+impl<T, S> Sink<T, S>
+where
+    S: Source + Send + Sync + 'static,
+    S::Item: Sample,
+    S::Item: Send,
+    T: std::cmp::Eq,
+    T: std::hash::Hash,
+    T: Clone + Send + 'static,
+    T: std::fmt::Debug,
+{
+    /// Builds a new `Sink` without a device, it plays nothing until `reopen` succeeds
+    pub fn closed() -> Self {
+        let (finished_sender, finished) = crossbeam_channel::unbounded();
+        let (filters, filter) = FilterChain::new();
+        let (dynamics, dynamics_filter) = FilterChain::new();
+        let mixdown = Mixdown {
+            mix: Vec::new(),
+            filter,
+            dynamics: dynamics_filter,
+            dither: Dither::new(),
+            sidechain: None,
+        };
+        Sink {
+            device: None,
+            volume: 1.0,
+            sources: SourcesType::<T, S>::default(),
+            mixdown: Arc::new(parking_lot::Mutex::new(mixdown)),
+            filters,
+            dynamics,
+            dithering: Arc::new(AtomicBool::new(false)),
+            levels: Arc::default(),
+            finished_sender,
+            finished,
+        }
+    }
+
+    /// Moves the sink to the playback device `device`, the default one when `None`.
+    ///
+    /// The sounds of the sink carry on from where they were on the new device, which needs to
+    /// be started.
+    pub fn reopen(
+        &mut self,
+        backend: &dyn Backend,
+        device: Option<&str>,
+    ) -> Result<(), DeviceError> {
+        // Only one device may pull from the sources at a time
+        self.close();
+        let device = backend.open_playback(device, self.callback())?;
+        device.set_volume(self.volume)?;
+        // Reset before the device starts, so the callback doesn't allocate for it
+        let mut mixdown = self.mixdown.lock();
+        let (sample_rate, channels) = (device.sample_rate(), device.channels());
+        self.filters
+            .set_stream(&mut mixdown.filter, sample_rate, channels);
+        self.dynamics
+            .set_stream(&mut mixdown.dynamics, sample_rate, channels);
+        drop(mixdown);
+        self.device = Some(device);
+        Ok(())
+    }
+
+    /// Drops the device of the sink, keeping its sounds where they are until `reopen`
+    pub fn close(&mut self) {
+        if let Some(device) = self.device.take() {
+            if let Err(err) = device.stop() {
+                debug!("failed to stop closed device {}", err);
+            }
+        }
+    }
+
+    /// Does the sink have a device
+    pub fn is_open(&self) -> bool {
+        self.device.is_some()
+    }
+
+    fn callback(&self) -> DataCallback {
+        let sources = self.sources.clone();
+        let mixdown = self.mixdown.clone();
+        let dithering = self.dithering.clone();
+        let levels = self.levels.clone();
+        let finished_sender = self.finished_sender.clone();
+        Arc::new(move |sample_rate, output, _input| {
+            let mut unlocked = sources.lock();
+            // Only held by another device while it is being replaced
+            let mut mixdown = match mixdown.try_lock() {
+                Some(mixdown) => mixdown,
+                None => return,
+            };
+            let Mixdown {
+                mix,
+                filter,
+                dynamics,
+                dither,
+                sidechain,
+            } = &mut *mixdown;
+            let channels = output.channels() as usize;
+            mix.clear();
+            mix.resize(output.sample_count(), 0.0);
+            mix_sources(
+                &mut unlocked,
+                sample_rate,
+                channels,
+                mix,
+                sidechain.as_deref(),
+                |key, instance| {
+                    // The receiver only goes away together with the sink
+                    let _ = finished_sender.send((key.clone(), instance));
+                },
+            );
+            drop(unlocked);
+            filter.apply_samples(sample_rate, channels, mix);
+            dynamics.apply_samples(sample_rate, channels, mix);
+            levels.record_samples(mix, channels);
+            let dither = Some(dither).filter(|_| dithering.load(Ordering::Relaxed));
+            write_mix(mix, output, dither);
+        })
+    }
+
+    /// The filters applied to the mixed sounds, kept when the device changes
+    pub fn filters(&mut self) -> &mut FilterChain {
+        &mut self.filters
+    }
+
+    /// Applied after `filters`, for the compressor and limiter to see the mix that goes out
+    pub fn dynamics(&mut self) -> &mut FilterChain {
+        &mut self.dynamics
+    }
+
+    /// Ducks the sources played as the music bed while `sidechain` says so
+    pub fn set_sidechain(&mut self, sidechain: Arc<Sidechain>) {
+        self.mixdown.lock().sidechain = Some(sidechain);
+    }
+
+    /// Adds triangular noise to the mix before it is rounded to an integer device format, which
+    /// hides the rounding of quiet sounds and fades under a steady hiss
+    pub fn set_dither(&mut self, dither: bool) {
+        self.dithering.store(dither, Ordering::Relaxed);
+    }
+
+    /// Level of each channel of the mix since the last call, before it is clipped to the
+    /// device format
+    pub fn take_levels(&self) -> Vec<ChannelLevel> {
+        self.levels.take()
+    }
+
+    /// Starts playing `source` as `instance` of the sound under `key`, next to any instances
+    /// that are already playing
+    pub fn play(
+        &mut self,
+        key: T,
+        instance: InstanceId,
+        source: S,
+        settings: PlaySettings,
+    ) -> Result<()> {
+        // Sounds can't pile up while the device is gone
+        if !self.is_open() {
+            return Err(DeviceError::Closed.into());
+        }
+        let playing = PlayingSource::with_settings(source, instance, settings)?;
+        let mut unlocked = self.sources.lock();
+        unlocked.entry(key).or_default().push(playing);
+        Ok(())
+    }
+
+    #[allow(dead_code)]
     pub fn remove(&mut self, key: &T) {
         let mut unlocked = self.sources.lock();
         unlocked.remove(key);
     }
 
-    pub fn is_playing(&mut self, key: &T) -> bool {
+    /// Fades every instance of the sound out and removes it once it is silent.
+    ///
+    /// The fade lasts `fade` seconds, or the fade out each instance was played with when `None`.
+    /// Paused instances are removed straight away.
+    pub fn fade_out(&mut self, key: &T, fade: Option<f32>) {
+        let mut unlocked = self.sources.lock();
+        if let Some(sources) = unlocked.get_mut(key) {
+            Self::fade_out_sources(sources, None, fade);
+            if sources.is_empty() {
+                unlocked.remove(key);
+            }
+        }
+    }
+
+    /// Fades out a single instance of the sound, see `fade_out`
+    pub fn fade_out_instance(&mut self, key: &T, instance: InstanceId, fade: Option<f32>) {
+        let mut unlocked = self.sources.lock();
+        if let Some(sources) = unlocked.get_mut(key) {
+            Self::fade_out_sources(sources, Some(instance), fade);
+            if sources.is_empty() {
+                unlocked.remove(key);
+            }
+        }
+    }
+
+    /// Fades every sound in the sink out, see `fade_out`
+    pub fn fade_out_all(&mut self, fade: Option<f32>) {
+        let mut unlocked = self.sources.lock();
+        for sources in unlocked.values_mut() {
+            Self::fade_out_sources(sources, None, fade);
+        }
+        unlocked.retain(|_, sources| !sources.is_empty());
+    }
+
+    /// Fades out `instance`, or every source when `None`
+    fn fade_out_sources(
+        sources: &mut Vec<PlayingSource<S>>,
+        instance: Option<InstanceId>,
+        fade: Option<f32>,
+    ) {
+        let selected =
+            |playing: &PlayingSource<S>| instance.is_none() || instance == Some(playing.instance);
+        sources.retain(|playing| !(selected(playing) && playing.paused));
+        for playing in sources.iter_mut().filter(|playing| selected(playing)) {
+            let length = fade.unwrap_or(playing.envelope.fades.fade_out);
+            playing.envelope.stop(length);
+        }
+    }
+
+    pub fn is_playing(&mut self, key: &T, instance: InstanceId) -> bool {
         let unlocked = self.sources.lock();
-        unlocked.contains_key(&key)
+        unlocked
+            .get(key)
+            .into_iter()
+            .flatten()
+            .any(|playing| playing.instance == instance)
+    }
+
+    /// Moves every instance of the sound to `pos` seconds from its start
+    ///
+    /// The sources are taken out of the mixer while seeking so slow seeks don't stall the
+    /// other sounds.
+    pub fn seek(&mut self, key: &T, pos: f32) -> Result<()> {
+        if pos < 0.0 {
+            return Err(anyhow!("supplied seek timestamp is negative {}", pos));
+        }
+        let mut sources = match self.sources.lock().remove(key) {
+            Some(sources) => sources,
+            None => return Ok(()),
+        };
+        let mut result = Ok(());
+        for playing in sources.iter_mut() {
+            if let Err(err) = playing.seek(pos) {
+                result = Err(anyhow!("could not seek {:?}: {}", key, err));
+            }
+        }
+        let mut unlocked = self.sources.lock();
+        let entry = unlocked.entry(key.clone()).or_default();
+        entry.splice(0..0, sources);
+        result
+    }
+
+    /// Changes the gain of every instance of the sound, keeping their normalization
+    pub fn set_gain(&mut self, key: &T, gain: f32) {
+        let mut unlocked = self.sources.lock();
+        if let Some(sources) = unlocked.get_mut(key) {
+            for playing in sources.iter_mut() {
+                playing.mix.gain = gain;
+            }
+        }
+    }
+
+    /// Changes the stereo position of every instance of the sound
+    pub fn set_pan(&mut self, key: &T, pan: f32) {
+        let mut unlocked = self.sources.lock();
+        if let Some(sources) = unlocked.get_mut(key) {
+            for playing in sources.iter_mut() {
+                playing.mix.pan = pan;
+            }
+        }
+    }
+
+    /// Pauses every instance of the sound, keeping its position
+    pub fn pause(&mut self, key: &T) {
+        self.set_paused(key, true)
+    }
+
+    /// Resumes every instance of the sound from where it was paused
+    pub fn resume(&mut self, key: &T) {
+        self.set_paused(key, false)
+    }
+
+    fn set_paused(&mut self, key: &T, paused: bool) {
+        let mut unlocked = self.sources.lock();
+        if let Some(sources) = unlocked.get_mut(key) {
+            for playing in sources.iter_mut() {
+                playing.paused = paused;
+            }
+        }
+    }
+
+    /// Pauses every sound in the sink
+    pub fn pause_all(&mut self) {
+        self.set_all_paused(true)
+    }
+
+    /// Resumes every sound in the sink
+    pub fn resume_all(&mut self) {
+        self.set_all_paused(false)
+    }
+
+    fn set_all_paused(&mut self, paused: bool) {
+        let mut unlocked = self.sources.lock();
+        for playing in unlocked.values_mut().flatten() {
+            playing.paused = paused;
+        }
+    }
+
+    /// Playback position of the given instance of the sound, `None` once it stopped
+    pub fn position(&self, key: &T, instance: InstanceId) -> Option<Duration> {
+        let unlocked = self.sources.lock();
+        unlocked
+            .get(key)
+            .and_then(|sources| sources.iter().find(|playing| playing.instance == instance))
+            .map(|playing| Duration::from_secs_f32(playing.current_duration))
     }
 
     /// Gets the volume of the sound.
@@ -245,51 +1039,293 @@ where
     /// multiply each sample by this value.
     #[inline]
     #[allow(dead_code)]
-    pub fn volume(&self) -> Result<f32> {
-        self.device
-            .get_master_volume()
-            .map_err(|err| anyhow!("Could not get volume {}", err))
+    pub fn volume(&self) -> f32 {
+        self.volume
     }
 
     /// Changes the volume of the sound.
     ///
     /// The value `1.0` is the "normal" volume (unfiltered input). Any value other than `1.0` will
-    /// multiply each sample by this value.
+    /// multiply each sample by this value. It is kept for the next device while the sink is
+    /// closed.
     #[inline]
-    pub fn set_volume(&self, value: f32) -> Result<()> {
-        self.device
-            .set_master_volume(value)
-            .map_err(|err| anyhow!("Could not set volume {}", err))
+    pub fn set_volume(&mut self, value: f32) -> Result<(), DeviceError> {
+        self.volume = value;
+        match self.device.as_ref() {
+            Some(device) => device.set_volume(value),
+            None => Ok(()),
+        }
+    }
+
+    /// Receives every instance the mixer drops because it ended or faded out after a stop
+    pub fn finished(&self) -> &crossbeam_channel::Receiver<(T, InstanceId)> {
+        &self.finished
     }
 
     /// Starts the sink
     #[inline]
-    pub fn start(&self) -> Result<()> {
-        self.device
-            .start()
-            .map_err(|err| anyhow!("Could not start device {}", err))?;
-        self.stopped
-            .store(false, std::sync::atomic::Ordering::Relaxed);
-        Ok(())
+    pub fn start(&self) -> Result<(), DeviceError> {
+        self.device.as_ref().ok_or(DeviceError::Closed)?.start()
     }
 
     /// Stops the sink
     #[inline]
     #[allow(dead_code)]
-    pub fn stop(&self) -> Result<()> {
-        self.stopped
-            .store(true, std::sync::atomic::Ordering::Relaxed);
-        if self.device.is_started() {
-            self.device
-                .stop()
-                .map_err(|err| anyhow!("Could not stop device {}", err))?;
-        }
-        Ok(())
+    pub fn stop(&self) -> Result<(), DeviceError> {
+        self.device.as_ref().ok_or(DeviceError::Closed)?.stop()
     }
 
-    /// Is the sink stopped
+    /// Has the device of the sink stopped, a closed sink isn't stopped
     #[inline]
     pub fn stopped(&self) -> bool {
-        self.stopped.load(std::sync::atomic::Ordering::Relaxed)
+        self.device
+            .as_ref()
+            .into_iter()
+            .any(|device| device.stopped())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_samples, write_mix, Dither, Envelope, Fades, Looping, Mix, PlayingSource};
+    use crate::sound::source::{SeekError, Source};
+    use miniaudio::{Format, Frames, FramesMut};
+    use std::time::Duration;
+
+    /// Mono source at 10 Hz whose samples are their own index
+    struct Counter {
+        pos: i16,
+        len: i16,
+        seekable: bool,
+    }
+
+    impl Iterator for Counter {
+        type Item = f32;
+
+        fn next(&mut self) -> Option<f32> {
+            if self.pos >= self.len {
+                return None;
+            }
+            self.pos += 1;
+            Some((self.pos - 1) as f32)
+        }
+    }
+
+    impl Source for Counter {
+        fn current_frame_len(&self) -> Option<usize> {
+            None
+        }
+
+        fn channels(&self) -> u16 {
+            1
+        }
+
+        fn sample_rate(&self) -> u32 {
+            10
+        }
+
+        fn total_duration(&self) -> Option<Duration> {
+            None
+        }
+
+        fn seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+            if !self.seekable {
+                return Err(SeekError::NotSupported);
+            }
+            self.pos = (pos.as_secs_f32() * 10.0).round() as i16;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn envelope_fades_in_and_stops() {
+        let mut envelope = Envelope::new(Fades {
+            fade_in: 1.0,
+            fade_out: 0.0,
+        });
+        assert!(envelope.next(0.5, f32::INFINITY).abs() < 1e-6);
+        assert!((envelope.next(0.5, f32::INFINITY) - 0.5).abs() < 1e-6);
+        assert!((envelope.next(0.5, f32::INFINITY) - 1.0).abs() < 1e-6);
+
+        envelope.stop(1.0);
+        assert!((envelope.next(0.5, f32::INFINITY) - 1.0).abs() < 1e-6);
+        assert!((envelope.next(0.5, f32::INFINITY) - 0.5).abs() < 1e-6);
+        assert!(envelope.stopped());
+    }
+
+    #[test]
+    fn envelope_fades_out_before_end() {
+        let mut envelope = Envelope::new(Fades {
+            fade_in: 0.0,
+            fade_out: 2.0,
+        });
+        assert!((envelope.next(0.1, 3.0) - 1.0).abs() < 1e-6);
+        assert!((envelope.next(0.1, 1.0) - 0.5).abs() < 1e-6);
+        assert!(envelope.next(0.1, 0.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn loops_between_points() {
+        let looping = Looping {
+            start: 0.2,
+            end: Some(0.5),
+            repeats: Some(1),
+        };
+        let counter = Counter {
+            pos: 0,
+            len: 8,
+            seekable: true,
+        };
+        let mut playing = PlayingSource::new(
+            counter,
+            0,
+            Mix::default(),
+            Fades::default(),
+            Some(looping),
+            f32::INFINITY,
+        );
+        assert_eq!(
+            playing.read(12),
+            vec![0.0, 1.0, 2.0, 3.0, 4.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0]
+        );
+        assert!(playing.finished);
+    }
+
+    #[test]
+    fn loops_at_end_of_source() {
+        let looping = Looping {
+            start: 0.0,
+            end: None,
+            repeats: None,
+        };
+        let counter = Counter {
+            pos: 0,
+            len: 3,
+            seekable: true,
+        };
+        let mut playing = PlayingSource::new(
+            counter,
+            0,
+            Mix::default(),
+            Fades::default(),
+            Some(looping),
+            f32::INFINITY,
+        );
+        assert_eq!(playing.read(7), vec![0.0, 1.0, 2.0, 0.0, 1.0, 2.0, 0.0]);
+        assert!(!playing.finished);
+    }
+
+    #[test]
+    fn repeats_loop_without_seeking() {
+        let looping = Looping {
+            start: 0.1,
+            end: Some(0.3),
+            repeats: Some(2),
+        };
+        let counter = Counter {
+            pos: 0,
+            len: 5,
+            seekable: false,
+        };
+        let mut playing = PlayingSource::new(
+            counter,
+            0,
+            Mix::default(),
+            Fades::default(),
+            Some(looping),
+            f32::INFINITY,
+        );
+        assert_eq!(
+            playing.read(10),
+            vec![0.0, 1.0, 2.0, 1.0, 2.0, 1.0, 2.0, 3.0, 4.0]
+        );
+        assert!(playing.finished);
+    }
+
+    #[test]
+    fn writes_mix_in_device_format() {
+        let mix = [0.5, -1.5, 1.0 / 8_388_607.0];
+        let mut s16 = [0i16; 3];
+        write_mix(&mix, &mut FramesMut::wrap(&mut s16, Format::S16, 1), None);
+        assert_eq!(s16, [16_384, -32_768, 0]);
+
+        // The extra depth of 24 bit keeps the smallest step
+        let mut s24 = [0u8; 9];
+        write_mix(&mix, &mut FramesMut::wrap(&mut s24, Format::S24, 1), None);
+        assert_eq!(&s24[6..], &[1, 0, 0]);
+        assert_eq!(&s24[3..6], &[0, 0, 0x80]);
+
+        let mut u8s = [0u8; 3];
+        write_mix(&mix, &mut FramesMut::wrap(&mut u8s, Format::U8, 1), None);
+        assert_eq!(u8s, [192, 0, 128]);
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let mix = [0.5, -1.0, 1.0, -0.25];
+        for (format, size) in [
+            (Format::U8, 1),
+            (Format::S16, 2),
+            (Format::S24, 3),
+            (Format::S32, 4),
+            (Format::F32, 4),
+        ]
+        .iter()
+        {
+            let mut bytes = vec![0u8; mix.len() * size];
+            write_mix(&mix, &mut FramesMut::wrap(&mut bytes, *format, 1), None);
+            let mut samples = [0.0; 4];
+            read_samples(&Frames::wrap(&bytes, *format, 1), &mut samples);
+            for (sample, expected) in samples.iter().zip(mix.iter()) {
+                assert!(
+                    (sample - expected).abs() < 0.01,
+                    "{:?} read {}",
+                    format,
+                    sample
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn dither_averages_out() {
+        // A quarter of a step, which rounding alone would lose
+        let mix = vec![0.25 / 32_767.0; 4096];
+        let mut output = vec![0i16; mix.len()];
+        let mut dither = Dither::new();
+        write_mix(
+            &mix,
+            &mut FramesMut::wrap(&mut output, Format::S16, 1),
+            Some(&mut dither),
+        );
+        assert!(output.iter().all(|sample| (-1..=2).contains(sample)));
+        let mean = output.iter().map(|sample| *sample as f32).sum::<f32>() / output.len() as f32;
+        assert!((mean - 0.25).abs() < 0.05, "averaged {}", mean);
+    }
+
+    #[test]
+    fn panning_never_raises_a_channel() {
+        let gains = |pan| {
+            Mix {
+                pan,
+                ..Mix::default()
+            }
+            .channel_gains(2)
+        };
+        assert_eq!(gains(0.0), [1.0, 1.0]);
+        assert_eq!(gains(-1.0)[0], 1.0);
+        assert!(gains(-1.0)[1].abs() < 1e-6);
+        let half = gains(0.5);
+        assert_eq!(half[1], 1.0);
+        assert!((half[0] - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+        assert_eq!(
+            Mix {
+                gain: 0.5,
+                pan: 1.0,
+                normalization: 1.0,
+            }
+            .channel_gains(1),
+            [0.5, 0.5]
+        );
     }
 }
@@ -2,6 +2,8 @@
 
 //! Sources of sound and various filters.
 
+use std::error::Error;
+use std::fmt;
 use std::time::Duration;
 
 use super::sample::Sample;
@@ -29,8 +31,35 @@ where
     ///
     /// `None` indicates at the same time "infinite" or "unknown".
     fn total_duration(&self) -> Option<Duration>;
+
+    /// Moves the playback position to `pos`, measured from the start of the source.
+    ///
+    /// Sources that can't reposition themselves return `SeekError::NotSupported`.
+    fn seek(&mut self, _pos: Duration) -> Result<(), SeekError> {
+        Err(SeekError::NotSupported)
+    }
+}
+
+/// Error that can happen when seeking a source.
+#[derive(Debug, Clone)]
+pub enum SeekError {
+    /// The source doesn't support seeking.
+    NotSupported,
+    /// The underlying data could not be read at the new position.
+    Io(String),
 }
 
+impl fmt::Display for SeekError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SeekError::NotSupported => write!(f, "Seeking is not supported"),
+            SeekError::Io(err) => write!(f, "Seek failed: {}", err),
+        }
+    }
+}
+
+impl Error for SeekError {}
+
 impl<S> Source for Box<dyn Source<Item = S>>
 where
     S: Sample,
@@ -54,6 +83,11 @@ where
     fn total_duration(&self) -> Option<Duration> {
         (**self).total_duration()
     }
+
+    #[inline]
+    fn seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        (**self).seek(pos)
+    }
 }
 
 impl<S> Source for Box<dyn Source<Item = S> + Send>
@@ -79,6 +113,11 @@ where
     fn total_duration(&self) -> Option<Duration> {
         (**self).total_duration()
     }
+
+    #[inline]
+    fn seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        (**self).seek(pos)
+    }
 }
 
 impl<S> Source for Box<dyn Source<Item = S> + Send + Sync>
@@ -104,4 +143,9 @@ where
     fn total_duration(&self) -> Option<Duration> {
         (**self).total_duration()
     }
+
+    #[inline]
+    fn seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        (**self).seek(pos)
+    }
 }
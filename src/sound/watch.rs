@@ -0,0 +1,361 @@
+//! Keeps the devices of the sound loop open, following the `config::DeviceFallback` when they
+//! go missing
+
+use log::{error, warn};
+use std::time::{Duration, Instant};
+
+use super::backend::{Backend, DeviceError, DeviceInfo};
+use super::{DeviceRole, Event, Events, LoopDevices, Passthrough, SinkDecoder};
+use crate::config::DeviceFallback;
+
+/// Tracks the device used for a role and decides when to open it again
+pub(super) struct DeviceWatch {
+    role: DeviceRole,
+    /// The device asked for, the default one when `None`
+    wanted: Option<String>,
+    /// Stable id of the device asked for, which finds it again when its name changed
+    wanted_id: Option<String>,
+    fallback: DeviceFallback,
+    /// The device open for the role, `Some(None)` being the default one
+    open: Option<Option<String>>,
+    /// `Event::DeviceLost` was sent and the device hasn't been restored since
+    lost: bool,
+    /// Asked for another device with `switch`, which isn't open yet
+    switching: bool,
+    failures: u32,
+    next_attempt: Instant,
+}
+
+impl DeviceWatch {
+    fn new(
+        role: DeviceRole,
+        wanted: Option<String>,
+        wanted_id: Option<String>,
+        fallback: DeviceFallback,
+    ) -> Self {
+        Self {
+            role,
+            wanted,
+            wanted_id,
+            fallback,
+            open: None,
+            lost: false,
+            switching: false,
+            failures: 0,
+            next_attempt: Instant::now(),
+        }
+    }
+
+    fn name(&self) -> String {
+        match (&self.wanted, &self.wanted_id) {
+            (Some(name), _) if !name.is_empty() => name.clone(),
+            (_, Some(id)) => id.clone(),
+            _ => "default".to_string(),
+        }
+    }
+
+    fn gave_up(&self) -> bool {
+        match self.fallback {
+            DeviceFallback::Retry { attempts, .. } => self.failures >= attempts,
+            _ => false,
+        }
+    }
+
+    /// Is the open device missing from `available`
+    fn open_missing(&self, available: &[DeviceInfo]) -> bool {
+        match &self.open {
+            Some(Some(name)) => !present(available, name),
+            _ => false,
+        }
+    }
+
+    /// The device to open now, `Some(None)` being the default one, given the devices present.
+    ///
+    /// Asks for the wanted device again after falling back to the default one, as soon as it is
+    /// back.
+    fn due(&mut self, available: &[DeviceInfo], events: &Events) -> Option<Option<String>> {
+        // Follows the device to its new name when it was renamed
+        if let Some(id) = self.wanted_id.as_ref() {
+            if let Some(device) = available.iter().find(|device| device.id == *id) {
+                self.wanted = Some(device.name.clone());
+            }
+        }
+        let wanted_present = match &self.wanted {
+            Some(name) => present(available, name),
+            None => true,
+        };
+        if let Some(open) = &self.open {
+            let on_fallback = *open != self.wanted;
+            if on_fallback && !wanted_present {
+                self.report_lost(events);
+            }
+            return Some(self.wanted.clone()).filter(|_| on_fallback && wanted_present);
+        }
+        if self.gave_up() || Instant::now() < self.next_attempt {
+            return None;
+        }
+        if wanted_present {
+            return Some(self.wanted.clone());
+        }
+        self.report_lost(events);
+        match self.fallback {
+            // Playing the microphone to the default speakers would only make feedback
+            DeviceFallback::UseDefault if self.role != DeviceRole::Loop => Some(None),
+            DeviceFallback::Retry { .. } => {
+                self.failed(&DeviceError::NotFound(self.name()), events);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Asks for another device, the default one when `None`. The open device stays open until
+    /// the new one can be opened.
+    fn switch(&mut self, wanted: Option<String>) {
+        self.wanted = wanted;
+        self.wanted_id = None;
+        self.lost = false;
+        self.switching = true;
+        self.failures = 0;
+        self.next_attempt = Instant::now();
+    }
+
+    fn opened(&mut self, device: Option<String>, events: &Events) {
+        if device == self.wanted {
+            if self.lost {
+                events.send(Event::DeviceRestored(self.role, self.name()));
+            } else if self.switching {
+                events.send(Event::DeviceSwitched(self.role, self.name()));
+            }
+            self.lost = false;
+            self.switching = false;
+            self.failures = 0;
+        } else {
+            warn!(
+                "{} device \"{}\" is missing, using the default device",
+                self.role,
+                self.name()
+            );
+        }
+        self.open = Some(device);
+    }
+
+    /// The device couldn't be opened, the role is left without one
+    fn failed(&mut self, err: &DeviceError, events: &Events) {
+        warn!(
+            "could not open {} device \"{}\": {}",
+            self.role,
+            self.name(),
+            err
+        );
+        self.open = None;
+        self.failures += 1;
+        if let DeviceFallback::Retry { interval_ms, .. } = self.fallback {
+            self.next_attempt = Instant::now() + Duration::from_millis(interval_ms);
+            if self.gave_up() {
+                error!("giving up on {} device \"{}\"", self.role, self.name());
+                events.send(Event::DeviceError(format!(
+                    "giving up on {} device \"{}\": {}",
+                    self.role,
+                    self.name(),
+                    err
+                )));
+            }
+        }
+    }
+
+    /// The open device went away
+    fn closed(&mut self, err: &DeviceError, events: &Events) {
+        warn!("lost {} device \"{}\": {}", self.role, self.name(), err);
+        self.open = None;
+        self.failures = 0;
+        self.next_attempt = Instant::now();
+        self.report_lost(events);
+    }
+
+    fn report_lost(&mut self, events: &Events) {
+        if !self.lost {
+            self.lost = true;
+            events.send(Event::DeviceLost(self.role, self.name()));
+        }
+    }
+}
+
+fn present(available: &[DeviceInfo], name: &str) -> bool {
+    available.iter().any(|device| device.name == name)
+}
+
+/// The watches of every device of the sound loop
+pub(super) struct DeviceWatches {
+    output: DeviceWatch,
+    loopback: DeviceWatch,
+    input: DeviceWatch,
+}
+
+impl DeviceWatches {
+    pub(super) fn new(devices: &LoopDevices) -> Self {
+        Self {
+            output: DeviceWatch::new(
+                DeviceRole::Output,
+                devices.output.clone(),
+                None,
+                devices.fallback,
+            ),
+            loopback: DeviceWatch::new(
+                DeviceRole::Loop,
+                Some(devices.loopback.clone()),
+                devices.loopback_id.clone(),
+                devices.fallback,
+            ),
+            input: DeviceWatch::new(
+                DeviceRole::Input,
+                devices.input.clone(),
+                None,
+                devices.fallback,
+            ),
+        }
+    }
+
+    /// Moves `role` to `device` on the next device check, see `DeviceWatch::switch`
+    pub(super) fn switch(&mut self, role: DeviceRole, device: Option<String>) {
+        let watch = match role {
+            DeviceRole::Output => &mut self.output,
+            DeviceRole::Loop => &mut self.loopback,
+            DeviceRole::Input => &mut self.input,
+        };
+        watch.switch(device);
+    }
+}
+
+/// Restarts stopped devices, closing the ones that can't be restarted.
+///
+/// With `check` the devices are listed as well, to notice unplugged devices and to open the
+/// ones that are due as set by the fallback.
+pub(super) fn maintain_devices(
+    backend: &dyn Backend,
+    watches: &mut DeviceWatches,
+    output_sink: &mut SinkDecoder,
+    loopback_sink: &mut SinkDecoder,
+    passthrough: &mut Passthrough,
+    check: bool,
+    events: &Events,
+) {
+    let available = if check {
+        match backend.devices() {
+            Ok(devices) => Some(devices),
+            Err(err) => {
+                error!("failed to list devices {}", err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let (playback, capture) = match available.as_ref() {
+        Some(devices) => (
+            Some(devices.playback.as_slice()),
+            Some(devices.capture.as_slice()),
+        ),
+        None => (None, None),
+    };
+
+    watch_sink(&mut watches.output, output_sink, backend, playback, events);
+    watch_sink(
+        &mut watches.loopback,
+        loopback_sink,
+        backend,
+        playback,
+        events,
+    );
+    let loop_device = watches.loopback.open.clone().flatten();
+    watch_passthrough(
+        &mut watches.input,
+        passthrough,
+        loop_device,
+        backend,
+        capture,
+        events,
+    );
+}
+
+fn watch_sink(
+    watch: &mut DeviceWatch,
+    sink: &mut SinkDecoder,
+    backend: &dyn Backend,
+    available: Option<&[DeviceInfo]>,
+    events: &Events,
+) {
+    if sink.stopped() {
+        match sink.start() {
+            Ok(()) => events.send(Event::DeviceRestored(watch.role, watch.name())),
+            Err(err) => {
+                sink.close();
+                watch.closed(&err, events);
+            }
+        }
+    }
+    let available = match available {
+        Some(available) => available,
+        None => return,
+    };
+    if watch.open_missing(available) {
+        sink.close();
+        watch.closed(&DeviceError::NotFound(watch.name()), events);
+    }
+    if let Some(device) = watch.due(available, events) {
+        match sink
+            .reopen(backend, device.as_deref())
+            .and_then(|_| sink.start())
+        {
+            Ok(()) => watch.opened(device, events),
+            Err(err) => {
+                sink.close();
+                watch.failed(&err, events);
+            }
+        }
+    }
+}
+
+/// Like `watch_sink` for the stream passing the microphone to `loop_device`, which is `None`
+/// while the loop device is lost
+fn watch_passthrough(
+    watch: &mut DeviceWatch,
+    passthrough: &mut Passthrough,
+    loop_device: Option<String>,
+    backend: &dyn Backend,
+    available: Option<&[DeviceInfo]>,
+    events: &Events,
+) {
+    if passthrough.loop_device() != loop_device.as_deref() {
+        // The stream follows the loop device, losing or moving it isn't the input's doing
+        passthrough.close();
+        watch.open = None;
+    }
+    let loop_device = match loop_device {
+        Some(loop_device) => loop_device,
+        None => return,
+    };
+    if passthrough.stopped() {
+        match passthrough.start() {
+            Ok(()) => events.send(Event::DeviceRestored(watch.role, watch.name())),
+            Err(err) => {
+                passthrough.close();
+                watch.closed(&err, events);
+            }
+        }
+    }
+    let available = match available {
+        Some(available) => available,
+        None => return,
+    };
+    if watch.open_missing(available) {
+        passthrough.close();
+        watch.closed(&DeviceError::NotFound(watch.name()), events);
+    }
+    if let Some(device) = watch.due(available, events) {
+        match passthrough.reopen(backend, device.as_deref(), &loop_device) {
+            Ok(()) => watch.opened(device, events),
+            Err(err) => watch.failed(&err, events),
+        }
+    }
+}
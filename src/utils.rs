@@ -8,6 +8,12 @@ use serde::de::{Deserialize, Visitor, SeqAccess};
 use serde::ser::{Serialize, SerializeSeq};
 
 use std::marker::PhantomData;
+
+/// Converts a level in decibels into a linear amplitude multiplier
+pub fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
 #[derive(Clone)]
 pub struct IdMap<V> {
     v: HashMap<usize, V>,
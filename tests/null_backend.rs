@@ -0,0 +1,978 @@
+#![cfg(feature = "wav")]
+
+use mlws_lib::config;
+use mlws_lib::sound::backend::{Backend, NullBackend};
+use mlws_lib::sound::filter::{FilterId, NewFilter};
+use mlws_lib::sound::{
+    self, DeviceRole, Event, Events, InstanceId, LoopDevices, Message, PlayOptions, SoundDevices,
+    SoundStatus,
+};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+struct RunningLoop {
+    backend: NullBackend,
+    sender: crossbeam_channel::Sender<Message>,
+    status: crossbeam_channel::Receiver<Message>,
+    events: crossbeam_channel::Receiver<Event>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+/// The default devices of the null backend, with "Loop" as the loop device
+fn devices() -> LoopDevices {
+    LoopDevices {
+        input: None,
+        output: None,
+        loopback: "Loop".to_string(),
+        loopback_id: None,
+        fallback: config::DeviceFallback::default(),
+        levels: config::Levels::default(),
+        mic: config::MicSettings::default(),
+        eq: config::Equalizers::default(),
+        dynamics: config::SinkDynamics::default(),
+        dither: false,
+        ducking: None,
+        normalization: None,
+    }
+}
+
+fn start_loop() -> RunningLoop {
+    start_loop_with(devices())
+}
+
+fn start_loop_with(devices: LoopDevices) -> RunningLoop {
+    let backend = NullBackend::new(&["Speakers", "Loop", "Headset"], &["Mic", "Headset Mic"]);
+    let events = Events::default();
+    let subscriber = events.subscribe();
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let (status_sender, status) = crossbeam_channel::unbounded();
+
+    let loop_backend = backend.clone();
+    let loop_sender = sender.clone();
+    let thread = std::thread::spawn(move || {
+        sound::run_sound_loop_with_backend(
+            &loop_backend,
+            receiver,
+            status_sender,
+            loop_sender,
+            devices,
+            events,
+        );
+    });
+    let running = RunningLoop {
+        backend,
+        sender,
+        status,
+        events: subscriber,
+        thread,
+    };
+    // The loop only answers once its devices are open
+    running
+        .sender
+        .send(Message::PlayStatus(
+            Vec::new(),
+            0.0,
+            config::Levels::default(),
+        ))
+        .unwrap();
+    running
+        .status
+        .recv_timeout(TIMEOUT)
+        .expect("loop didn't start");
+    running
+}
+
+impl RunningLoop {
+    fn wait_for(&self, matches: impl Fn(&Event) -> bool) -> Event {
+        loop {
+            let event = self
+                .events
+                .recv_timeout(TIMEOUT)
+                .expect("no event received");
+            if matches(&event) {
+                return event;
+            }
+        }
+    }
+
+    /// Waits for the loop to handle every message sent before
+    fn sync(&self) {
+        self.play_status();
+    }
+
+    /// Status of every instance once the loop handled every message sent before
+    fn play_status(&self) -> sound::PlayStatusVecType {
+        self.sender
+            .send(Message::PlayStatus(
+                Vec::new(),
+                0.0,
+                config::Levels::default(),
+            ))
+            .unwrap();
+        match self
+            .status
+            .recv_timeout(TIMEOUT)
+            .expect("loop didn't answer")
+        {
+            Message::PlayStatus(sounds, _, _) => sounds,
+            message => panic!("unexpected answer {:?}", message),
+        }
+    }
+
+    /// Status and id of every instance of the sound
+    fn instances(&self, sound: &config::Sound) -> Vec<(SoundStatus, InstanceId)> {
+        self.play_status()
+            .into_iter()
+            .filter(|(_, playing, ..)| playing == sound)
+            .map(|(status, _, _, _, instance)| (status, instance))
+            .collect()
+    }
+
+    fn play(&self, sound: &config::Sound) {
+        self.sender
+            .send(Message::PlaySound(
+                sound.clone(),
+                SoundDevices::Output,
+                PlayOptions::default(),
+            ))
+            .unwrap();
+    }
+
+    /// Next event about a sound starting or stopping
+    fn next_sound_event(&self) -> Event {
+        self.wait_for(|event| {
+            matches!(
+                event,
+                Event::SoundStarted(..) | Event::SoundStopped(..) | Event::SoundFinished(..)
+            )
+        })
+    }
+
+    fn kill(self) {
+        self.sender.send(Message::Kill).unwrap();
+        self.thread.join().unwrap();
+    }
+}
+
+/// Writes a stereo WAV in the null backend's format so nothing gets resampled
+fn write_wav(name: &str, samples: &[i16]) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join("mlws_null_backend_test");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate: 48000,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+    for sample in samples {
+        writer.write_sample(*sample).unwrap();
+    }
+    writer.finalize().unwrap();
+    path
+}
+
+#[test]
+fn plays_sound_on_output_device() {
+    let running = start_loop();
+    let samples: Vec<i16> = (0..2400).map(|i| (i % 200 - 100) as i16 * 50).collect();
+    let sound = config::Sound {
+        wav: write_wav("ramp.wav", &samples),
+        ..config::Sound::default()
+    };
+
+    running
+        .sender
+        .send(Message::PlaySound(
+            sound.clone(),
+            SoundDevices::Output,
+            PlayOptions::default(),
+        ))
+        .unwrap();
+    running.wait_for(|event| matches!(event, Event::SoundStarted(..)));
+
+    running.backend.advance(samples.len() / 2 + 512);
+    let output = running.backend.take_output("Speakers");
+    assert_eq!(&output[..samples.len()], &samples[..]);
+    assert!(output[samples.len()..].iter().all(|sample| *sample == 0));
+    assert!(running
+        .backend
+        .take_output("Loop")
+        .iter()
+        .all(|sample| *sample == 0));
+
+    let finished = running.wait_for(|event| !matches!(event, Event::PositionUpdate(_)));
+    assert!(matches!(finished, Event::SoundFinished(finished, _) if finished == sound));
+    running.kill();
+}
+
+#[test]
+fn passes_microphone_to_loop_device() {
+    let running = start_loop();
+    let mic: Vec<i16> = (0..960).map(|i| i as i16).collect();
+    running.backend.push_input("Mic", &mic);
+
+    running.backend.advance(mic.len() / 2);
+    assert_eq!(running.backend.take_output("Loop"), mic);
+    running.kill();
+}
+
+#[test]
+fn finds_loop_device_by_id() {
+    let running = start_loop_with(LoopDevices {
+        loopback: String::new(),
+        loopback_id: Some("null:Loop".to_string()),
+        ..devices()
+    });
+    let listed = running.backend.devices().unwrap();
+    assert_eq!(listed.playback_by_id("null:Loop").unwrap().name, "Loop");
+    assert!(listed.playback[0].is_default && !listed.playback[1].is_default);
+
+    let mic: Vec<i16> = (0..960).map(|i| i as i16).collect();
+    running.backend.push_input("Mic", &mic);
+    running.backend.advance(mic.len() / 2);
+    assert_eq!(running.backend.take_output("Loop"), mic);
+    running.kill();
+}
+
+#[test]
+fn reopens_unplugged_output_device() {
+    let running = start_loop_with(LoopDevices {
+        output: Some("Speakers".to_string()),
+        fallback: config::DeviceFallback::Wait,
+        ..devices()
+    });
+    let samples: Vec<i16> = (0..96000).map(|i| (i % 200 - 100) as i16 * 50).collect();
+    let sound = config::Sound {
+        wav: write_wav("long_ramp.wav", &samples),
+        ..config::Sound::default()
+    };
+    running
+        .sender
+        .send(Message::PlaySound(
+            sound,
+            SoundDevices::Output,
+            PlayOptions::default(),
+        ))
+        .unwrap();
+    running.wait_for(|event| matches!(event, Event::SoundStarted(..)));
+    running.backend.advance(480);
+    assert_eq!(
+        &running.backend.take_output("Speakers")[..],
+        &samples[..960]
+    );
+
+    running.backend.unplug("Speakers");
+    let lost = running.wait_for(|event| matches!(event, Event::DeviceLost(..)));
+    assert_eq!(
+        lost,
+        Event::DeviceLost(DeviceRole::Output, "Speakers".to_string())
+    );
+    running.backend.advance(480);
+    assert!(running.backend.take_output("Speakers").is_empty());
+
+    running.backend.plug("Speakers");
+    let restored = running.wait_for(|event| matches!(event, Event::DeviceRestored(..)));
+    assert_eq!(
+        restored,
+        Event::DeviceRestored(DeviceRole::Output, "Speakers".to_string())
+    );
+    // The sound carries on from where it was when the device went away
+    running.backend.advance(480);
+    assert_eq!(
+        &running.backend.take_output("Speakers")[..],
+        &samples[960..1920]
+    );
+    running.kill();
+}
+
+#[test]
+fn moves_playing_sound_to_new_output_device() {
+    let running = start_loop();
+    let samples: Vec<i16> = (0..96000).map(|i| (i % 300 - 150) as i16 * 40).collect();
+    let sound = config::Sound {
+        wav: write_wav("long_saw.wav", &samples),
+        ..config::Sound::default()
+    };
+    running
+        .sender
+        .send(Message::PlaySound(
+            sound,
+            SoundDevices::Output,
+            PlayOptions::default(),
+        ))
+        .unwrap();
+    running.wait_for(|event| matches!(event, Event::SoundStarted(..)));
+    running.backend.advance(480);
+    assert_eq!(
+        &running.backend.take_output("Speakers")[..],
+        &samples[..960]
+    );
+
+    running
+        .sender
+        .send(Message::SetOutputDevice(Some("Headset".to_string())))
+        .unwrap();
+    let switched = running.wait_for(|event| matches!(event, Event::DeviceSwitched(..)));
+    assert_eq!(
+        switched,
+        Event::DeviceSwitched(DeviceRole::Output, "Headset".to_string())
+    );
+    running.backend.advance(480);
+    assert_eq!(
+        &running.backend.take_output("Headset")[960..],
+        &samples[960..1920]
+    );
+    assert!(running
+        .backend
+        .take_output("Speakers")
+        .iter()
+        .all(|sample| *sample == 0));
+    running.kill();
+}
+
+#[test]
+fn moves_microphone_with_loop_device() {
+    let running = start_loop();
+    running
+        .sender
+        .send(Message::SetLoopDevice("Headset".to_string()))
+        .unwrap();
+    running.wait_for(|event| matches!(event, Event::DeviceSwitched(DeviceRole::Loop, _)));
+    running
+        .sender
+        .send(Message::SetInputDevice(Some("Headset Mic".to_string())))
+        .unwrap();
+    running.wait_for(|event| matches!(event, Event::DeviceSwitched(DeviceRole::Input, _)));
+    running.backend.take_output("Headset");
+
+    let mic: Vec<i16> = (0..960).map(|i| i as i16).collect();
+    running.backend.push_input("Mic", &mic);
+    running.backend.push_input("Headset Mic", &mic);
+    running.backend.advance(mic.len() / 2);
+    assert_eq!(running.backend.take_output("Headset"), mic);
+    assert!(running
+        .backend
+        .take_output("Loop")
+        .iter()
+        .all(|sample| *sample == 0));
+    running.kill();
+}
+
+#[test]
+fn applies_device_levels() {
+    let running = start_loop_with(LoopDevices {
+        levels: config::Levels {
+            output: config::Level {
+                volume: 1.0,
+                muted: true,
+            },
+            ..config::Levels::default()
+        },
+        ..devices()
+    });
+    running
+        .sender
+        .send(Message::SetDeviceVolume(DeviceRole::Loop, 0.5))
+        .unwrap();
+    running
+        .sender
+        .send(Message::SetDeviceVolume(DeviceRole::Input, 0.25))
+        .unwrap();
+    running
+        .sender
+        .send(Message::PlayStatus(
+            Vec::new(),
+            0.0,
+            config::Levels::default(),
+        ))
+        .unwrap();
+    let status = running.status.recv_timeout(TIMEOUT).unwrap();
+    match status {
+        Message::PlayStatus(_, volume, levels) => {
+            assert_eq!(volume, 1.0);
+            assert!(levels.output.muted);
+            assert_eq!(levels.loopback.volume, 0.5);
+            assert_eq!(levels.mic.volume, 0.25);
+        }
+        other => panic!("unexpected reply {:?}", other),
+    }
+
+    let samples = vec![4000i16; 960];
+    let sound = config::Sound {
+        wav: write_wav("flat.wav", &samples),
+        ..config::Sound::default()
+    };
+    running
+        .sender
+        .send(Message::PlaySound(
+            sound,
+            SoundDevices::Both,
+            PlayOptions::default(),
+        ))
+        .unwrap();
+    running.wait_for(|event| matches!(event, Event::SoundStarted(..)));
+    running.backend.push_input("Mic", &[8000; 960]);
+    running.backend.advance(480);
+    assert!(running
+        .backend
+        .take_output("Speakers")
+        .iter()
+        .all(|sample| *sample == 0));
+    // Half the sound and a quarter of the microphone
+    assert!(running
+        .backend
+        .take_output("Loop")
+        .iter()
+        .all(|sample| *sample == 4000));
+    running.kill();
+}
+
+#[test]
+fn gates_microphone_with_push_to_talk() {
+    let running = start_loop_with(LoopDevices {
+        mic: config::MicSettings {
+            gain_db: 6.0,
+            mode: config::MicMode::PushToTalk,
+            ..config::MicSettings::default()
+        },
+        ..devices()
+    });
+    running
+        .sender
+        .send(Message::SetMeterInterval(Some(Duration::from_millis(10))))
+        .unwrap();
+    running.backend.push_input("Mic", &[4000; 960]);
+    running.backend.advance(480);
+    assert!(running
+        .backend
+        .take_output("Loop")
+        .iter()
+        .all(|sample| *sample == 0));
+    // Metered while the microphone is closed, after the gain
+    let level = running.wait_for(|event| matches!(event, Event::MicLevel(level) if *level > 0.0));
+    assert!(matches!(level, Event::MicLevel(level) if (level - 0.244).abs() < 0.01));
+
+    running.sender.send(Message::SetPushKeyHeld(true)).unwrap();
+    running.wait_for(|event| *event == Event::MicOpen(true));
+    running.backend.push_input("Mic", &[4000; 960]);
+    running.backend.advance(480);
+    let gained = running.backend.take_output("Loop");
+    assert!(gained.iter().all(|sample| (*sample - 7981).abs() <= 1));
+    running.kill();
+}
+
+#[test]
+fn filters_microphone() {
+    let running = start_loop();
+    let halve = FilterId::new();
+    let invert = FilterId::new();
+    running
+        .sender
+        .send(Message::AddFilter(
+            DeviceRole::Input,
+            halve,
+            NewFilter::from_fn(|frame: &mut [f32]| frame.iter_mut().for_each(|x| *x *= 0.5)),
+        ))
+        .unwrap();
+    running
+        .sender
+        .send(Message::AddFilter(
+            DeviceRole::Input,
+            invert,
+            NewFilter::from_fn(|frame: &mut [f32]| frame.iter_mut().for_each(|x| *x = -*x)),
+        ))
+        .unwrap();
+    running.sync();
+    running.backend.push_input("Mic", &[8192; 960]);
+    running.backend.advance(480);
+    assert!(running
+        .backend
+        .take_output("Loop")
+        .iter()
+        .all(|sample| *sample == -4096));
+
+    running
+        .sender
+        .send(Message::BypassFilter(DeviceRole::Input, invert, true))
+        .unwrap();
+    running
+        .sender
+        .send(Message::RemoveFilter(DeviceRole::Input, halve))
+        .unwrap();
+    running.sync();
+    running.backend.push_input("Mic", &[8192; 960]);
+    running.backend.advance(480);
+    assert!(running
+        .backend
+        .take_output("Loop")
+        .iter()
+        .all(|sample| *sample == 8192));
+    running.kill();
+}
+
+#[test]
+fn toggles_voice_preset() {
+    let echo = config::VoicePreset {
+        effects: vec![config::Effect::Echo {
+            delay_ms: 10.0,
+            feedback: 0.0,
+            mix: 1.0,
+        }],
+        keys: Vec::new(),
+    };
+    let running = start_loop_with(LoopDevices {
+        mic: config::MicSettings {
+            presets: vec![("echo".to_string(), echo)].into_iter().collect(),
+            ..config::MicSettings::default()
+        },
+        ..devices()
+    });
+    running
+        .sender
+        .send(Message::ToggleVoicePreset("echo".to_string()))
+        .unwrap();
+    running.wait_for(|event| *event == Event::VoicePresetChanged(Some("echo".to_string())));
+
+    // Only the echo, 480 frames late
+    running.backend.push_input("Mic", &[1000; 960]);
+    running.backend.advance(480);
+    assert!(running
+        .backend
+        .take_output("Loop")
+        .iter()
+        .all(|sample| *sample == 0));
+    running.backend.push_input("Mic", &[2000; 960]);
+    running.backend.advance(480);
+    assert!(running
+        .backend
+        .take_output("Loop")
+        .iter()
+        .all(|sample| (*sample - 1000).abs() <= 1));
+
+    running
+        .sender
+        .send(Message::ToggleVoicePreset("echo".to_string()))
+        .unwrap();
+    running.wait_for(|event| *event == Event::VoicePresetChanged(None));
+    running.sync();
+    running.backend.push_input("Mic", &[3000; 960]);
+    running.backend.advance(480);
+    assert!(running
+        .backend
+        .take_output("Loop")
+        .iter()
+        .all(|sample| *sample == 3000));
+    running.kill();
+}
+
+#[test]
+fn pauses_and_resumes_sound() {
+    let running = start_loop();
+    let samples: Vec<i16> = (0..19200).map(|i| (i / 2 % 1000 + 1) as i16).collect();
+    let sound = config::Sound {
+        wav: write_wav("pause.wav", &samples),
+        ..config::Sound::default()
+    };
+    running.play(&sound);
+    let id = match running.next_sound_event() {
+        Event::SoundStarted(started, id) if started == sound => id,
+        event => panic!("unexpected event {:?}", event),
+    };
+    running.sync();
+    running.backend.advance(480);
+    running.backend.take_output("Speakers");
+
+    running
+        .sender
+        .send(Message::PauseSound(sound.clone()))
+        .unwrap();
+    assert_eq!(running.instances(&sound), vec![(SoundStatus::Paused, id)]);
+    let position = running.play_status()[0].2;
+    running.backend.advance(4800);
+    assert!(running
+        .backend
+        .take_output("Speakers")
+        .iter()
+        .all(|sample| *sample == 0));
+    // Frozen where it was paused
+    assert_eq!(running.play_status()[0].2, position);
+
+    running
+        .sender
+        .send(Message::ResumeSound(sound.clone()))
+        .unwrap();
+    assert_eq!(running.instances(&sound), vec![(SoundStatus::Playing, id)]);
+    running.backend.advance(480);
+    let output = running.backend.take_output("Speakers");
+    let resumed = (position.as_secs_f64() * 48000.0).round() as usize * 2;
+    assert_eq!(&output[..], &samples[resumed..resumed + 960]);
+    assert!(running.play_status()[0].2 > position);
+    running.kill();
+}
+
+#[test]
+fn seeks_sound() {
+    let running = start_loop();
+    let samples: Vec<i16> = (0..19200).map(|i| (i / 2) as i16).collect();
+    let sound = config::Sound {
+        wav: write_wav("seek.wav", &samples),
+        ..config::Sound::default()
+    };
+    running.play(&sound);
+    running.next_sound_event();
+    running.sync();
+    running.backend.advance(480);
+    running.backend.take_output("Speakers");
+
+    running
+        .sender
+        .send(Message::SeekSound(
+            sound.clone(),
+            Duration::from_millis(100),
+        ))
+        .unwrap();
+    let position = running.play_status()[0].2;
+    assert!((position.as_secs_f32() - 0.1).abs() < 0.001);
+    running.backend.advance(480);
+    assert_eq!(
+        &running.backend.take_output("Speakers")[..],
+        &samples[9600..10560]
+    );
+
+    // Back before where it is now
+    running
+        .sender
+        .send(Message::SeekSound(sound.clone(), Duration::from_millis(50)))
+        .unwrap();
+    running.sync();
+    running.backend.advance(480);
+    assert_eq!(
+        &running.backend.take_output("Speakers")[..],
+        &samples[4800..5760]
+    );
+    running.kill();
+}
+
+/// A sound that keeps playing while the null backend isn't advanced
+fn retriggered_sound(name: &str, retrigger: config::Retrigger) -> config::Sound {
+    config::Sound {
+        wav: write_wav(name, &[1000; 9600]),
+        settings: config::SoundSettings {
+            retrigger,
+            ..config::SoundSettings::default()
+        },
+        ..config::Sound::default()
+    }
+}
+
+fn started(running: &RunningLoop, sound: &config::Sound) -> InstanceId {
+    match running.next_sound_event() {
+        Event::SoundStarted(started, id) if started == *sound => id,
+        event => panic!("expected {:?} to start, got {:?}", sound.wav, event),
+    }
+}
+
+fn stopped(running: &RunningLoop, sound: &config::Sound) -> InstanceId {
+    match running.next_sound_event() {
+        Event::SoundStopped(stopped, id) if stopped == *sound => id,
+        event => panic!("expected {:?} to stop, got {:?}", sound.wav, event),
+    }
+}
+
+#[test]
+fn overlaps_up_to_max_instances() {
+    let running = start_loop();
+    let sound = retriggered_sound(
+        "overlap.wav",
+        config::Retrigger::Overlap {
+            max_instances: Some(2),
+        },
+    );
+    running.play(&sound);
+    let first = started(&running, &sound);
+    running.play(&sound);
+    let second = started(&running, &sound);
+    assert_eq!(
+        running.instances(&sound),
+        vec![
+            (SoundStatus::Playing, first),
+            (SoundStatus::Playing, second)
+        ]
+    );
+
+    // The oldest copy makes room for the third
+    running.play(&sound);
+    assert_eq!(stopped(&running, &sound), first);
+    let third = started(&running, &sound);
+    assert_eq!(
+        running.instances(&sound),
+        vec![
+            (SoundStatus::Playing, second),
+            (SoundStatus::Playing, third)
+        ]
+    );
+    running.kill();
+}
+
+#[test]
+fn restarts_retriggered_sound() {
+    let running = start_loop();
+    let sound = retriggered_sound("restart.wav", config::Retrigger::Restart);
+    running.play(&sound);
+    let first = started(&running, &sound);
+    running.play(&sound);
+    assert_eq!(stopped(&running, &sound), first);
+    let second = started(&running, &sound);
+    assert_ne!(first, second);
+    assert_eq!(
+        running.instances(&sound),
+        vec![(SoundStatus::Playing, second)]
+    );
+    running.kill();
+}
+
+#[test]
+fn ignores_retriggered_sound() {
+    let running = start_loop();
+    let sound = retriggered_sound("ignore.wav", config::Retrigger::Ignore);
+    running.play(&sound);
+    let first = started(&running, &sound);
+    running.play(&sound);
+    assert_eq!(
+        running.instances(&sound),
+        vec![(SoundStatus::Playing, first)]
+    );
+    assert!(!running
+        .events
+        .try_iter()
+        .any(|event| matches!(event, Event::SoundStarted(..) | Event::SoundStopped(..))));
+    running.kill();
+}
+
+#[test]
+fn toggles_retriggered_sound() {
+    let running = start_loop();
+    let sound = retriggered_sound("toggle.wav", config::Retrigger::Toggle);
+    running.play(&sound);
+    let first = started(&running, &sound);
+    running.play(&sound);
+    assert_eq!(stopped(&running, &sound), first);
+    assert!(running.instances(&sound).is_empty());
+    assert!(!running
+        .events
+        .try_iter()
+        .any(|event| matches!(event, Event::SoundStarted(..))));
+
+    // Starts again once it is off
+    running.play(&sound);
+    let second = started(&running, &sound);
+    assert_eq!(
+        running.instances(&sound),
+        vec![(SoundStatus::Playing, second)]
+    );
+    running.kill();
+}
+
+#[test]
+fn chokes_sounds_in_the_same_group() {
+    let running = start_loop();
+    let in_group = |name: &str, group: &str| config::Sound {
+        wav: write_wav(name, &[1000; 9600]),
+        settings: config::SoundSettings {
+            choke_group: Some(group.to_string()),
+            ..config::SoundSettings::default()
+        },
+        ..config::Sound::default()
+    };
+    let open_hat = in_group("open_hat.wav", "hats");
+    let closed_hat = in_group("closed_hat.wav", "hats");
+    let snare = in_group("snare.wav", "snares");
+
+    running.play(&open_hat);
+    let open = started(&running, &open_hat);
+    running.play(&snare);
+    let snare_id = started(&running, &snare);
+    running.play(&closed_hat);
+    assert_eq!(stopped(&running, &open_hat), open);
+    let closed = started(&running, &closed_hat);
+    assert!(running.instances(&open_hat).is_empty());
+    assert_eq!(
+        running.instances(&closed_hat),
+        vec![(SoundStatus::Playing, closed)]
+    );
+    assert_eq!(
+        running.instances(&snare),
+        vec![(SoundStatus::Playing, snare_id)]
+    );
+
+    running
+        .sender
+        .send(Message::StopChokeGroup("hats".to_string()))
+        .unwrap();
+    assert_eq!(stopped(&running, &closed_hat), closed);
+    assert!(running.instances(&closed_hat).is_empty());
+    assert_eq!(
+        running.instances(&snare),
+        vec![(SoundStatus::Playing, snare_id)]
+    );
+    running.kill();
+}
+
+#[test]
+fn limits_overlapping_sounds() {
+    let limiter = config::Limiter {
+        ceiling_db: -6.0,
+        release_ms: 50.0,
+        lookahead_ms: 0.0,
+    };
+    let running = start_loop_with(LoopDevices {
+        dynamics: config::SinkDynamics {
+            output: config::Dynamics {
+                compressor: None,
+                limiter: Some(limiter),
+            },
+            ..config::SinkDynamics::default()
+        },
+        ..devices()
+    });
+    let sound = config::Sound {
+        wav: write_wav("loud.wav", &[20000; 9600]),
+        ..config::Sound::default()
+    };
+    for _ in 0..2 {
+        running
+            .sender
+            .send(Message::PlaySound(
+                sound.clone(),
+                SoundDevices::Output,
+                PlayOptions::default(),
+            ))
+            .unwrap();
+        running.wait_for(|event| matches!(event, Event::SoundStarted(..)));
+    }
+
+    // Together they would go way past full scale
+    running.backend.advance(4800);
+    let ceiling = (32767.0 * mlws_lib::utils::db_to_gain(-6.0)).round() as i16;
+    assert!(running
+        .backend
+        .take_output("Speakers")
+        .iter()
+        .all(|sample| (*sample - ceiling).abs() <= 1));
+    let reduction = running.wait_for(|event| matches!(event, Event::GainReduction(..)));
+    assert!(
+        matches!(reduction, Event::GainReduction(DeviceRole::Output, db) if (db - 7.7).abs() < 0.1)
+    );
+    running.kill();
+}
+
+#[test]
+fn ducks_microphone_while_sound_plays() {
+    let running = start_loop_with(LoopDevices {
+        ducking: Some(config::Ducking {
+            amount_db: 20.0,
+            attack_ms: 0.0,
+            release_ms: 10.0,
+            mic: true,
+            music_bed: false,
+        }),
+        ..devices()
+    });
+    let sound = config::Sound {
+        wav: write_wav("clip.wav", &[1000; 9600]),
+        ..config::Sound::default()
+    };
+    running
+        .sender
+        .send(Message::PlaySound(
+            sound,
+            SoundDevices::Output,
+            PlayOptions::default(),
+        ))
+        .unwrap();
+    running.wait_for(|event| matches!(event, Event::SoundStarted(..)));
+    running.sync();
+
+    running.backend.push_input("Mic", &[10000; 960]);
+    running.backend.advance(480);
+    let ducked = running.backend.take_output("Loop");
+    assert!(ducked.iter().all(|sample| (*sample - 1000).abs() <= 1));
+
+    // The clip ends in here, then the microphone comes back up over the release. The loop may
+    // see the end before the microphone stream got that far, so the release is looked for in
+    // the output rather than expected at a frame.
+    running.backend.push_input("Mic", &[10000; 9600]);
+    running.backend.advance(4800);
+    running.wait_for(|event| matches!(event, Event::SoundFinished(..)));
+    running.sync();
+    running.backend.push_input("Mic", &[10000; 960]);
+    running.backend.advance(480);
+    let left: Vec<i16> = running
+        .backend
+        .take_output("Loop")
+        .into_iter()
+        .step_by(2)
+        .collect();
+    let release = left
+        .iter()
+        .position(|sample| (*sample - 1000).abs() > 1)
+        .expect("microphone never came back up");
+    // Still ducked when the release starts, then up one step at a time
+    assert!(left[release] > 1000 && left[release] - 1000 <= 20);
+    assert!(left[release..]
+        .windows(2)
+        .all(|pair| (pair[1] - pair[0]).abs() <= 20));
+    assert!((left[left.len() - 1] - 10000).abs() <= 1);
+    running.kill();
+}
+
+#[test]
+fn meters_output_and_microphone() {
+    let running = start_loop();
+    running
+        .sender
+        .send(Message::SetMeterInterval(Some(Duration::from_millis(10))))
+        .unwrap();
+    let sound = config::Sound {
+        wav: write_wav("full_scale.wav", &[32767; 9600]),
+        ..config::Sound::default()
+    };
+    running
+        .sender
+        .send(Message::PlaySound(
+            sound,
+            SoundDevices::Output,
+            PlayOptions::default(),
+        ))
+        .unwrap();
+    running.wait_for(|event| matches!(event, Event::SoundStarted(..)));
+
+    // The microphone only on the left, at a quarter of full scale
+    let mic: Vec<i16> = (0..2400).flat_map(|_| vec![8192, 0]).collect();
+    running.backend.push_input("Mic", &mic);
+    running.backend.advance(2400);
+
+    let output = running.wait_for(|event| matches!(event, Event::Levels(DeviceRole::Output, _)));
+    let levels = match output {
+        Event::Levels(_, levels) => levels,
+        _ => unreachable!(),
+    };
+    assert_eq!(levels.len(), 2);
+    assert!(levels
+        .iter()
+        .all(|level| level.clipped && (level.rms - 1.0).abs() < 0.001));
+
+    let input = running.wait_for(|event| matches!(event, Event::Levels(DeviceRole::Input, _)));
+    let levels = match input {
+        Event::Levels(_, levels) => levels,
+        _ => unreachable!(),
+    };
+    assert!((levels[0].peak - 0.25).abs() < 0.001);
+    assert!((levels[0].rms - 0.25).abs() < 0.001);
+    assert!(!levels[0].clipped);
+    assert_eq!(levels[1].peak, 0.0);
+    running.kill();
+}